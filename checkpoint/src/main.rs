@@ -0,0 +1,40 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::core::pid_t;
+use ::container::Container;
+
+fn main() {
+    let matches = clap::App::new("aucont_checkpoint")
+        .version("0.1")
+        .about("Save a daemonized container to a directory, so that it can be started again \
+        with aucont_restore. The container is stopped unless --leave-running is given. \
+        Requires CRIU.")
+        .arg(clap::Arg::with_name("leave_running")
+            .long("leave-running")
+            .help("Continue running the container after the checkpoint."))
+        .arg(clap::Arg::with_name("pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start."))
+        .arg(clap::Arg::with_name("dir")
+            .index(2)
+            .required(true)
+            .value_name("DIR")
+            .help("Directory for the checkpoint. Must not exist."))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("pid"), pid_t);
+    let dir = matches.value_of("dir").unwrap();
+    if ::std::path::Path::new(dir).exists() {
+        panic!("'{}' already exists", dir);
+    }
+
+    let container = Container::open(id).check("Error accessing container");
+    container.checkpoint(dir, matches.is_present("leave_running"))
+        .check("Error checkpointing container");
+}
@@ -0,0 +1,254 @@
+use ::std::thread;
+use ::std::sync::{Arc, Mutex};
+use ::std::time::{Duration, Instant};
+use ::container::{self, pid_t, Container};
+use ::rand::{Rng, SeedableRng, XorShiftRng};
+use cluster::Cluster;
+use config::{ChaosAction, ChaosRule, Config};
+use replica::Control;
+
+// An event injected into a replica, or one which was skipped or failed
+#[derive(Serialize, Debug, Clone)]
+pub struct ChaosEvent {
+    // since the start of the schedule
+    pub time_secs: f64,
+    // one of `ChaosAction`, or "resume" and "unthrottle" once the replica is restored
+    pub action: String,
+    pub replica: String,
+    pub container: Option<pid_t>,
+    // e.g. "signal 9" or "10% cpu for 5s"
+    pub detail: String,
+    // why the event wasn't injected, e.g. the replica had no running container
+    pub skipped: Option<String>,
+    pub error: Option<String>,
+}
+
+// The events are logged to stderr as they happen and kept for the report
+pub struct ChaosLog {
+    start: Instant,
+    events: Mutex<Vec<ChaosEvent>>,
+}
+
+impl ChaosLog {
+    fn record(&self, action: &str, replica: &str, container: Option<pid_t>, detail: String,
+              res: container::Result<()>) {
+        let error = res.err().map(|e| e.to_string());
+        self.push(action, replica, container, detail, None, error);
+    }
+
+    fn skip(&self, action: &str, replica: &str, detail: String, reason: &str) {
+        self.push(action, replica, None, detail, Some(reason.to_string()), None);
+    }
+
+    fn push(&self, action: &str, replica: &str, container: Option<pid_t>, detail: String,
+            skipped: Option<String>, error: Option<String>) {
+        let event = ChaosEvent {
+            time_secs: self.start.elapsed().as_secs_f64(),
+            action: action.to_string(),
+            replica: replica.to_string(),
+            container,
+            detail,
+            skipped,
+            error,
+        };
+        let outcome = match (&event.skipped, &event.error) {
+            (Some(reason), _) => format!(" skipped: {}", reason),
+            (None, Some(e)) => format!(" failed: {}", e),
+            (None, None) => String::new(),
+        };
+        eprintln!("Chaos at {:.1}s: {} {} ({}){}", event.time_secs, event.action,
+                  event.replica, event.detail, outcome);
+        self.events.lock().unwrap().push(event);
+    }
+
+    pub fn events(&self) -> Vec<ChaosEvent> {
+        let mut events = self.events.lock().unwrap().clone();
+        events.sort_by(|a, b| a.time_secs.partial_cmp(&b.time_secs).unwrap());
+        events
+    }
+}
+
+// An event of the schedule, targeting a replica of the config
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledEvent {
+    // since the start of the schedule
+    pub time_secs: f64,
+    // index in `Chaos::events`
+    pub rule: usize,
+    // index in `Config::services`
+    pub service: usize,
+    pub replica_ix: u32,
+}
+
+// Each rule draws from its own generator, so that its events don't depend on the other rules
+fn rule_rng(seed: u64, rule_ix: usize) -> XorShiftRng {
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, rule_ix as u32, 0x9e37_79b9])
+}
+
+// The replicas of the config named by the targets of the rule, all of them if there are none
+fn rule_targets(rule: &ChaosRule, config: &Config) -> Vec<(usize, u32)> {
+    config.services.iter().enumerate()
+        .flat_map(|(service_ix, service)| (0..service.replicas).map(move |ix| (service_ix, ix)))
+        .filter(|&(service, ix)| rule.targets.is_empty() || rule.targets.iter().any(|name| {
+            *name == config.services[service].name
+                || *name == config.services[service].replica_name(ix)
+        }))
+        .collect()
+}
+
+// Draws the events of all the rules from the seed, ordered by time. The targets are
+// the replicas the config starts, so that scaling the cluster doesn't change the schedule.
+pub fn schedule(rules: &[ChaosRule], seed: u64, config: &Config) -> Vec<ScheduledEvent> {
+    let mut events = Vec::new();
+    for (rule_ix, rule) in rules.iter().enumerate() {
+        let mut rng = rule_rng(seed, rule_ix);
+        let targets = rule_targets(rule, config);
+        let times = match (rule.at_secs, rule.every_secs, rule.until_secs) {
+            (Some(at), _, _) => vec![at],
+            (None, Some(every), Some(until)) => (1..).map(|n| n as f64 * every)
+                .take_while(|time| *time <= until)
+                .collect(),
+            _ => Vec::new(),
+        };
+        for time_secs in times {
+            // both are drawn each time, so that the later events don't depend on the earlier ones
+            let (roll, pick) = (rng.gen::<f64>(), rng.gen::<f64>());
+            if roll >= rule.probability || targets.is_empty() {
+                continue;
+            }
+            let (service, replica_ix) =
+                targets[((pick * targets.len() as f64) as usize).min(targets.len() - 1)];
+            events.push(ScheduledEvent { time_secs, rule: rule_ix, service, replica_ix });
+        }
+    }
+    // stable, so that the simultaneous events keep the order of their rules
+    events.sort_by(|a, b| a.time_secs.partial_cmp(&b.time_secs).unwrap());
+    events
+}
+
+// Injects the events of the schedule in a separate thread until the cluster is stopping.
+// Events of replicas which were scaled down or have no running container are skipped.
+pub fn start(rules: &[ChaosRule], schedule: Vec<ScheduledEvent>, cluster: Arc<Mutex<Cluster>>)
+             -> Arc<ChaosLog> {
+    let log = Arc::new(ChaosLog { start: Instant::now(), events: Mutex::new(Vec::new()) });
+    let rules = rules.to_vec();
+    let thread_log = log.clone();
+    thread::spawn(move || run_schedule(&rules, &schedule, &cluster, &thread_log));
+    log
+}
+
+fn run_schedule(rules: &[ChaosRule], schedule: &[ScheduledEvent], cluster: &Mutex<Cluster>,
+                log: &Arc<ChaosLog>) {
+    for event in schedule {
+        let due = log.start + Duration::from_secs_f64(event.time_secs);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        let rule = &rules[event.rule];
+        let target = {
+            let cluster = cluster.lock().unwrap();
+            if cluster.stopping {
+                return;
+            }
+            let service = &cluster.config.services[event.service];
+            let name = service.replica_name(event.replica_ix);
+            let control = cluster.replica(event.service, event.replica_ix)
+                .map(|handle| handle.control.clone());
+            (name, control, service.cpu_perc.unwrap_or(100))
+        };
+        match target {
+            (name, Some(control), cpu_perc) => inject(rule, &name, &control, cpu_perc, log),
+            (name, None, _) => {
+                log.skip(&rule.action.to_string(), &name, detail(rule), "The replica was removed");
+            },
+        }
+    }
+}
+
+// e.g. "signal 9" or "10% cpu for 5s"
+fn detail(rule: &ChaosRule) -> String {
+    let duration = rule.duration_secs.unwrap_or(0.0);
+    match rule.action {
+        ChaosAction::Kill => format!("signal {}", rule.signal().unwrap()),
+        ChaosAction::Pause => format!("for {}s", duration),
+        ChaosAction::Throttle => format!("{}% cpu for {}s", rule.cpu_perc.unwrap(), duration),
+    }
+}
+
+// Pause and throttle are undone after their duration, unless the container has exited
+fn inject(rule: &ChaosRule, name: &str, control: &Control, cpu_perc: u32, log: &Arc<ChaosLog>) {
+    let action = rule.action.to_string();
+    let duration = rule.duration_secs.unwrap_or(0.0);
+    let detail = detail(rule);
+    let id = match control.lock().unwrap().container {
+        Some(id) => id,
+        None => return log.skip(&action, name, detail, "The replica has no running container"),
+    };
+    let res = Container::open(id).and_then(|mut container| match rule.action {
+        ChaosAction::Kill => container.kill(rule.signal().unwrap()),
+        ChaosAction::Pause => container.pause(),
+        ChaosAction::Throttle => container.set_cpu_perc(rule.cpu_perc.unwrap()),
+    });
+    let injected = res.is_ok();
+    log.record(&action, name, Some(id), detail, res);
+    if !injected || rule.action == ChaosAction::Kill {
+        return;
+    }
+
+    let name = name.to_string();
+    let control = control.clone();
+    let throttled = rule.action == ChaosAction::Throttle;
+    let log = log.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs_f64(duration));
+        if control.lock().unwrap().container != Some(id) {
+            return;
+        }
+        let res = Container::open(id).and_then(|mut container| if throttled {
+            container.set_cpu_perc(cpu_perc)
+        } else {
+            container.resume()
+        });
+        let (action, detail) = if throttled {
+            ("unthrottle", format!("{}% cpu", cpu_perc))
+        } else {
+            ("resume", format!("after {}s", duration))
+        };
+        log.record(action, &name, Some(id), detail, res);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{parse_config, Format};
+
+    fn config() -> Config {
+        parse_config(r#"{"output_dir_path": "/tmp",
+            "services": [{"name": "web", "image": "/img", "cmd": "sh", "replicas": 3},
+                         {"name": "db", "image": "/img", "cmd": "sh"}],
+            "chaos": {"events": [
+                {"action": "kill", "every_secs": 1, "until_secs": 60, "probability": 0.5},
+                {"action": "pause", "targets": ["db"], "at_secs": 5, "duration_secs": 1}]}}"#,
+                     Format::Json).unwrap().normalize().unwrap()
+    }
+
+    #[test]
+    fn the_seed_determines_the_schedule() {
+        let config = config();
+        let rules = &config.chaos.as_ref().unwrap().events;
+        let events = schedule(rules, 42, &config);
+        assert_eq!(events, schedule(rules, 42, &config));
+        assert_ne!(events, schedule(rules, 43, &config));
+        assert!(events.windows(2).all(|pair| pair[0].time_secs <= pair[1].time_secs));
+
+        let kills = events.iter().filter(|event| event.rule == 0).cloned().collect::<Vec<_>>();
+        assert!(!kills.is_empty() && kills.len() < 60);
+        assert!(kills.iter().all(|event| event.time_secs <= 60.0));
+        // the events of a rule don't depend on the other rules
+        assert_eq!(kills, schedule(&rules[..1], 42, &config));
+        assert!(events.contains(&ScheduledEvent {
+            time_secs: 5.0, rule: 1, service: 1, replica_ix: 0 }));
+    }
+}
@@ -0,0 +1,407 @@
+use ::std::{fs, thread};
+use ::std::sync::{mpsc, Arc, Mutex};
+use ::std::thread::JoinHandle;
+use ::std::time::Duration;
+use ::core::check::Check;
+use ::core::raw_process::RawProcess;
+use ::container;
+use ::container::CommentError;
+use ::container::ipam::{self, Network};
+use ::net::{bridge_create, bridge_delete, route_add, route_del};
+use config::Config;
+use membership::{Member, Membership};
+use output::{Output, ReplicaOutput};
+use report::ReplicaReport;
+use replica::{Control, Replica, ReplicaAddr, ReplicaControl, start_replica, stop_container,
+              wait_ready};
+
+// Network created for the cluster together with its bridge, both are removed on drop
+pub struct ClusterNetwork {
+    // key in `Config::networks`, None for the default network
+    pub key: Option<String>,
+    pub network: Network,
+    pub bridge: String,
+}
+
+impl ClusterNetwork {
+    pub fn create(key: Option<String>, name: String, subnet: &str, bridge: String)
+                  -> ClusterNetwork {
+        let network = Network::create(&name, subnet, None, Some(&bridge), false)
+            .check("Error creating network");
+        defer_on_unwind! {{
+            Network::delete(&name, true).log_error("Error deleting network");
+        }};
+        // the replicas may reach other networks through the bridge
+        bridge_create(&bridge, Some(network.gateway()), true)
+            .check("Error setting up network bridge");
+        ClusterNetwork { key, network, bridge }
+    }
+}
+
+impl Drop for ClusterNetwork {
+    fn drop(&mut self) {
+        bridge_delete(&self.bridge).log_error("Error deleting network bridge");
+        Network::delete(self.network.name(), true).log_error("Error deleting network");
+    }
+}
+
+pub struct ReplicaHandle {
+    pub replica: Replica,
+    pub control: Control,
+    // None once the thread is joined
+    pub thread: Option<JoinHandle<container::Result<()>>>,
+    pub started: bool,
+}
+
+// Replicas of the cluster and their networks. The operations which stop the replicas
+// take the mutex of the cluster, and only hold it while the cluster is modified.
+pub struct Cluster {
+    pub config: Config,
+    pub networks: Vec<ClusterNetwork>,
+    pub replicas: Vec<ReplicaHandle>,
+    pub output: Arc<Output>,
+    pub membership: Arc<Membership>,
+    // replicas removed by the shutdown or by scaling down (true), kept for the report
+    pub removed: Vec<(Replica, Control, bool)>,
+    // set by `shutdown`, no replicas are started afterwards
+    pub stopping: bool,
+}
+
+impl Cluster {
+    pub fn new(config: Config, networks: Vec<ClusterNetwork>, output: Arc<Output>,
+               membership: Membership) -> Cluster {
+        Cluster {
+            config,
+            networks,
+            replicas: Vec::new(),
+            output,
+            membership: Arc::new(membership),
+            removed: Vec::new(),
+            stopping: false,
+        }
+    }
+
+    // Leases the address of a new replica with the lowest free index, the replica isn't started.
+    // Addresses are leased by the replicas rather than by their containers,
+    // so that they don't change when a replica is restarted.
+    pub fn add_replica(&mut self, service_ix: usize) -> container::Result<Replica> {
+        let service = &self.config.services[service_ix];
+        let ix = (0..).find(|ix| self.replicas.iter()
+            .all(|handle| handle.replica.service != service_ix || handle.replica.ix != *ix))
+            .unwrap();
+        let name = service.replica_name(ix);
+        // the output of all the containers of the replica is appended to them
+        for path in &[service.output_file(&self.config.output_dir_path, ix),
+                      service.stderr_file(&self.config.output_dir_path, ix)] {
+            fs::File::create(path).comment_error("Error creating replica output file")?;
+        }
+        let mut addrs: Vec<ReplicaAddr> = Vec::new();
+        let res = service.network_keys().into_iter().try_for_each(|key| {
+            let network = self.networks.iter().find(|network| network.key == key).unwrap();
+            let ip = network.network.allocate(&name)
+                .comment_error("Error allocating replica address")?;
+            route_add(ip, &network.bridge).comment_error("Error adding network route")?;
+            addrs.push(ReplicaAddr {
+                network: network.network.name().to_string(),
+                bridge: network.bridge.clone(),
+                ip,
+                prefix_len: network.network.prefix_len(),
+            });
+            Ok(())
+        });
+        if let Err(e) = res {
+            for addr in &addrs {
+                route_del(addr.ip, &addr.bridge).log_error("Error removing network route");
+            }
+            ipam::release_owner(&name).log_error("Error releasing replica address");
+            return Err(e);
+        }
+        let replica = Replica { service: service_ix, ix, name, addrs };
+        self.replicas.push(ReplicaHandle {
+            replica: replica.clone(),
+            control: ReplicaControl::new(),
+            thread: None,
+            started: false,
+        });
+        Ok(replica)
+    }
+
+    // Rewrites the membership file and /etc/hosts of the running replicas, and signals the ones
+    // of the services which ask for it. Replicas which aren't started yet aren't signalled.
+    pub fn update_membership(&self) -> container::Result<()> {
+        let mut handles = self.replicas.iter().collect::<Vec<_>>();
+        handles.sort_by_key(|handle| (handle.replica.service, handle.replica.ix));
+        let members = handles.iter().map(|handle| Member {
+            name: handle.replica.name.clone(),
+            service: self.config.services[handle.replica.service].name.clone(),
+            ix: handle.replica.ix,
+            addrs: handle.replica.addrs.iter()
+                .map(|addr| (addr.network.clone(), addr.ip)).collect(),
+        }).collect();
+        if !self.membership.update(members).comment_error("Error writing membership file")? {
+            return Ok(());
+        }
+        for handle in handles.iter().filter(|handle| handle.started) {
+            // checked by `Config::normalize`
+            let signal = self.config.services[handle.replica.service].membership_signal()
+                .ok().flatten();
+            let id = handle.control.lock().unwrap().container;
+            if let Some(id) = id {
+                let hosts = self.membership.hosts(&handle.replica.networks());
+                container::Container::open(id).and_then(|mut container| container.set_hosts(&hosts))
+                    .log_error(format!("Error updating hosts of replica {}", handle.replica.name));
+            }
+            if let (Some(signal), Some(id)) = (signal, id) {
+                RawProcess::from_pid(id).signal(signal).log_error(format!(
+                    "Error notifying replica {} of membership change", handle.replica.name));
+            }
+        }
+        Ok(())
+    }
+
+    // Starts the manager thread of the replica, `started` is passed to `start_replica`
+    pub fn start(&mut self, name: &str, started: mpsc::Sender<()>) -> container::Result<()> {
+        if self.stopping {
+            return Err(container::Error::simple("The cluster is shutting down"));
+        }
+        let handle = self.replicas.iter_mut().find(|handle| handle.replica.name == name)
+            .unwrap();
+        let service = &self.config.services[handle.replica.service];
+        let shaping = self.config.shaping(service).comment_error("Invalid config")?;
+        let output = ReplicaOutput {
+            output: self.output.clone(),
+            stdout_path: service.output_file(&self.config.output_dir_path, handle.replica.ix),
+            stderr_path: service.stderr_file(&self.config.output_dir_path, handle.replica.ix),
+        };
+        let control = ReplicaControl::new();
+        handle.control = control.clone();
+        handle.started = true;
+        handle.thread = Some(start_replica(service.clone(), handle.replica.clone(), output,
+                                           self.membership.clone(), shaping, started, control));
+        Ok(())
+    }
+
+    // The replicas removed by the shutdown keep their final state
+    pub fn report(&self) -> Vec<ReplicaReport> {
+        let mut replicas = self.replicas.iter()
+            .map(|handle| {
+                let state = if handle.started { None } else { Some("not started") };
+                (&handle.replica, &handle.control, state)
+            })
+            .chain(self.removed.iter().map(|(replica, control, scaled_down)| {
+                (replica, control, if *scaled_down { Some("removed") } else { None })
+            }))
+            .collect::<Vec<_>>();
+        replicas.sort_by_key(|&(replica, _, _)| (replica.service, replica.ix));
+        replicas.into_iter().map(|(replica, control, state)| {
+            let service = &self.config.services[replica.service];
+            let control = control.lock().unwrap();
+            let state = state.map(|state| state.to_string())
+                .unwrap_or_else(|| control.state.to_string());
+            ReplicaReport {
+                name: replica.name.clone(),
+                service: service.name.clone(),
+                ix: replica.ix,
+                ip: replica.ip().to_string(),
+                state,
+                restarts: control.restarts,
+                stdout_file: service.output_file(&self.config.output_dir_path, replica.ix),
+                stderr_file: service.stderr_file(&self.config.output_dir_path, replica.ix),
+                runs: control.runs.clone(),
+            }
+        }).collect()
+    }
+
+    // The replicas given by their names or the names of their services, ordered by service
+    // and index. All the replicas if there are no names.
+    // The current replica with index `ix` of the service, if it wasn't scaled down
+    pub fn replica(&self, service: usize, ix: u32) -> Option<&ReplicaHandle> {
+        self.replicas.iter()
+            .find(|handle| handle.replica.service == service && handle.replica.ix == ix)
+    }
+
+    pub fn service_index(&self, name: &str) -> container::Result<usize> {
+        self.config.services.iter().position(|service| service.name == name)
+            .ok_or_else(|| container::Error::simple(format!("No service '{}'", name)))
+    }
+
+    fn find(&self, name: &str) -> container::Result<&ReplicaHandle> {
+        self.replicas.iter().find(|handle| handle.replica.name == name)
+            .ok_or_else(|| container::Error::simple(format!("No replica '{}'", name)))
+    }
+
+    fn stop_timeout(&self, replica: &Replica) -> Duration {
+        Duration::from_secs_f64(self.config.services[replica.service].stop_timeout_secs)
+    }
+}
+
+// Name, state, address, container, restarts and health of the replicas
+pub fn list(cluster: &Mutex<Cluster>) -> Vec<Vec<String>> {
+    let cluster = cluster.lock().unwrap();
+    let mut handles = cluster.replicas.iter().collect::<Vec<_>>();
+    handles.sort_by_key(|handle| (handle.replica.service, handle.replica.ix));
+    handles.into_iter().map(|handle| {
+        let control = handle.control.lock().unwrap();
+        let state = if handle.started {
+            control.state.to_string()
+        } else {
+            "not started".to_string()
+        };
+        let health = control.container
+            .and_then(|id| container::health::read_health(id).ok().flatten());
+        vec![
+            handle.replica.name.clone(),
+            state,
+            handle.replica.ip().to_string(),
+            control.container.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string()),
+            control.restarts.to_string(),
+            health.map(|health| health.to_string()).unwrap_or_else(|| "-".to_string()),
+        ]
+    }).collect()
+}
+
+// Stops the removed replicas concurrently and releases their addresses
+fn remove(handles: Vec<(ReplicaHandle, Duration)>) -> container::Result<()> {
+    let stops = handles.into_iter().map(|(handle, timeout)| thread::spawn(move || {
+        let res = stop_container(&handle.control, timeout);
+        if let Some(thread) = handle.thread {
+            let error_string = &format!("Error in replica manager {}", handle.replica.name);
+            thread.join().unwrap().log_error(error_string);
+        }
+        res?;
+        ipam::release_owner(&handle.replica.name)
+            .comment_error("Error releasing replica address")?;
+        handle.replica.addrs.iter().try_for_each(|addr| {
+            route_del(addr.ip, &addr.bridge).comment_error("Error removing network route")
+        })
+    })).collect::<Vec<_>>();
+    let mut res = Ok(());
+    for stop in stops {
+        if let Err(e) = stop.join().unwrap() {
+            res = Err(e);
+        }
+    }
+    res
+}
+
+// New replicas get the lowest free indices, the ones with the highest indices are removed.
+// Returns the names of the added and the removed replicas.
+pub fn scale(cluster: &Mutex<Cluster>, service: &str, count: u32)
+             -> container::Result<(Vec<String>, Vec<String>)> {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    {
+        let mut cluster = cluster.lock().unwrap();
+        if cluster.stopping {
+            return Err(container::Error::simple("The cluster is shutting down"));
+        }
+        let service_ix = cluster.service_index(service)?;
+        cluster.config.services[service_ix].replicas = count;
+        let mut ixs = cluster.replicas.iter()
+            .filter(|handle| handle.replica.service == service_ix)
+            .map(|handle| handle.replica.ix).collect::<Vec<_>>();
+        ixs.sort();
+        while (ixs.len() as u32) < count {
+            let replica = cluster.add_replica(service_ix)?;
+            ixs.push(replica.ix);
+            added.push(replica.name);
+        }
+        for ix in ixs.into_iter().skip(count as usize) {
+            let pos = cluster.replicas.iter()
+                .position(|handle| handle.replica.service == service_ix && handle.replica.ix == ix)
+                .unwrap();
+            let handle = cluster.replicas.remove(pos);
+            handle.control.lock().unwrap().removed = true;
+            cluster.removed.push((handle.replica.clone(), handle.control.clone(), true));
+            let timeout = cluster.stop_timeout(&handle.replica);
+            removed.push((handle, timeout));
+        }
+        // the new replicas aren't signalled, they get the new members at once
+        cluster.update_membership()?;
+        for name in &added {
+            // nothing waits for the new replicas to start
+            cluster.start(name, mpsc::channel().0)?;
+        }
+    }
+    let names = removed.iter().map(|(handle, _)| handle.replica.name.clone()).collect();
+    remove(removed)?;
+    Ok((added, names))
+}
+
+// The container of the replica is stopped and started again regardless of the restart policy.
+// The manager of a replica which has exited is started again.
+pub fn restart(cluster: &Mutex<Cluster>, name: &str) -> container::Result<Control> {
+    let (control, timeout) = {
+        let mut cluster = cluster.lock().unwrap();
+        let handle = cluster.find(name)?;
+        let running = handle.thread.as_ref().map(|thread| !thread.is_finished()).unwrap_or(false);
+        if running {
+            handle.control.lock().unwrap().restart_requested = true;
+            (handle.control.clone(), cluster.stop_timeout(&handle.replica))
+        } else {
+            // the error of the previous manager is already reported by `ReplicaState::Failed`
+            cluster.start(name, mpsc::channel().0)?;
+            return Ok(cluster.find(name)?.control.clone());
+        }
+    };
+    stop_container(&control, timeout)?;
+    Ok(control)
+}
+
+// Restarts the replicas of the service one at a time, waiting until each of them is ready.
+// Calls `progress` with the name of each restarted replica.
+pub fn rolling_restart<F>(cluster: &Mutex<Cluster>, service: &str, mut progress: F)
+                          -> container::Result<()>
+    where F: FnMut(&str) {
+    let (names, timeout) = {
+        let cluster = cluster.lock().unwrap();
+        let service_ix = cluster.service_index(service)?;
+        let mut replicas = cluster.replicas.iter().map(|handle| &handle.replica)
+            .filter(|replica| replica.service == service_ix).collect::<Vec<_>>();
+        replicas.sort_by_key(|replica| replica.ix);
+        let names = replicas.into_iter().map(|replica| replica.name.clone()).collect::<Vec<_>>();
+        (names, cluster.config.services[service_ix].health_timeout())
+    };
+    for name in names {
+        let control = restart(cluster, &name)?;
+        wait_ready(&name, &control, timeout)?;
+        progress(&name);
+    }
+    Ok(())
+}
+
+// Stops and removes all the replicas, the networks are kept.
+// The replicas are sent SIGTERM and killed after the stop timeouts of their services.
+pub fn shutdown(cluster: &Mutex<Cluster>) -> container::Result<()> {
+    let removed = {
+        let mut cluster = cluster.lock().unwrap();
+        cluster.stopping = true;
+        let handles = cluster.replicas.drain(..).collect::<Vec<_>>();
+        handles.into_iter().map(|handle| {
+            handle.control.lock().unwrap().removed = true;
+            cluster.removed.push((handle.replica.clone(), handle.control.clone(), false));
+            let timeout = cluster.stop_timeout(&handle.replica);
+            (handle, timeout)
+        }).collect()
+    };
+    remove(removed)
+}
+
+// Waits until all the replica managers exit, returns whether all of them succeeded
+pub fn join(cluster: &Mutex<Cluster>) -> bool {
+    let threads = cluster.lock().unwrap().replicas.iter_mut()
+        .filter_map(|handle| {
+            let name = handle.replica.name.clone();
+            handle.thread.take().map(|thread| (name, thread))
+        })
+        .collect::<Vec<_>>();
+    let mut ok = true;
+    for (name, thread) in threads {
+        let error_string = &format!("Error in replica manager {}", name);
+        if thread.join().unwrap().log_error(error_string).is_none() {
+            ok = false;
+        }
+    }
+    ok
+}
+
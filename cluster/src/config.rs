@@ -0,0 +1,841 @@
+use ::std::{fmt, io, path};
+use ::std::collections::{BTreeMap, HashSet};
+use ::std::time::Duration;
+use ::container::{ExitStatus, Shaping, parse_rate, parse_delay, parse_loss, parse_memory};
+use ::container::health::{HealthCheck, parse_duration};
+use ::libc::{self, c_int};
+
+// Name of the service described by the single-service format: "container_image_path", "cmd",
+// "args" and "replica_count" at the top level. Replicas of a service are named
+// "SERVICE-IX", so these keep their names "replica-IX".
+pub const LEGACY_SERVICE: &str = "replica";
+// Names the network given by `Config::subnet` in `Service::networks`
+pub const DEFAULT_NETWORK: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub output_dir_path: String,
+    // default network of the services. The first address is assigned to the bridge,
+    // replicas get the following ones.
+    #[serde(default = "default_subnet")]
+    pub subnet: String,
+    // additional networks by name, each with its own bridge. The name "default" is reserved.
+    #[serde(default)]
+    pub networks: BTreeMap<String, NetworkSpec>,
+    #[serde(default)]
+    pub services: Vec<Service>,
+    // conditions of the link of each replica to the bridge, e.g. "10mbit", "100ms" and "1%"
+    #[serde(default)]
+    pub net_rate: Option<String>,
+    #[serde(default)]
+    pub net_delay: Option<String>,
+    #[serde(default)]
+    pub net_loss: Option<String>,
+    // links between the replicas cut while they run, see aucont_partition
+    #[serde(default)]
+    pub partitions: Vec<Partition>,
+    // faults injected into the replicas while they run
+    #[serde(default)]
+    pub chaos: Option<Chaos>,
+
+    #[serde(default)]
+    container_image_path: Option<String>,
+    #[serde(default)]
+    cmd: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    replica_count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkSpec {
+    pub subnet: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Service {
+    pub name: String,
+    pub image: String,
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_replicas")]
+    pub replicas: u32,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // percent of the cpu resources of each replica
+    #[serde(default)]
+    pub cpu_perc: Option<u32>,
+    // memory limit of each replica, e.g. "256M"
+    #[serde(default)]
+    pub memory: Option<String>,
+    // services whose replicas are started before the ones of this service
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    // keys of `Config::networks` or "default", only the default network if empty.
+    // The first one holds the default route and the address listed in the membership file.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    // override the link conditions of the config
+    #[serde(default)]
+    pub net_rate: Option<String>,
+    #[serde(default)]
+    pub net_delay: Option<String>,
+    #[serde(default)]
+    pub net_loss: Option<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    // restarts in a row, unlimited if not set
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
+    // the delay before a restart doubles with each restart in a row up to the maximal one.
+    // Up to a half of it is random, so that the replicas which fail together are spread out.
+    #[serde(default = "default_restart_delay")]
+    pub restart_delay_secs: f64,
+    #[serde(default = "default_max_restart_delay")]
+    pub max_restart_delay_secs: f64,
+    // restarts are no longer in a row once the replica has been ready for this long,
+    // i.e. running and healthy if the service has a health check
+    #[serde(default = "default_restart_reset")]
+    pub restart_reset_secs: f64,
+    // replicas which are stopped by the supervisor are killed if they run for longer after
+    // SIGTERM, e.g. when they are restarted or the service is scaled down
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout_secs: f64,
+    // unhealthy replicas are killed and restarted according to the restart policy.
+    // The services depending on this one wait until its replicas are healthy.
+    #[serde(default)]
+    pub health_check: Option<HealthCheckSpec>,
+    // replicas which aren't healthy this long after their container is created are killed,
+    // and the start of the services depending on this one fails. No limit if not set.
+    #[serde(default)]
+    pub health_timeout_secs: Option<f64>,
+    // sent to the replicas when the membership file changes, e.g. "SIGHUP" or "10".
+    // The file lists all the replicas of the cluster, see `Membership`.
+    #[serde(default)]
+    pub membership_signal: Option<String>,
+}
+
+// See `HealthCheck`, durations are given as "30s", "500ms" or "1m"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthCheckSpec {
+    pub cmd: String,
+    #[serde(default)]
+    pub interval: Option<String>,
+    #[serde(default)]
+    pub timeout: Option<String>,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub start_period: Option<String>,
+}
+
+// Whether a replica is restarted when its container exits.
+// A replica is stopped when its container is cancelled from outside of the cluster,
+// e.g. by aucont_stop. The replicas stopped by the supervisor aren't subject to the policy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    No,
+    #[default]
+    OnFailure,
+    // even a stopped replica is restarted, so it can only be stopped through the supervisor
+    Always,
+    UnlessStopped,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, status: ExitStatus, stopped: bool) -> bool {
+        match *self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure => !stopped && !status.success(),
+            RestartPolicy::Always => true,
+            RestartPolicy::UnlessStopped => !stopped,
+        }
+    }
+}
+
+// The links between each pair of the groups are cut `at_secs` after the start and healed
+// `duration_secs` later, or stay cut until the end. With `one_way` only the packets from
+// the first group to the others are dropped. Links are only cut within each network.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Partition {
+    pub at_secs: f64,
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    pub groups: Vec<Vec<Member>>,
+    #[serde(default)]
+    pub one_way: bool,
+}
+
+// Replicas are given by their index among the replicas of all the services in the order of
+// the config, by their name "SERVICE-IX" or all at once by the name of their service
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Member {
+    Index(u32),
+    Name(String),
+}
+
+// The replicas affected by the events are drawn by a generator seeded with `seed`, so that
+// the same seed replays the same schedule. A random seed is used unless it's given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chaos {
+    #[serde(default)]
+    pub seed: Option<u64>,
+    pub events: Vec<ChaosRule>,
+}
+
+// The event happens once `at_secs` after the start, or every `every_secs` with `probability`
+// until `until_secs`. Each time it affects a random one of the `targets`, which are replicas
+// or all the replicas of a service, any replica if there are none. The targets are drawn
+// among the replicas the config starts, see `chaos::schedule`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChaosRule {
+    pub action: ChaosAction,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub at_secs: Option<f64>,
+    #[serde(default)]
+    pub every_secs: Option<f64>,
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+    #[serde(default)]
+    pub until_secs: Option<f64>,
+    // how long the replica stays paused or throttled
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    // sent by kill, "SIGKILL" by default
+    #[serde(default)]
+    pub signal: Option<String>,
+    // cpu limit of a throttled replica
+    #[serde(default)]
+    pub cpu_perc: Option<u32>,
+}
+
+// A killed replica is restarted according to its restart policy, as if it crashed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChaosAction {
+    Kill,
+    Pause,
+    Throttle,
+}
+
+impl fmt::Display for ChaosAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ChaosAction::Kill => "kill",
+            ChaosAction::Pause => "pause",
+            ChaosAction::Throttle => "throttle",
+        })
+    }
+}
+
+impl ChaosRule {
+    pub fn signal(&self) -> io::Result<c_int> {
+        self.signal.as_ref().map_or(Ok(libc::SIGKILL), |signal| parse_signal(signal))
+    }
+
+    // `path` of the rule in the config, e.g. "chaos.events[0]"
+    fn validate(&self, path: &str, config: &Config) -> io::Result<()> {
+        let field = |name: &str| format!("{}.{}", path, name);
+        let times = [("at_secs", self.at_secs), ("every_secs", self.every_secs),
+            ("until_secs", self.until_secs), ("duration_secs", self.duration_secs)];
+        for &(name, secs) in &times {
+            if secs.map(|secs| !secs.is_finite() || secs < 0.0).unwrap_or(false) {
+                return Err(invalid_field(&field(name), "Expected a non-negative duration"));
+            }
+        }
+        match (self.at_secs, self.every_secs) {
+            (Some(_), None) => {},
+            (None, Some(every)) if every > 0.0 => if self.until_secs.is_none() {
+                // the whole schedule is drawn at the start
+                return Err(invalid_field(&field("until_secs"), "Missing with 'every_secs'"));
+            },
+            _ => return Err(invalid_field(path, "Either 'at_secs' or 'every_secs' is needed")),
+        }
+        if !(0.0..=1.0).contains(&self.probability) {
+            return Err(invalid_field(&field("probability"), "Expected 0 to 1"));
+        }
+        match self.action {
+            ChaosAction::Kill => {
+                self.signal().map_err(|e| invalid_field(&field("signal"), e))?;
+            },
+            ChaosAction::Pause | ChaosAction::Throttle => if self.duration_secs.is_none() {
+                return Err(invalid_field(&field("duration_secs"),
+                                         format!("Missing for a {} event", self.action)));
+            },
+        }
+        if self.action == ChaosAction::Throttle {
+            match self.cpu_perc {
+                None => {
+                    return Err(invalid_field(&field("cpu_perc"), "Missing for a throttle event"));
+                },
+                Some(perc) if !(1..=100).contains(&perc) => {
+                    return Err(invalid_field(&field("cpu_perc"), "Expected 1 to 100"));
+                },
+                Some(_) => {},
+            }
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            let valid = config.services.iter().any(|service| {
+                *target == service.name || (0..service.replicas)
+                    .any(|ix| *target == service.replica_name(ix))
+            });
+            if !valid {
+                return Err(invalid_field(&field(&format!("targets[{}]", i)),
+                                         format!("Unknown replica '{}'", target)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Format of the config file, detected by its extension unless given explicitly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> io::Result<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unknown config format '{}'", name))),
+        }
+    }
+
+    // JSON for unknown extensions, as it used to be the only format
+    pub fn from_path(path: &str) -> Format {
+        path::Path::new(path).extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Format::parse(ext).ok())
+            .unwrap_or(Format::Json)
+    }
+}
+
+// Messages of all the parsers include the line and the column, the field is added here.
+// YAML messages may start with the field already.
+fn parse_error<E: fmt::Display>(e: serde_path_to_error::Error<E>) -> io::Error {
+    let field = e.path().to_string();
+    let message = e.into_inner().to_string();
+    if field == "." {
+        return invalid(message);
+    }
+    let message = message.trim_start_matches(&format!("{}: ", field));
+    invalid(format!("field '{}': {}", field, message))
+}
+
+pub fn parse_config(data: &str, format: Format) -> io::Result<Config> {
+    match format {
+        Format::Json => serde_path_to_error::deserialize(
+            &mut ::serde_json::Deserializer::from_str(data)).map_err(parse_error),
+        Format::Yaml => serde_path_to_error::deserialize(
+            ::serde_yaml::Deserializer::from_str(data)).map_err(parse_error),
+        Format::Toml => serde_path_to_error::deserialize(
+            &mut ::toml::Deserializer::new(data)).map_err(parse_error),
+    }
+}
+
+fn default_subnet() -> String {
+    "10.0.0.0/16".to_string()
+}
+
+fn default_replicas() -> u32 {
+    1
+}
+
+fn default_restart_delay() -> f64 {
+    1.0
+}
+
+fn default_max_restart_delay() -> f64 {
+    60.0
+}
+
+fn default_restart_reset() -> f64 {
+    60.0
+}
+
+fn default_stop_timeout() -> f64 {
+    10.0
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+// A number or a name with or without the "SIG" prefix
+fn parse_signal(s: &str) -> io::Result<c_int> {
+    if let Ok(signum) = s.parse::<c_int>() {
+        if signum > 0 && signum < 65 {
+            return Ok(signum);
+        }
+    }
+    let signals = [
+        ("HUP", libc::SIGHUP), ("INT", libc::SIGINT), ("QUIT", libc::SIGQUIT),
+        ("USR1", libc::SIGUSR1), ("USR2", libc::SIGUSR2), ("TERM", libc::SIGTERM),
+        ("CONT", libc::SIGCONT), ("WINCH", libc::SIGWINCH), ("KILL", libc::SIGKILL),
+        ("ABRT", libc::SIGABRT), ("SEGV", libc::SIGSEGV),
+    ];
+    let name = s.trim_start_matches("SIG");
+    signals.iter().find(|&&(other, _)| other == name).map(|&(_, signum)| signum)
+        .ok_or_else(|| invalid(format!("Invalid signal '{}'", s)))
+}
+
+fn invalid<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// Errors found after parsing name the field like the parse errors, e.g. "services[1].memory"
+fn invalid_field<M: fmt::Display>(field: &str, message: M) -> io::Error {
+    invalid(format!("field '{}': {}", field, message))
+}
+
+// Names are used for hostnames, lease owners and environment variables
+fn check_name(field: &str, kind: &str, name: &str) -> io::Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid_field(field, format!("Invalid {} name '{}', it must start with a letter and \
+        contain only letters, digits, '_' and '-'", kind, name)))
+    }
+}
+
+// `prefix` is the path of the object with the fields, e.g. "services[1]."
+fn check_shaping(prefix: &str, rate: &Option<String>, delay: &Option<String>,
+                 loss: &Option<String>) -> io::Result<()> {
+    let field = |name| format!("{}{}", prefix, name);
+    if let Some(ref rate) = *rate {
+        parse_rate(rate).map_err(|e| invalid_field(&field("net_rate"), e))?;
+    }
+    if let Some(ref delay) = *delay {
+        parse_delay(delay).map_err(|e| invalid_field(&field("net_delay"), e))?;
+    }
+    if let Some(ref loss) = *loss {
+        parse_loss(loss).map_err(|e| invalid_field(&field("net_loss"), e))?;
+    }
+    Ok(())
+}
+
+fn parse_shaping(rate: &Option<String>, delay: &Option<String>, loss: &Option<String>)
+                 -> io::Result<Shaping> {
+    Ok(Shaping {
+        rate: rate.as_ref().map(|rate| parse_rate(rate)).transpose()?,
+        delay: delay.as_ref().map(|delay| parse_delay(delay)).transpose()?,
+        loss: loss.as_ref().map(|loss| parse_loss(loss)).transpose()?,
+    })
+}
+
+impl Config {
+    // Converts the single-service format and checks the references between the services
+    pub fn normalize(mut self) -> io::Result<Config> {
+        let legacy = self.container_image_path.is_some() || self.cmd.is_some()
+            || self.replica_count.is_some();
+        if legacy {
+            if !self.services.is_empty() {
+                return Err(invalid("Either 'services' or 'container_image_path', 'cmd' and \
+                'replica_count' must be given, not both"));
+            }
+            let missing = |field| invalid(format!("Missing field '{}'", field));
+            self.services.push(Service {
+                name: LEGACY_SERVICE.to_string(),
+                image: self.container_image_path.take()
+                    .ok_or_else(|| missing("container_image_path"))?,
+                cmd: self.cmd.take().ok_or_else(|| missing("cmd"))?,
+                args: self.args.split_off(0),
+                replicas: self.replica_count.take().ok_or_else(|| missing("replica_count"))?,
+                env: BTreeMap::new(),
+                cpu_perc: None,
+                memory: None,
+                depends_on: Vec::new(),
+                networks: Vec::new(),
+                net_rate: None,
+                net_delay: None,
+                net_loss: None,
+                restart: RestartPolicy::default(),
+                max_restarts: None,
+                restart_delay_secs: default_restart_delay(),
+                max_restart_delay_secs: default_max_restart_delay(),
+                restart_reset_secs: default_restart_reset(),
+                stop_timeout_secs: default_stop_timeout(),
+                health_check: None,
+                health_timeout_secs: None,
+                membership_signal: None,
+            });
+        }
+        if self.services.is_empty() {
+            return Err(invalid("No services are given"));
+        }
+
+        for name in self.networks.keys() {
+            let field = format!("networks.{}", name);
+            check_name(&field, "network", name)?;
+            if name == DEFAULT_NETWORK {
+                return Err(invalid_field(&field, format!("Network name '{}' is reserved", name)));
+            }
+        }
+        check_shaping("", &self.net_rate, &self.net_delay, &self.net_loss)?;
+        let mut names = HashSet::new();
+        for (i, service) in self.services.iter().enumerate() {
+            let field = |name: &str| format!("services[{}].{}", i, name);
+            check_name(&field("name"), "service", &service.name)?;
+            if !names.insert(service.name.as_str()) {
+                return Err(invalid_field(&field("name"),
+                                         format!("Service '{}' is given twice", service.name)));
+            }
+            if service.cpu_perc.map(|perc| perc > 100).unwrap_or(false) {
+                return Err(invalid_field(&field("cpu_perc"), "Expected at most 100"));
+            }
+            for (j, network) in service.networks.iter().enumerate() {
+                let field = field(&format!("networks[{}]", j));
+                if network != DEFAULT_NETWORK && !self.networks.contains_key(network) {
+                    return Err(invalid_field(&field, format!("Unknown network '{}'", network)));
+                }
+                if service.networks[..j].contains(network) {
+                    return Err(invalid_field(&field,
+                                             format!("Network '{}' is given twice", network)));
+                }
+            }
+            let durations = [
+                ("restart_delay_secs", service.restart_delay_secs),
+                ("max_restart_delay_secs", service.max_restart_delay_secs),
+                ("restart_reset_secs", service.restart_reset_secs),
+                ("stop_timeout_secs", service.stop_timeout_secs),
+            ];
+            for &(name, secs) in &durations {
+                if !secs.is_finite() || secs < 0.0 {
+                    return Err(invalid_field(&field(name), "Expected a non-negative duration"));
+                }
+            }
+            if let Some(secs) = service.health_timeout_secs {
+                if !secs.is_finite() || secs < 0.0 {
+                    return Err(invalid_field(&field("health_timeout_secs"),
+                                             "Expected a non-negative duration"));
+                }
+                if service.health_check.is_none() {
+                    return Err(invalid_field(&field("health_timeout_secs"),
+                                             "Needs a health check"));
+                }
+            }
+            service.memory_limit().map_err(|e| invalid_field(&field("memory"), e))?;
+            if let Some(ref spec) = service.health_check {
+                let durations = [("interval", &spec.interval), ("timeout", &spec.timeout),
+                    ("start_period", &spec.start_period)];
+                for &(name, value) in &durations {
+                    if let Some(ref value) = *value {
+                        parse_duration(value).map_err(|e| {
+                            invalid_field(&field(&format!("health_check.{}", name)), e)
+                        })?;
+                    }
+                }
+            }
+            service.membership_signal()
+                .map_err(|e| invalid_field(&field("membership_signal"), e))?;
+            check_shaping(&field(""), &service.net_rate, &service.net_delay, &service.net_loss)?;
+        }
+        for (i, service) in self.services.iter().enumerate() {
+            let dep = service.depends_on.iter().enumerate()
+                .find(|&(_, dep)| !names.contains(dep.as_str()));
+            if let Some((j, dep)) = dep {
+                return Err(invalid_field(&format!("services[{}].depends_on[{}]", i, j),
+                                         format!("Unknown service '{}'", dep)));
+            }
+        }
+        self.start_order()?;
+
+        let replica_count = self.services.iter().map(|service| service.replicas).sum::<u32>();
+        for (i, partition) in self.partitions.iter().enumerate() {
+            for (j, group) in partition.groups.iter().enumerate() {
+                for (k, member) in group.iter().enumerate() {
+                    let valid = match *member {
+                        Member::Index(ix) => ix < replica_count,
+                        Member::Name(ref name) => self.services.iter().any(|service| {
+                            *name == service.name || (0..service.replicas)
+                                .any(|ix| *name == service.replica_name(ix))
+                        }),
+                    };
+                    if !valid {
+                        return Err(invalid_field(
+                            &format!("partitions[{}].groups[{}][{}]", i, j, k),
+                            format!("Unknown replica {:?}", member)));
+                    }
+                }
+            }
+        }
+        for (i, rule) in self.chaos.iter().flat_map(|chaos| chaos.events.iter()).enumerate() {
+            rule.validate(&format!("chaos.events[{}]", i), &self)?;
+        }
+        Ok(self)
+    }
+
+    // Indices of the services, each after the services it depends on.
+    // Services are ordered by levels, so that waiting for a dependency delays as few as possible.
+    pub fn start_order(&self) -> io::Result<Vec<usize>> {
+        let mut order: Vec<usize> = Vec::new();
+        while order.len() < self.services.len() {
+            let is_started = |name: &String| {
+                order.iter().any(|&ix| self.services[ix].name == *name)
+            };
+            let ready = (0..self.services.len())
+                .filter(|ix| !order.contains(ix)
+                    && self.services[*ix].depends_on.iter().all(is_started))
+                .collect::<Vec<_>>();
+            if ready.is_empty() {
+                let cycle = (0..self.services.len()).filter(|ix| !order.contains(ix))
+                    .collect::<Vec<_>>();
+                let names = cycle.iter().map(|&ix| self.services[ix].name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(invalid_field(&format!("services[{}].depends_on", cycle[0]), format!(
+                    "Dependencies of services {} form a cycle", names.join(", "))));
+            }
+            order.extend(ready);
+        }
+        Ok(order)
+    }
+
+    // The link conditions of the service override the ones of the config
+    pub fn shaping(&self, service: &Service) -> io::Result<Shaping> {
+        let or = |value: &Option<String>, default: &Option<String>| {
+            value.clone().or_else(|| default.clone())
+        };
+        parse_shaping(&or(&service.net_rate, &self.net_rate),
+                      &or(&service.net_delay, &self.net_delay),
+                      &or(&service.net_loss, &self.net_loss))
+    }
+}
+
+impl Service {
+    pub fn replica_name(&self, ix: u32) -> String {
+        format!("{}-{}", self.name, ix)
+    }
+
+    // Replicas of the single-service format keep their old output files
+    pub fn output_file(&self, output_dir_path: &str, ix: u32) -> String {
+        self.replica_file(output_dir_path, "output", ix)
+    }
+
+    pub fn stderr_file(&self, output_dir_path: &str, ix: u32) -> String {
+        self.replica_file(output_dir_path, "stderr", ix)
+    }
+
+    fn replica_file(&self, output_dir_path: &str, prefix: &str, ix: u32) -> String {
+        if self.name == LEGACY_SERVICE {
+            format!("{}/{}_{}.txt", output_dir_path, prefix, ix)
+        } else {
+            format!("{}/{}_{}.txt", output_dir_path, prefix, self.replica_name(ix))
+        }
+    }
+
+    // Keys of `Config::networks` the replicas are connected to, None for the default network
+    pub fn network_keys(&self) -> Vec<Option<String>> {
+        if self.networks.is_empty() {
+            return vec![None];
+        }
+        self.networks.iter()
+            .map(|network| Some(network.clone()).filter(|network| network != DEFAULT_NETWORK))
+            .collect()
+    }
+
+    pub fn health_check(&self) -> io::Result<Option<HealthCheck>> {
+        let spec = match self.health_check {
+            Some(ref spec) => spec,
+            None => return Ok(None),
+        };
+        let duration = |value: &Option<String>, default| match *value {
+            Some(ref value) => parse_duration(value),
+            None => Ok(default),
+        };
+        let check = HealthCheck::new(spec.cmd.clone());
+        Ok(Some(HealthCheck {
+            interval: duration(&spec.interval, check.interval)?,
+            timeout: duration(&spec.timeout, check.timeout)?,
+            retries: spec.retries.unwrap_or(check.retries),
+            start_period: duration(&spec.start_period, check.start_period)?,
+            ..check
+        }))
+    }
+
+    pub fn health_timeout(&self) -> Option<Duration> {
+        self.health_timeout_secs.map(Duration::from_secs_f64)
+    }
+
+    pub fn memory_limit(&self) -> io::Result<Option<u64>> {
+        self.memory.as_ref().map(|memory| parse_memory(memory)).transpose()
+    }
+
+    pub fn membership_signal(&self) -> io::Result<Option<c_int>> {
+        self.membership_signal.as_ref().map(|signal| parse_signal(signal)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(services: &str) -> io::Result<Config> {
+        parse_config(&format!(r#"{{"output_dir_path": "/tmp", {}}}"#, services), Format::Json)?
+            .normalize()
+    }
+
+    fn error(services: &str) -> String {
+        config(services).expect_err(services).to_string()
+    }
+
+    #[test]
+    fn legacy_config_is_a_single_service() {
+        let config = config(r#""container_image_path": "/img", "cmd": "/bin/sh",
+                                "replica_count": 2"#).unwrap();
+        assert_eq!(config.services.len(), 1);
+        assert_eq!(config.services[0].name, LEGACY_SERVICE);
+        assert_eq!(config.services[0].replicas, 2);
+        assert_eq!(config.services[0].replica_name(1), "replica-1");
+        assert!(error(r#""cmd": "/bin/sh""#).contains("container_image_path"));
+    }
+
+    #[test]
+    fn yaml_errors_are_located() {
+        let config = parse_config("output_dir_path: /tmp
+services:
+  - name: web
+    image: /img
+    cmd: sh
+    replicas: 2
+", Format::Yaml).unwrap();
+        assert_eq!(config.services[0].name, "web");
+        assert_eq!(config.services[0].replicas, 2);
+
+        let error = |data| parse_config(data, Format::Yaml).err().unwrap().to_string();
+        assert_eq!(error("output_dir_path: /tmp
+services:
+  - name: web
+    replicas: two
+"), "field 'services[0].replicas': invalid type: string \"two\", expected u32 \
+        at line 4 column 15");
+        assert!(error("services:\n  - name: web\n   image: /img\n")
+            .ends_with("at line 3 column 9"));
+    }
+
+    #[test]
+    fn toml_errors_are_located() {
+        let config = parse_config(r#"output_dir_path = "/tmp"
+
+[[services]]
+name = "web"
+image = "/img"
+cmd = "sh"
+args = ["-c", "true"]
+"#, Format::Toml).unwrap();
+        assert_eq!(config.services[0].cmd, "sh");
+        assert_eq!(config.services[0].args, vec!["-c", "true"]);
+
+        let error = |data| parse_config(data, Format::Toml).err().unwrap().to_string();
+        assert_eq!(error(r#"output_dir_path = "/tmp"
+[[services]]
+name = "web"
+replicas = "two"
+"#), "field 'services[0].replicas': invalid type: string \"two\", expected u32 \
+        for key `services.replicas` at line 4 column 12");
+        assert_eq!(error("[[services]]\nname = web\n"),
+                   "invalid TOML value, did you mean to use a quoted string? at line 2 column 8");
+    }
+
+    #[test]
+    fn services_start_after_their_dependencies() {
+        let config = config(r#""services": [
+            {"name": "web", "image": "/img", "cmd": "sh", "depends_on": ["api", "db"]},
+            {"name": "api", "image": "/img", "cmd": "sh", "depends_on": ["db"]},
+            {"name": "db", "image": "/img", "cmd": "sh"},
+            {"name": "cache", "image": "/img", "cmd": "sh"}]"#).unwrap();
+        assert_eq!(config.start_order().unwrap(), vec![2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn dependency_cycles_are_rejected() {
+        let message = error(r#""services": [
+            {"name": "db", "image": "/img", "cmd": "sh"},
+            {"name": "a", "image": "/img", "cmd": "sh", "depends_on": ["b", "db"]},
+            {"name": "b", "image": "/img", "cmd": "sh", "depends_on": ["a"]}]"#);
+        assert_eq!(message, "field 'services[1].depends_on': Dependencies of services a, b \
+        form a cycle");
+        assert!(error(r#""services": [
+            {"name": "a", "image": "/img", "cmd": "sh", "depends_on": ["a"]}]"#)
+            .contains("cycle"));
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let service = |fields: &str| error(&format!(r#""services": [
+            {{"name": "a", "image": "/img", "cmd": "sh"}},
+            {{"name": "b", "image": "/img", "cmd": "sh", {}}}]"#, fields));
+        assert!(service(r#""memory": "1Q""#).starts_with("field 'services[1].memory': "));
+        assert!(service(r#""cpu_perc": 101"#).starts_with("field 'services[1].cpu_perc': "));
+        assert!(service(r#""depends_on": ["c"]"#)
+            .starts_with("field 'services[1].depends_on[0]': "));
+        assert!(service(r#""networks": ["back"]"#)
+            .starts_with("field 'services[1].networks[0]': "));
+        assert!(service(r#""net_delay": "soon""#).starts_with("field 'services[1].net_delay': "));
+        assert!(service(r#""health_check": {"cmd": "true", "timeout": "1y"}"#)
+            .starts_with("field 'services[1].health_check.timeout': "));
+        assert!(service(r#""health_timeout_secs": 10"#)
+            .starts_with("field 'services[1].health_timeout_secs': "));
+        assert!(error(r#""services": [
+            {"name": "a", "image": "/img", "cmd": "sh"},
+            {"name": "a", "image": "/img", "cmd": "sh"}]"#)
+            .starts_with("field 'services[1].name': "));
+
+        let rule = |fields: &str| error(&format!(r#""services": [
+            {{"name": "a", "image": "/img", "cmd": "sh"}}],
+            "chaos": {{"events": [{{"action": "kill", "at_secs": 1}}, {{{}}}]}}"#, fields));
+        assert!(rule(r#""action": "kill", "at_secs": 1, "targets": ["a-1"]"#)
+            .starts_with("field 'chaos.events[1].targets[0]': "));
+        assert!(rule(r#""action": "kill", "at_secs": 1, "signal": "SIGFOO""#)
+            .starts_with("field 'chaos.events[1].signal': "));
+        assert!(rule(r#""action": "pause", "at_secs": 1"#)
+            .starts_with("field 'chaos.events[1].duration_secs': "));
+        assert!(rule(r#""action": "kill""#).starts_with("field 'chaos.events[1]': "));
+        assert!(rule(r#""action": "kill", "every_secs": 1"#)
+            .starts_with("field 'chaos.events[1].until_secs': "));
+        assert!(rule(r#""action": "throttle", "at_secs": 1, "duration_secs": 1, "cpu_perc": 0"#)
+            .starts_with("field 'chaos.events[1].cpu_perc': "));
+    }
+
+    #[test]
+    fn only_always_restarts_stopped_replicas() {
+        let (success, failure, killed) =
+            (ExitStatus::Exited(0), ExitStatus::Exited(1), ExitStatus::Signaled(15, false));
+        // restarted after a success, a failure and a stop
+        let cases = [
+            (RestartPolicy::No, [false, false, false]),
+            (RestartPolicy::OnFailure, [false, true, false]),
+            (RestartPolicy::Always, [true, true, true]),
+            (RestartPolicy::UnlessStopped, [true, true, false]),
+        ];
+        for &(policy, expected) in &cases {
+            let restarted = [policy.should_restart(success, false),
+                             policy.should_restart(failure, false),
+                             policy.should_restart(killed, true)];
+            assert_eq!(restarted, expected, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn signals_are_parsed_by_number_or_name() {
+        assert_eq!(parse_signal("9").unwrap(), libc::SIGKILL);
+        assert_eq!(parse_signal("SIGHUP").unwrap(), libc::SIGHUP);
+        assert_eq!(parse_signal("TERM").unwrap(), libc::SIGTERM);
+        for invalid in &["0", "65", "-1", "SIGFOO", "sigterm", ""] {
+            assert!(parse_signal(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use ::std::{fs, io, thread};
+use ::std::io::{BufRead, BufReader, Write};
+use ::std::os::unix::net::{UnixListener, UnixStream};
+use ::std::sync::{mpsc, Arc, Mutex};
+use ::core::aucont_paths::CLUSTERS_DIR;
+use ::core::check::Check;
+use ::container;
+use cluster::{self, Cluster};
+
+// A client sends a single line with the command and its arguments separated by spaces.
+// The supervisor replies with the lines of the result followed by "ok" or "error: MESSAGE".
+
+pub fn socket_path(name: &str) -> String {
+    format!("{}/{}.sock", CLUSTERS_DIR, name)
+}
+
+// Accepts the commands in a background thread. `shutdown` is notified once the replicas
+// are stopped by the shutdown command.
+pub fn serve(path: &str, cluster: Arc<Mutex<Cluster>>, shutdown: mpsc::Sender<()>)
+             -> io::Result<()> {
+    fs::create_dir_all(CLUSTERS_DIR)?;
+    // left by a crashed supervisor
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        res => res?,
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream.log_error("Error accepting control connection") {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let cluster = cluster.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            handle(stream, &cluster, &shutdown).log_error("Error in control connection");
+        });
+    });
+    Ok(())
+}
+
+fn handle(stream: UnixStream, cluster: &Mutex<Cluster>, shutdown: &mpsc::Sender<()>)
+          -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let mut out = &stream;
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let res = match words.as_slice() {
+        ["list"] => {
+            for row in cluster::list(cluster) {
+                writeln!(out, "{}", row.join("\t"))?;
+            }
+            Ok(())
+        },
+        ["scale", service, count] => match count.parse::<u32>() {
+            Ok(count) => cluster::scale(cluster, service, count).map(|(added, removed)| {
+                for name in added {
+                    let _ = writeln!(out, "Added {}", name);
+                }
+                for name in removed {
+                    let _ = writeln!(out, "Removed {}", name);
+                }
+            }),
+            Err(_) => Err(container::Error::simple(format!("Invalid replica count '{}'", count))),
+        },
+        ["restart", name] => cluster::restart(cluster, name).map(|_| ()),
+        ["rolling-restart", service] => cluster::rolling_restart(cluster, service, |name| {
+            let _ = writeln!(out, "Restarted {}", name);
+        }),
+        ["shutdown"] => cluster::shutdown(cluster).map(|()| {
+            let _ = shutdown.send(());
+        }),
+        _ => Err(container::Error::simple(format!("Unknown command '{}'", line.trim()))),
+    };
+    match res {
+        Ok(()) => writeln!(out, "ok"),
+        Err(e) => writeln!(out, "error: {}", e),
+    }
+}
+
+// The only running supervisor unless the cluster is given
+fn find_socket(cluster: Option<&str>) -> io::Result<String> {
+    if let Some(cluster) = cluster {
+        return Ok(socket_path(cluster));
+    }
+    let mut sockets = Vec::new();
+    match fs::read_dir(CLUSTERS_DIR) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        res => for entry in res? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".sock") {
+                sockets.push(name.trim_end_matches(".sock").to_string());
+            }
+        },
+    }
+    match sockets.len() {
+        0 => Err(io::Error::other("No cluster supervisor is running")),
+        1 => Ok(socket_path(&sockets[0])),
+        _ => {
+            sockets.sort();
+            Err(io::Error::other(format!(
+                "Several cluster supervisors are running, choose one with --cluster: {}",
+                sockets.join(", "))))
+        },
+    }
+}
+
+// Prints the result of the command, returns false if the command failed
+pub fn ctl(cluster: Option<&str>, command: &[&str]) -> io::Result<bool> {
+    let path = find_socket(cluster)?;
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Error connecting to {}: {}", path, e)))?;
+    writeln!(stream, "{}", command.join(" "))?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "ok" {
+            return Ok(true);
+        }
+        if line.starts_with("error: ") {
+            eprintln!("{}", line.replacen("error: ", "ERROR: ", 1));
+            return Ok(false);
+        }
+        println!("{}", line);
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The supervisor closed the connection"))
+}
@@ -1,126 +1,405 @@
-#[macro_use(shell, sudo)]
 extern crate aucont_core as core;
 extern crate aucont_lib_container as container;
+extern crate aucont_util_net as net;
 
+#[macro_use]
 extern crate clap;
 extern crate serde_json;
+extern crate serde_yaml;
+extern crate serde_path_to_error;
+extern crate toml;
+extern crate rand;
+extern crate libc;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate scopeguard;
 
-use ::std::{fs, process, env};
-use ::std::str::FromStr;
-use ::std::net::Ipv4Addr;
+mod config;
+mod replica;
+mod cluster;
+mod control;
+mod signals;
+mod output;
+mod membership;
+mod report;
+mod chaos;
+
+use ::std::{fs, io, process};
+use ::std::collections::HashSet;
+use ::std::sync::{mpsc, Arc, Mutex};
+use ::std::sync::atomic::{AtomicI32, Ordering};
 use ::core::check::Check;
-use ::std::thread::{self, JoinHandle};
+use ::std::thread;
+use ::std::time::{Duration, Instant, SystemTime};
 use ::core::getpid;
-use ::container::factory::*;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Config {
-    container_image_path: String,
-    cmd: String,
-    args: Vec<String>,
-    replica_count: u32,
-    output_dir_path: String,
+use ::core::aucont_paths::CLUSTERS_DIR;
+use ::container::partition::Partitions;
+use config::{Config, Partition, Member, Format, parse_config};
+use replica::Replica;
+use cluster::{Cluster, ClusterNetwork};
+use output::{Output, OutputMode};
+use membership::Membership;
+use report::{Report, unix_time};
+
+// Pairs of the groups whose links are cut, in the order of `Partition::groups`
+fn group_pairs<T: Clone>(partition: &Partition, groups: &[Vec<T>]) -> Vec<(Vec<T>, Vec<T>)> {
+    let mut pairs = Vec::new();
+    for (i, a) in groups.iter().enumerate() {
+        if partition.one_way && i > 0 {
+            break;
+        }
+        for b in &groups[i + 1..] {
+            pairs.push((a.clone(), b.clone()));
+        }
+    }
+    pairs
 }
 
-fn get_ip(last_octet: u8) -> String {
-    format!("10.0.2.{}", last_octet)
+fn resolve_member(replicas: &[Replica], config: &Config, member: &Member) -> Vec<Replica> {
+    match *member {
+        Member::Index(ix) => replicas.get(ix as usize).cloned().into_iter().collect(),
+        Member::Name(ref name) => replicas.iter()
+            .filter(|replica| {
+                replica.name == *name || config.services[replica.service].name == *name
+            })
+            .cloned().collect(),
+    }
 }
 
+// Cuts or heals the links between the pairs of the groups in each of their networks
+fn apply_partition(partition: &Partition, pairs: &[(Vec<Replica>, Vec<Replica>)], heal: bool) {
+    let networks = pairs.iter()
+        .flat_map(|(a, _)| a.iter().flat_map(|replica| replica.networks()))
+        .collect::<HashSet<_>>();
+    for network in networks {
+        let partitions = match Partitions::open(network).log_error("Error opening network") {
+            Some(partitions) => partitions,
+            None => continue,
+        };
+        let ips = |group: &[Replica]| group.iter()
+            .filter_map(|replica| replica.ip_in(network)).collect::<Vec<_>>();
+        for (a, b) in pairs {
+            let (a, b) = (ips(a), ips(b));
+            let res = match (heal, partition.one_way) {
+                (true, _) => partitions.heal(&a, &b),
+                (false, true) => partitions.cut_one_way(&a, &b),
+                (false, false) => partitions.cut(&a, &b),
+            };
+            res.log_error(if heal { "Error healing links" } else { "Error cutting links" });
+        }
+    }
+}
 
-fn start_replica(config: Config, replica_id: u32, replica_ip: Ipv4Addr, bridge: String)
-                 -> JoinHandle<container::Result<()>> {
+// The thread sleeps until the partition is due, errors are only logged
+fn schedule_partition(partition: Partition, groups: Vec<Vec<Replica>>) {
     thread::spawn(move || {
-        let output_path = format!("{}/output_{}.txt", config.output_dir_path, replica_id);
-        for attempt in 0..1000 {
-            let config = config.clone();
-            let container = ContainerFactory::new_container(ContainerConfig {
-                daemonize: true,
-                image_path: config.container_image_path,
-                cmd: config.cmd,
-                cmd_args: config.args,
-                net: Some(NetworkConfig {
-                    cont_addr: replica_ip,
-                    host_addr: None,
-                    host_bridge: Some(bridge.clone()),
-                }),
-                cpu_perc: None,
-                environment: vec![
-                    ("REPLICA_IX".to_string(), replica_id.to_string()),
-                    ("REPLICA_COUNT".to_string(), config.replica_count.to_string()),
-                    ("RESTART_NUMBER".to_string(), attempt.to_string()),
-                ],
-                redirect_stdout: Some(output_path.clone()),
-                ..Default::default()
-            })?;
-
-            let code = container.wait_and_clear()?;
-            if code == 0 { return Ok(()); }
-        }
-        Err(container::Error::simple("Maximal number of attempts exceeded"))
-    })
+        let pairs = group_pairs(&partition, &groups);
+        let names = groups.iter()
+            .map(|group| group.iter().map(|replica| replica.name.as_str()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_secs_f64(partition.at_secs.max(0.0)));
+        eprintln!("Cutting links between replica groups {:?}", names);
+        apply_partition(&partition, &pairs, false);
+
+        let duration = match partition.duration_secs {
+            Some(duration) => duration,
+            None => return,
+        };
+        thread::sleep(Duration::from_secs_f64(duration.max(0.0)));
+        eprintln!("Healing links between replica groups {:?}", names);
+        apply_partition(&partition, &pairs, true);
+    });
 }
 
+fn ctl_main(matches: &clap::ArgMatches) -> i32 {
+    let (command, args) = matches.subcommand();
+    let mut words = vec![command];
+    if let Some(args) = args {
+        for arg in &["service", "replica", "count"] {
+            words.extend(args.value_of(arg));
+        }
+    }
+    match control::ctl(matches.value_of("cluster"), &words) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            1
+        },
+    }
+}
 
 fn real_main() -> i32 {
+    let service_arg = || clap::Arg::with_name("service")
+        .index(1)
+        .required(true)
+        .value_name("SERVICE");
     let matches = clap::App::new("aucont_cluster")
         .version("0.1")
         .about("Orchestration tool for aucont containers.")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .arg(clap::Arg::with_name("config")
             .index(1)
             .required(true)
             .value_name("CONFIG_FILE")
             .help("Config file path"))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["json", "yaml", "toml"])
+            .help("Format of the config file, detected by its extension by default"))
+        .arg(clap::Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .value_name("MODE")
+            .possible_values(&["files", "prefixed", "json"])
+            .default_value("files")
+            .help("Besides the output files, show the output of the replicas prefixed with \
+                   their names or as JSON lines"))
+        .arg(clap::Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Colour the prefixes of the output, if it's a terminal by default"))
+        .arg(clap::Arg::with_name("report")
+            .long("report")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Where the JSON report of the run is written, report.json in the output \
+                   directory by default"))
+        .arg(clap::Arg::with_name("chaos_seed")
+            .long("chaos-seed")
+            .takes_value(true)
+            .value_name("SEED")
+            .help("Seed of the chaos schedule of the config, replaces the one given there"))
+        .arg(clap::Arg::with_name("supervise")
+            .long("supervise")
+            .help("Keep running after the replicas exit and accept commands of \
+                   'aucont_cluster ctl' until it's shut down"))
+        .subcommand(clap::SubCommand::with_name("ctl")
+            .about("Controls a cluster started with --supervise")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .arg(clap::Arg::with_name("cluster")
+                .long("cluster")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Name of the cluster, e.g. cluster-1234, required if several are running"))
+            .subcommand(clap::SubCommand::with_name("list")
+                .about("Lists the replicas with their state, address, container, \
+                        restarts and health"))
+            .subcommand(clap::SubCommand::with_name("scale")
+                .about("Adds or removes replicas of the service")
+                .arg(service_arg())
+                .arg(clap::Arg::with_name("count")
+                    .index(2)
+                    .required(true)
+                    .value_name("COUNT")))
+            .subcommand(clap::SubCommand::with_name("restart")
+                .about("Restarts the replica regardless of the restart policy")
+                .arg(clap::Arg::with_name("replica")
+                    .index(1)
+                    .required(true)
+                    .value_name("REPLICA")))
+            .subcommand(clap::SubCommand::with_name("rolling-restart")
+                .about("Restarts the replicas of the service one at a time, \
+                        waiting until each of them is ready")
+                .arg(service_arg()))
+            .subcommand(clap::SubCommand::with_name("shutdown")
+                .about("Stops the replicas and the supervisor")))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("ctl") {
+        return ctl_main(matches);
+    }
+
     let config_path = matches.value_of("config").unwrap();
+    let format = match matches.value_of("format") {
+        Some(format) => Format::parse(format).unwrap(),
+        None => Format::from_path(config_path),
+    };
 
-    let config_file = fs::File::open(config_path).check("ERROR opening config file");
-    let config: Config = serde_json::from_reader(config_file)
-        .check("ERROR reading or parsing config");
+    let config_data = fs::read_to_string(config_path).check("ERROR reading config file");
+    let config = parse_config(&config_data, format).check("ERROR parsing config");
+    let config = config.normalize().check("Invalid config");
+    let start_order = config.start_order().check("Invalid config");
+    let chaos_seed = match matches.value_of("chaos_seed") {
+        Some(_) => Some(value_t_or_exit!(matches.value_of("chaos_seed"), u64)),
+        None => config.chaos.as_ref().and_then(|chaos| chaos.seed),
+    };
+    let chaos_seed = config.chaos.as_ref().map(|_| chaos_seed.unwrap_or_else(rand::random));
+    // printed, so that a run with a random seed can be replayed
+    if let Some(seed) = chaos_seed {
+        eprintln!("Chaos seed: {}", seed);
+    }
+    let chaos_schedule = config.chaos.as_ref().zip(chaos_seed)
+        .map(|(chaos, seed)| chaos::schedule(&chaos.events, seed, &config));
 
+    let started_at = SystemTime::now();
+    let start_time = Instant::now();
     let pid = getpid();
-    let bridge = format!("auc{}br", pid);
-    sudo!("ip", "link", "add", &bridge, "type", "bridge").check("Error setting up network bridge");
+    let name = format!("cluster-{}", pid);
+    let mut networks = vec![ClusterNetwork::create(
+        None, name.clone(), &config.subnet, format!("auc{}br", pid))];
+    for (i, (key, spec)) in config.networks.iter().enumerate() {
+        networks.push(ClusterNetwork::create(
+            Some(key.clone()), format!("{}-{}", name, key), &spec.subnet,
+            format!("auc{}b{}", pid, i)));
+    }
+
+    let output_mode = OutputMode::parse(matches.value_of("output").unwrap()).unwrap();
+    let color = match matches.value_of("color").unwrap() {
+        "always" => true,
+        "never" => false,
+        _ => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+    };
+    let width = config.services.iter()
+        .map(|service| service.replica_name(service.replicas.saturating_sub(1)).len())
+        .max().unwrap_or(0);
+    let output = Output::new(output_mode, color, width);
+
+    let membership = Membership::create(format!("{}/{}", CLUSTERS_DIR, name))
+        .check("Error creating membership file");
+    let mut cluster = Cluster::new(config.clone(), networks, output, membership);
+    let mut replicas = Vec::new();
+    for (service_ix, service) in config.services.iter().enumerate() {
+        for _ in 0..service.replicas {
+            replicas.push(cluster.add_replica(service_ix).check("Error adding replica"));
+        }
+    }
+    cluster.update_membership().check("Error updating membership");
+    let cluster = Arc::new(Mutex::new(cluster));
+    // the networks are removed once the replicas are, even if the control thread is running
     defer! {{
-        sudo!("ip", "link", "del", &bridge).log_error("Error deleting network bridge");
+        let mut cluster = cluster.lock().unwrap();
+        cluster.networks.clear();
+        cluster.membership.remove().log_error("Error removing membership file");
     }};
-    sudo!("ip", "link", "set", &bridge, "up").check("Error setting up network bridge");
+
+    // the links may be changed with aucont_partition as well
+    for network in &cluster.lock().unwrap().networks {
+        eprintln!("Replica network: {}", network.network.name());
+    }
+    for partition in &config.partitions {
+        let groups = partition.groups.iter()
+            .map(|group| group.iter()
+                .flat_map(|member| resolve_member(&replicas, &config, member))
+                .collect())
+            .collect();
+        schedule_partition(partition.clone(), groups);
+    }
+    let chaos_log = config.chaos.as_ref().zip(chaos_schedule)
+        .map(|(chaos, schedule)| chaos::start(&chaos.events, schedule, cluster.clone()));
+
+    // notified once the replicas are stopped by a signal or by the shutdown command
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+    let signalled = Arc::new(AtomicI32::new(0));
+    {
+        let cluster = cluster.clone();
+        let signalled = signalled.clone();
+        let shutdown_sender = shutdown_sender.clone();
+        signals::handle_signals(move |signum| {
+            if signalled.compare_exchange(0, signum, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                eprintln!("Already stopping the replicas");
+                return;
+            }
+            eprintln!("Got signal {}, stopping the replicas", signum);
+            let cluster = cluster.clone();
+            let shutdown_sender = shutdown_sender.clone();
+            thread::spawn(move || {
+                cluster::shutdown(&cluster).log_error("Error stopping replicas");
+                let _ = shutdown_sender.send(());
+            });
+        }).check("Error installing signal handlers");
+    }
+
+    let supervise = matches.is_present("supervise");
+    let socket_path = control::socket_path(&name);
+    if supervise {
+        control::serve(&socket_path, cluster.clone(), shutdown_sender)
+            .check("Error creating control socket");
+        eprintln!("Control socket: {}", socket_path);
+    }
     defer! {{
-        sudo!("ip", "link", "set", &bridge, "down").log_error("Error disabling network bridge");
+        if supervise {
+            fs::remove_file(&socket_path).log_error("Error removing control socket");
+        }
     }};
-    sudo!("ip", "addr", "add", &get_ip(1), "dev", &bridge).check("Error setting ip address");
 
-    sudo!("iptables", "--append", "FORWARD", "--in-interface", &bridge, "--jump", "ACCEPT")
-        .check("Error configuring iptables");
-    defer! {{
-        sudo!("iptables", "--delete", "FORWARD", "--in-interface", &bridge, "--jump", "ACCEPT")
-            .log_error("Error removing rule from iptables");
-    }}
-
-    let ips: Vec<_> = (0..config.replica_count).map(|replica_id| {
-        let ip = &get_ip((replica_id + 100) as u8);
-        env::set_var(format!("REPLICA_{}_IP", replica_id), ip);
-        sudo!("ip", "route", "add", ip, "dev", &bridge).check("Error adding network route");
-        Ipv4Addr::from_str(ip).unwrap()
-    }).collect();
-
-    let threads: Vec<_> = (0..config.replica_count).map(|replica_id| {
-        start_replica(config.clone(), replica_id, ips[replica_id as usize], bridge.clone())
-    }).collect();
-
-    let mut failed = false;
-    for (replica_id, thread) in threads.into_iter().enumerate() {
-        let error_string = &format!("Error in replica manager {}", replica_id);
-        if let None = thread.join().unwrap().log_error(error_string) {
-            failed = true;
+    // a service is started once the first containers of all the replicas of its dependencies
+    // are created. Services which depend on failed ones aren't started at all.
+    let mut started: Vec<Vec<mpsc::Receiver<()>>> = config.services.iter().map(|_| Vec::new())
+        .collect();
+    let mut failed_services = HashSet::new();
+    for service_ix in start_order {
+        let service = &config.services[service_ix];
+        let mut failed_dep = None;
+        for dep in &service.depends_on {
+            let dep_ix = config.services.iter().position(|other| other.name == *dep).unwrap();
+            for receiver in started[dep_ix].drain(..) {
+                if receiver.recv().is_err() {
+                    failed_services.insert(dep_ix);
+                }
+            }
+            if failed_services.contains(&dep_ix) {
+                failed_dep = Some(dep);
+            }
+        }
+        // the dependencies are stopped rather than failed
+        let mut cluster = cluster.lock().unwrap();
+        if cluster.stopping {
+            break;
+        }
+        if let Some(dep) = failed_dep {
+            eprintln!("Service '{}' isn't started, since '{}' failed to start", service.name, dep);
+            failed_services.insert(service_ix);
+            continue;
+        }
+
+        for replica in replicas.iter().filter(|replica| replica.service == service_ix) {
+            let (sender, receiver) = mpsc::channel();
+            started[service_ix].push(receiver);
+            cluster.start(&replica.name, sender).check("Error starting replica");
         }
     }
 
-    if failed { 1 } else { 0 }
+    let failed = if supervise {
+        // the replicas are joined by the shutdown
+        let _ = shutdown_receiver.recv();
+        false
+    } else {
+        let failed = !cluster::join(&cluster) || !failed_services.is_empty();
+        if signalled.load(Ordering::SeqCst) != 0 {
+            // the addresses of the replicas must be released before the networks are removed
+            let _ = shutdown_receiver.recv();
+        }
+        failed
+    };
+    // the shell convention for the processes killed by a signal
+    let exit_code = match signalled.load(Ordering::SeqCst) {
+        0 => if failed { 1 } else { 0 },
+        signum => 128 + signum,
+    };
+
+    let report = Report {
+        cluster: name,
+        started_at: unix_time(started_at),
+        duration_secs: start_time.elapsed().as_secs_f64(),
+        exit_code,
+        replicas: cluster.lock().unwrap().report(),
+        chaos_seed,
+        chaos_events: chaos_log.map(|log| log.events()).unwrap_or_default(),
+    };
+    let report_path = matches.value_of("report").map(|path| path.to_string())
+        .unwrap_or_else(|| format!("{}/report.json", config.output_dir_path));
+    report.write(&report_path).log_error("Error writing report");
+    eprintln!();
+    report.print_summary(io::stderr()).log_error("Error printing summary");
+    eprintln!("Report: {}", report_path);
+    exit_code
 }
 
 fn main() {
@@ -0,0 +1,97 @@
+use ::std::{fs, io};
+use ::std::net::IpAddr;
+use ::std::sync::Mutex;
+
+// Where the membership directory is mounted in the containers
+pub const MEMBERSHIP_MOUNT: &str = "/run/cluster";
+pub const MEMBERS_FILE: &str = "members";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub service: String,
+    pub ix: u32,
+    // (network, address) pairs, the first one is listed in the file
+    pub addrs: Vec<(String, IpAddr)>,
+}
+
+impl Member {
+    // "SERVICE_IX_IP" with the name in upper case, e.g. "REPLICA_0_IP" or "DB_0_IP"
+    pub fn ip_variable(&self) -> String {
+        format!("{}_{}_IP", self.service.to_ascii_uppercase().replace('-', "_"), self.ix)
+    }
+
+    // The address in the first of the `networks` the member is connected to,
+    // or its first address if it shares none of them
+    fn ip_in(&self, networks: &[&str]) -> IpAddr {
+        networks.iter()
+            .filter_map(|network| self.addrs.iter().find(|addr| addr.0 == *network))
+            .next()
+            .unwrap_or(&self.addrs[0]).1
+    }
+}
+
+// Replicas of the cluster, written to "DIR/members" with a "NAME SERVICE IX IP" line each.
+// The file is replaced on each change, so that the replicas never read a partially written one.
+// That's why the directory rather than the file is mounted into the containers.
+pub struct Membership {
+    dir: String,
+    members: Mutex<Vec<Member>>,
+}
+
+impl Membership {
+    pub fn create(dir: String) -> io::Result<Membership> {
+        fs::create_dir_all(&dir)?;
+        let membership = Membership { dir, members: Mutex::new(Vec::new()) };
+        membership.write(&[])?;
+        Ok(membership)
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    fn write(&self, members: &[Member]) -> io::Result<()> {
+        let data = members.iter()
+            .map(|member| format!("{} {} {} {}\n", member.name, member.service, member.ix,
+                                  member.addrs[0].1))
+            .collect::<String>();
+        let tmp_path = format!("{}/.{}.tmp", self.dir, MEMBERS_FILE);
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, format!("{}/{}", self.dir, MEMBERS_FILE))
+    }
+
+    // Returns whether the members have changed
+    pub fn update(&self, members: Vec<Member>) -> io::Result<bool> {
+        let mut current = self.members.lock().unwrap();
+        if *current == members {
+            return Ok(false);
+        }
+        self.write(&members)?;
+        *current = members;
+        Ok(true)
+    }
+
+    // Addresses of the members as seen by a replica in the `networks`
+    pub fn hosts(&self, networks: &[&str]) -> Vec<(String, IpAddr)> {
+        self.members.lock().unwrap().iter()
+            .map(|member| (member.name.clone(), member.ip_in(networks)))
+            .collect()
+    }
+
+    // "SERVICE_IX_IP" variables of the members, which aren't updated in the running containers
+    pub fn ip_variables(&self, networks: &[&str]) -> Vec<(String, String)> {
+        self.members.lock().unwrap().iter()
+            .map(|member| (member.ip_variable(), member.ip_in(networks).to_string()))
+            .collect()
+    }
+
+    pub fn count(&self, service: &str) -> u32 {
+        self.members.lock().unwrap().iter().filter(|member| member.service == service).count()
+            as u32
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+}
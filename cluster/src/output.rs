@@ -0,0 +1,152 @@
+use ::std::{fs, io, thread};
+use ::std::io::{BufRead, BufReader, Write};
+use ::std::os::unix::io::{FromRawFd, RawFd};
+use ::std::sync::Arc;
+use ::std::time::SystemTime;
+use ::core::check::Check;
+use ::libc;
+use ::serde_json;
+use report::unix_time;
+
+// ANSI colours of the replica prefixes, red is left for the errors
+const COLORS: [u8; 5] = [32, 33, 34, 35, 36];
+
+// Besides being written to the output files, the output of the replicas may be shown
+// by the cluster itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    // only the files
+    Files,
+    // "NAME | LINE", stderr of the replicas goes to stderr of the cluster
+    Prefixed,
+    // a JSON object per line, see `Record`
+    Json,
+}
+
+impl OutputMode {
+    pub fn parse(name: &str) -> Option<OutputMode> {
+        match name {
+            "files" => Some(OutputMode::Files),
+            "prefixed" => Some(OutputMode::Prefixed),
+            "json" => Some(OutputMode::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    // seconds since the Unix epoch
+    time: f64,
+    replica: &'a str,
+    service: &'a str,
+    stream: &'a str,
+    restart: u32,
+    line: &'a str,
+}
+
+pub struct Output {
+    mode: OutputMode,
+    color: bool,
+    // the prefixes are padded to this width
+    width: usize,
+}
+
+// What a reader of the container output needs to know about its source
+#[derive(Clone)]
+pub struct Source {
+    pub replica: String,
+    pub service: String,
+    pub restart: u32,
+    // index in `COLORS` modulo its length
+    pub color: usize,
+}
+
+// Where the output of a replica goes
+pub struct ReplicaOutput {
+    pub output: Arc<Output>,
+    pub stdout_path: String,
+    pub stderr_path: String,
+}
+
+fn pipe() -> io::Result<(fs::File, fs::File)> {
+    let mut fds: [RawFd; 2] = [0, 0];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) })
+}
+
+impl Output {
+    pub fn new(mode: OutputMode, color: bool, width: usize) -> Arc<Output> {
+        Arc::new(Output { mode, color, width })
+    }
+
+    // Returns the write end of a pipe which is to become stdout or stderr of the container.
+    // The output is appended to the file at `path` until all the write ends are closed.
+    // None if the output is only written to the files, the container appends to them then.
+    pub fn capture(self: &Arc<Self>, source: &Source, stream: &'static str, path: &str)
+                   -> io::Result<Option<fs::File>> {
+        if self.mode == OutputMode::Files {
+            return Ok(None);
+        }
+        let (reader, writer) = pipe()?;
+        let mut file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        let output = self.clone();
+        let source = source.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {},
+                    Err(e) => {
+                        eprintln!("Error reading output of replica {}: {}", source.replica, e);
+                        break;
+                    },
+                }
+                file.write_all(&line).log_error("Error writing replica output");
+                output.show(&source, stream, &line);
+            }
+        });
+        Ok(Some(writer))
+    }
+
+    fn show(&self, source: &Source, stream: &str, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\n');
+        match self.mode {
+            OutputMode::Files => {},
+            OutputMode::Prefixed => {
+                let prefix = if self.color {
+                    format!("\x1b[{}m{:width$} |\x1b[0m", COLORS[source.color % COLORS.len()],
+                            source.replica, width = self.width)
+                } else {
+                    format!("{:width$} |", source.replica, width = self.width)
+                };
+                // a whole line is written at once
+                let _ = if stream == "stderr" {
+                    writeln!(io::stderr().lock(), "{} {}", prefix, line)
+                } else {
+                    writeln!(io::stdout().lock(), "{} {}", prefix, line)
+                };
+            },
+            OutputMode::Json => {
+                let record = Record {
+                    time: unix_time(SystemTime::now()),
+                    replica: &source.replica,
+                    service: &source.service,
+                    stream,
+                    restart: source.restart,
+                    line,
+                };
+                let json = serde_json::to_string(&record).log_error("Error encoding output");
+                if let Some(json) = json {
+                    let _ = writeln!(io::stdout().lock(), "{}", json);
+                }
+            },
+        }
+    }
+}
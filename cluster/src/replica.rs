@@ -0,0 +1,440 @@
+use ::std::fmt;
+use ::std::net::IpAddr;
+use ::std::sync::{mpsc, Arc, Mutex};
+use ::std::thread::{self, JoinHandle};
+use ::std::time::{Duration, Instant, SystemTime};
+use ::std::os::unix::io::AsRawFd;
+use ::rand::{self, Rng};
+use ::libc;
+use ::core::raw_process::{pid_t, RawProcess};
+use ::core::check::Check;
+use ::container;
+use ::container::CommentError;
+use ::container::{Container, Finished, Shaping};
+use ::container::factory::*;
+use ::container::health::{read_health, HealthStatus};
+use config::Service;
+use membership::{Membership, MEMBERSHIP_MOUNT, MEMBERS_FILE};
+use output::{ReplicaOutput, Source};
+use report::{Run, Sampler};
+
+// How often the health of the replicas is polled, the checks themselves run less often
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// How often the replicas which are stopped or restarted by the supervisor are checked
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Replica of a service with the addresses leased for it
+#[derive(Clone)]
+pub struct Replica {
+    // index in `Config::services`
+    pub service: usize,
+    pub ix: u32,
+    pub name: String,
+    // one in each network of the service, in the order of `Service::networks`
+    pub addrs: Vec<ReplicaAddr>,
+}
+
+#[derive(Clone)]
+pub struct ReplicaAddr {
+    pub network: String,
+    pub bridge: String,
+    pub ip: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Replica {
+    // Address in the first network of the service
+    pub fn ip(&self) -> IpAddr {
+        self.addrs[0].ip
+    }
+
+    pub fn ip_in(&self, network: &str) -> Option<IpAddr> {
+        self.addrs.iter().find(|addr| addr.network == network).map(|addr| addr.ip)
+    }
+
+    pub fn networks(&self) -> Vec<&str> {
+        self.addrs.iter().map(|addr| addr.network.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaState {
+    // the container is created, or it's waiting to become healthy
+    Starting,
+    Running,
+    // waiting for the restart delay
+    Restarting,
+    Exited,
+    Failed,
+}
+
+impl fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplicaState::Starting => write!(f, "starting"),
+            ReplicaState::Running => write!(f, "running"),
+            ReplicaState::Restarting => write!(f, "restarting"),
+            ReplicaState::Exited => write!(f, "exited"),
+            ReplicaState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+// Shared by the replica manager and the supervisor, which stops and restarts the replicas
+pub struct ReplicaControl {
+    pub state: ReplicaState,
+    // the current container, if any
+    pub container: Option<pid_t>,
+    // restarts since the replica manager was started, including the requested ones
+    pub restarts: u32,
+    // the current container is running, and healthy if the service has a health check
+    pub ready: bool,
+    // the replica is being removed, its manager exits once the container does
+    pub removed: bool,
+    // the container is restarted right away once it exits, regardless of the restart policy
+    pub restart_requested: bool,
+    // the containers which have exited
+    pub runs: Vec<Run>,
+}
+
+pub type Control = Arc<Mutex<ReplicaControl>>;
+
+impl ReplicaControl {
+    pub fn new() -> Control {
+        Arc::new(Mutex::new(ReplicaControl {
+            state: ReplicaState::Starting,
+            container: None,
+            restarts: 0,
+            ready: false,
+            removed: false,
+            restart_requested: false,
+            runs: Vec::new(),
+        }))
+    }
+}
+
+// Exponential backoff with up to a half of the delay drawn from `rng`
+fn restart_delay<R: Rng>(service: &Service, restarts: u32, rng: &mut R) -> Duration {
+    let delay = (service.restart_delay_secs * 2f64.powi(restarts.min(64) as i32))
+        .min(service.max_restart_delay_secs);
+    Duration::from_secs_f64(delay * rng.gen_range(0.5, 1.0))
+}
+
+// Waits for the container to exit. Once it's healthy for the first time, `healthy` is notified.
+// An unhealthy container is killed, which is reported as the second value.
+// Containers which aren't healthy within `timeout` are killed as unhealthy ones,
+// and `healthy` is dropped then, so that the services waiting for the replica don't start.
+// `ready_at` is set once the container is healthy.
+fn wait_healthy(container: Container, name: &str, control: &Control,
+                healthy: &mut Option<mpsc::Sender<()>>, ready_at: &mut Option<Instant>,
+                timeout: Option<Duration>) -> container::Result<(Finished, bool)> {
+    let id = container.get_id();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(container.wait_and_clear_stats()));
+    let mut unhealthy = false;
+    loop {
+        if let Ok(res) = receiver.recv_timeout(HEALTH_POLL_INTERVAL) {
+            return res.map(|res| (res, unhealthy));
+        }
+        // the status is missing once the container is cleared
+        let health = read_health(id);
+        let timed_out = ready_at.is_none() && deadline.map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false);
+        match health {
+            Ok(Some(HealthStatus::Healthy)) => {
+                ready_at.get_or_insert_with(Instant::now);
+                {
+                    let mut control = control.lock().unwrap();
+                    control.state = ReplicaState::Running;
+                    control.ready = true;
+                }
+                if let Some(healthy) = healthy.take() {
+                    let _ = healthy.send(());
+                }
+            },
+            Ok(Some(HealthStatus::Unhealthy)) if !unhealthy => {
+                eprintln!("Replica {} is unhealthy, killing it", name);
+                unhealthy = true;
+                RawProcess::from_pid(id).signal(libc::SIGKILL)
+                    .log_error(format!("Error killing replica {}", name));
+            },
+            _ if timed_out && !unhealthy => {
+                eprintln!("Replica {} isn't healthy after {:.1}s, killing it", name,
+                          timeout.unwrap().as_secs_f64());
+                unhealthy = true;
+                healthy.take();
+                RawProcess::from_pid(id).signal(libc::SIGKILL)
+                    .log_error(format!("Error killing replica {}", name));
+            },
+            _ => {},
+        }
+    }
+}
+
+// Sleeps for `delay` unless the replica is removed or a restart is requested meanwhile
+fn sleep_interruptible(control: &Control, delay: Duration) {
+    let deadline = Instant::now() + delay;
+    loop {
+        {
+            let control = control.lock().unwrap();
+            if control.removed || control.restart_requested {
+                return;
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep(CONTROL_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+// The names of the replicas and their count are taken from `membership` for each container.
+// `started` is notified once the first container of the replica is created,
+// or once it's healthy if the service has a health check.
+// The replica is restarted according to the restart policy of its service,
+// or on request of the supervisor, see `ReplicaControl`.
+pub fn start_replica(service: Service, replica: Replica, output: ReplicaOutput,
+                     membership: Arc<Membership>, shaping: Shaping, started: mpsc::Sender<()>,
+                     control: Control) -> JoinHandle<container::Result<()>> {
+    thread::spawn(move || {
+        let res = run_replica(&service, &replica, &output, &membership, shaping, started,
+                              &control);
+        control.lock().unwrap().state = match res {
+            Ok(()) => ReplicaState::Exited,
+            Err(_) => ReplicaState::Failed,
+        };
+        res
+    })
+}
+
+fn run_replica(service: &Service, replica: &Replica, output: &ReplicaOutput,
+               membership: &Membership, shaping: Shaping, started: mpsc::Sender<()>,
+               control: &Control) -> container::Result<()> {
+    // checked by `Config::normalize`
+    let memory_limit = service.memory_limit().ok().flatten();
+    let mut started = Some(started);
+    // restarts in a row, i.e. since the replica was last ready for `restart_reset_secs`
+    let mut restarts = 0;
+    for restart_number in 0u32.. {
+        {
+            let mut control = control.lock().unwrap();
+            if control.removed {
+                return Ok(());
+            }
+            control.state = ReplicaState::Starting;
+        }
+        // the variables of the service may override the addresses of the members
+        let mut environment = membership.ip_variables(&replica.networks());
+        environment.extend(service.env.clone());
+        environment.extend(vec![
+            ("SERVICE_NAME".to_string(), service.name.clone()),
+            ("REPLICA_IX".to_string(), replica.ix.to_string()),
+            ("REPLICA_COUNT".to_string(), membership.count(&service.name).to_string()),
+            ("RESTART_NUMBER".to_string(), restart_number.to_string()),
+            ("CLUSTER_MEMBERS".to_string(), format!("{}/{}", MEMBERSHIP_MOUNT, MEMBERS_FILE)),
+        ]);
+        let source = Source {
+            replica: replica.name.clone(),
+            service: service.name.clone(),
+            restart: restart_number,
+            color: replica.service + replica.ix as usize,
+        };
+        let stdout = output.output.capture(&source, "stdout", &output.stdout_path)
+            .comment_error("Error capturing replica output")?;
+        let stderr = output.output.capture(&source, "stderr", &output.stderr_path)
+            .comment_error("Error capturing replica output")?;
+        // the write ends are closed once the container is created, so that the output
+        // ends once the container exits
+        let mut container = ContainerFactory::new_container(ContainerConfig {
+            daemonize: true,
+            image_path: service.image.clone(),
+            cmd: service.cmd.clone(),
+            cmd_args: service.args.clone(),
+            net: Some(NetworkConfig {
+                addrs: vec![NetworkAddress::host(replica.ip(), None)],
+                host_bridge: Some(replica.addrs[0].bridge.clone()),
+                shaping,
+                attachments: replica.addrs[1..].iter().map(|addr| Attachment {
+                    bridge: addr.bridge.clone(),
+                    addrs: vec![(addr.ip, addr.prefix_len)],
+                }).collect(),
+                ..Default::default()
+            }),
+            cpu_perc: service.cpu_perc,
+            memory_limit,
+            health_check: service.health_check().ok().flatten(),
+            hosts: membership.hosts(&replica.networks()),
+            mounts: vec![BindMount {
+                source: membership.dir().to_string(),
+                target: MEMBERSHIP_MOUNT.to_string(),
+                read_only: true,
+            }],
+            environment,
+            redirect_stdout: Some(output.stdout_path.clone()),
+            redirect_stderr: Some(output.stderr_path.clone()),
+            append_output: true,
+            stdout_fd: stdout.as_ref().map(AsRawFd::as_raw_fd),
+            stderr_fd: stderr.as_ref().map(AsRawFd::as_raw_fd),
+            ..Default::default()
+        })?;
+        drop((stdout, stderr));
+        {
+            let mut control = control.lock().unwrap();
+            control.container = Some(container.get_id());
+            control.restart_requested = false;
+            control.ready = service.health_check.is_none();
+            if control.ready {
+                control.state = ReplicaState::Running;
+            }
+            // the supervisor couldn't stop the container before it was registered
+            if control.removed {
+                container.cancel(libc::SIGKILL)?;
+            }
+        }
+
+        let start_time = Instant::now();
+        let started_at = SystemTime::now();
+        let sampler = Sampler::start(container.get_id());
+        let mut ready_at = None;
+        let res = if service.health_check.is_some() {
+            wait_healthy(container, &replica.name, control, &mut started, &mut ready_at,
+                         service.health_timeout())
+        } else {
+            ready_at = Some(start_time);
+            if let Some(started) = started.take() {
+                let _ = started.send(());
+            }
+            container.wait_and_clear_stats().map(|finished| (finished, false))
+        };
+        let usage = sampler.finish(res.as_ref().map(|&(finished, _)| finished.stats)
+            .unwrap_or_default());
+        let (removed, requested) = {
+            let mut control = control.lock().unwrap();
+            control.container = None;
+            control.ready = false;
+            if let Ok((finished, unhealthy)) = res {
+                control.runs.push(Run::new(restart_number, start_time, started_at,
+                                           finished.status, finished.stopped, unhealthy, usage));
+            }
+            (control.removed, control.restart_requested)
+        };
+        let (Finished { status, stopped, .. }, unhealthy) = res?;
+        if removed {
+            return Ok(());
+        }
+        let ready_for = ready_at.map(|ready_at| ready_at.elapsed().as_secs_f64());
+        if ready_for.map(|secs| secs >= service.restart_reset_secs).unwrap_or(false) {
+            restarts = 0;
+        }
+        let reason = if unhealthy {
+            "was unhealthy".to_string()
+        } else if stopped {
+            format!("was stopped ({})", status)
+        } else {
+            format!("exited with {}", status)
+        };
+
+        if requested {
+            eprintln!("Replica {} {}, restarting on request", replica.name, reason);
+            control.lock().unwrap().restarts += 1;
+            continue;
+        }
+        if !service.restart.should_restart(status, stopped) {
+            if status.success() || stopped {
+                return Ok(());
+            }
+            return Err(container::Error::simple(format!("Replica {}", reason)));
+        }
+        if service.max_restarts.map(|max| restarts >= max).unwrap_or(false) {
+            return Err(container::Error::simple(format!(
+                "Replica {}, maximal number of restarts in a row exceeded", reason)));
+        }
+        let delay = restart_delay(service, restarts, &mut rand::thread_rng());
+        restarts += 1;
+        {
+            let mut control = control.lock().unwrap();
+            control.state = ReplicaState::Restarting;
+            control.restarts += 1;
+        }
+        eprintln!("Replica {} {}, restarting in {:.1}s (restart {} in a row)",
+                  replica.name, reason, delay.as_secs_f64(), restarts);
+        sleep_interruptible(control, delay);
+    }
+    unreachable!()
+}
+
+// Sends SIGTERM to the current container of the replica and SIGKILL if it's still running
+// after `timeout`. Returns once the container has exited.
+pub fn stop_container(control: &Control, timeout: Duration) -> container::Result<()> {
+    let id = match control.lock().unwrap().container {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let is_current = || control.lock().unwrap().container == Some(id);
+    let signal = |signal| match Container::open(id).and_then(|mut c| c.cancel(signal)) {
+        // the container has exited meanwhile
+        Err(_) if !is_current() => Ok(()),
+        res => res,
+    };
+    signal(libc::SIGTERM)?;
+    let deadline = Instant::now() + timeout;
+    let mut killed = false;
+    while is_current() {
+        if !killed && Instant::now() >= deadline {
+            signal(libc::SIGKILL)?;
+            killed = true;
+        }
+        thread::sleep(CONTROL_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+// Waits until a new container of the replica is ready, fails if the replica exits meanwhile
+// or if it isn't ready within `timeout`
+pub fn wait_ready(name: &str, control: &Control, timeout: Option<Duration>)
+                  -> container::Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        {
+            let control = control.lock().unwrap();
+            if control.ready {
+                return Ok(());
+            }
+            if control.state == ReplicaState::Exited || control.state == ReplicaState::Failed {
+                return Err(container::Error::simple(format!(
+                    "Replica {} is {}", name, control.state)));
+            }
+        }
+        if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            return Err(container::Error::simple(format!(
+                "Replica {} isn't ready after {:.1}s", name, timeout.unwrap().as_secs_f64())));
+        }
+        thread::sleep(CONTROL_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rand::{SeedableRng, XorShiftRng};
+    use config::{parse_config, Format};
+
+    #[test]
+    fn restart_delay_doubles_up_to_the_maximum() {
+        let config = parse_config(r#"{"output_dir_path": "/tmp", "services": [
+            {"name": "web", "image": "/img", "cmd": "sh",
+             "restart_delay_secs": 1, "max_restart_delay_secs": 10}]}"#, Format::Json)
+            .unwrap().normalize().unwrap();
+        let seed = [1, 2, 3, 4];
+        let jitter = XorShiftRng::from_seed(seed).gen_range(0.5, 1.0);
+        let delay = |restarts| {
+            restart_delay(&config.services[0], restarts, &mut XorShiftRng::from_seed(seed))
+        };
+        assert_eq!(delay(0), Duration::from_secs_f64(jitter));
+        assert_eq!(delay(3), Duration::from_secs_f64(8.0 * jitter));
+        assert_eq!(delay(4), Duration::from_secs_f64(10.0 * jitter));
+        assert_eq!(delay(1000), Duration::from_secs_f64(10.0 * jitter));
+    }
+}
@@ -0,0 +1,199 @@
+use ::std::{fs, io, thread};
+use ::std::io::Write;
+use ::std::sync::mpsc;
+use ::std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ::container::{cgroup_stats, pid_t, CgroupStats, ExitStatus};
+use ::serde_json;
+use chaos::ChaosEvent;
+
+// How often the resource usage of the containers is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|time| time.as_secs_f64()).unwrap_or(0.0)
+}
+
+// Resource usage of a container, None if it isn't accounted in a cgroup of the container
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub cpu_time: Option<Duration>,
+    // 100% is a single CPU, averaged over `SAMPLE_INTERVAL`
+    pub peak_cpu_perc: Option<f64>,
+    pub peak_memory: Option<u64>,
+}
+
+// Samples the usage in a separate thread until `finish`
+pub struct Sampler {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<Usage>,
+}
+
+impl Sampler {
+    pub fn start(id: pid_t) -> Sampler {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut usage = Usage::default();
+            let mut last: Option<(Instant, Duration)> = None;
+            loop {
+                // the cgroups can't be found once the container has exited
+                let stats = match cgroup_stats(id) {
+                    Ok(stats) => stats,
+                    Err(_) => return usage,
+                };
+                let now = Instant::now();
+                if let Some(cpu_time) = stats.cpu_time {
+                    if let Some((time, last_cpu_time)) = last {
+                        let perc = 100.0 * cpu_time.saturating_sub(last_cpu_time).as_secs_f64()
+                            / (now - time).as_secs_f64();
+                        usage.peak_cpu_perc = Some(usage.peak_cpu_perc.unwrap_or(0.0).max(perc));
+                    }
+                    usage.cpu_time = Some(cpu_time);
+                    last = Some((now, cpu_time));
+                }
+                usage.peak_memory = stats.memory_peak.or(usage.peak_memory);
+                if stopped.recv_timeout(SAMPLE_INTERVAL) != Err(mpsc::RecvTimeoutError::Timeout) {
+                    return usage;
+                }
+            }
+        });
+        Sampler { stop, thread }
+    }
+
+    // `last` are the stats read right before the cgroups of the container were removed
+    pub fn finish(self, last: CgroupStats) -> Usage {
+        drop(self.stop);
+        let usage = self.thread.join().unwrap();
+        Usage {
+            cpu_time: last.cpu_time.or(usage.cpu_time),
+            peak_memory: last.memory_peak.or(usage.peak_memory),
+            ..usage
+        }
+    }
+}
+
+// A container of a replica from its start until it exited
+#[derive(Serialize, Debug, Clone)]
+pub struct Run {
+    // "RESTART_NUMBER" of the container
+    pub restart: u32,
+    // seconds since the Unix epoch
+    pub started_at: f64,
+    pub duration_secs: f64,
+    // e.g. "exit code 1" or "killed by signal 9"
+    pub exit_status: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    // cancelled, e.g. by aucont_stop or by the supervisor
+    pub stopped: bool,
+    pub unhealthy: bool,
+    pub cpu_time_secs: Option<f64>,
+    pub peak_cpu_perc: Option<f64>,
+    pub peak_memory_bytes: Option<u64>,
+}
+
+impl Run {
+    // `start_time` measures the duration, `started_at` is only reported
+    pub fn new(restart: u32, start_time: Instant, started_at: SystemTime, status: ExitStatus,
+               stopped: bool, unhealthy: bool, usage: Usage) -> Run {
+        let (exit_code, signal) = match status {
+            ExitStatus::Exited(code) => (Some(code), None),
+            ExitStatus::Signaled(signal, _) => (None, Some(signal)),
+        };
+        Run {
+            restart,
+            started_at: unix_time(started_at),
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            exit_status: status.to_string(),
+            exit_code,
+            signal,
+            stopped,
+            unhealthy,
+            cpu_time_secs: usage.cpu_time.map(|time| time.as_secs_f64()),
+            peak_cpu_perc: usage.peak_cpu_perc,
+            peak_memory_bytes: usage.peak_memory,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReplicaReport {
+    pub name: String,
+    pub service: String,
+    pub ix: u32,
+    pub ip: String,
+    // the final one, or "removed" for the replicas removed by scaling down
+    pub state: String,
+    pub restarts: u32,
+    pub stdout_file: String,
+    pub stderr_file: String,
+    pub runs: Vec<Run>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub cluster: String,
+    pub started_at: f64,
+    pub duration_secs: f64,
+    pub exit_code: i32,
+    pub replicas: Vec<ReplicaReport>,
+    // None without a chaos schedule
+    pub chaos_seed: Option<u64>,
+    pub chaos_events: Vec<ChaosEvent>,
+}
+
+impl Report {
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let data = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data + "\n")
+    }
+
+    // A line per replica with the totals of its runs
+    pub fn print_summary<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut rows = vec![["REPLICA", "STATE", "RESTARTS", "LAST EXIT", "UPTIME", "PEAK CPU",
+                             "PEAK MEMORY"].iter().map(|s| s.to_string()).collect::<Vec<_>>()];
+        for replica in &self.replicas {
+            let uptime = replica.runs.iter().map(|run| run.duration_secs).sum::<f64>();
+            let cpu = replica.runs.iter().filter_map(|run| run.peak_cpu_perc)
+                .fold(None, |res: Option<f64>, perc| Some(res.map_or(perc, |res| res.max(perc))));
+            let memory = replica.runs.iter().filter_map(|run| run.peak_memory_bytes).max();
+            rows.push(vec![
+                replica.name.clone(),
+                replica.state.clone(),
+                replica.restarts.to_string(),
+                replica.runs.last().map(|run| run.exit_status.clone())
+                    .unwrap_or_else(|| "-".to_string()),
+                format!("{:.1}s", uptime),
+                cpu.map(|perc| format!("{:.1}%", perc)).unwrap_or_else(|| "-".to_string()),
+                memory.map(format_bytes).unwrap_or_else(|| "-".to_string()),
+            ]);
+        }
+        let widths = (0..rows[0].len())
+            .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap())
+            .collect::<Vec<_>>();
+        for row in rows {
+            let line = row.iter().zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>().join("  ");
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        if let Some(seed) = self.chaos_seed {
+            let count = |skipped: bool, failed: bool| self.chaos_events.iter()
+                .filter(|event| event.skipped.is_some() == skipped
+                    && event.error.is_some() == failed)
+                .count();
+            writeln!(out, "Chaos events: {} injected, {} skipped, {} failed, seed {}",
+                     count(false, false), count(true, false), count(false, true), seed)?;
+        }
+        Ok(())
+    }
+}
+
+// "512B", "1.5K", "20.0M" or "1.2G"
+fn format_bytes(bytes: u64) -> String {
+    let units = [(1u64 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    match units.iter().find(|&&(size, _)| bytes >= size) {
+        Some(&(size, unit)) => format!("{:.1}{}", bytes as f64 / size as f64, unit),
+        None => format!("{}B", bytes),
+    }
+}
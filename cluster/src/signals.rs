@@ -0,0 +1,46 @@
+use ::std::{io, mem, ptr, thread};
+use ::std::os::unix::io::RawFd;
+use ::std::sync::atomic::{AtomicI32, Ordering};
+use ::core::{c_int, sys_read};
+use ::libc::{self, c_void};
+
+// Write end of the pipe through which the handler passes the signals to the waiting thread,
+// since hardly anything else may be done in a signal handler
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// errno is restored, since the handler may run between a call and the check of its errno
+extern "C" fn handler(signum: c_int) {
+    unsafe {
+        let errno = libc::__errno_location();
+        let saved_errno = *errno;
+        let byte = signum as u8;
+        libc::write(SIGNAL_PIPE.load(Ordering::SeqCst), &byte as *const u8 as *const c_void, 1);
+        *errno = saved_errno;
+    }
+}
+
+// Calls `on_signal` in a separate thread with each SIGINT or SIGTERM the process gets.
+// The handlers are reset by exec, so the containers get the default ones.
+pub fn handle_signals<F>(mut on_signal: F) -> io::Result<()>
+    where F: FnMut(c_int) + Send + 'static {
+    let mut fds: [RawFd; 2] = [0, 0];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    for &signum in &[libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handler as extern "C" fn(c_int) as usize;
+        action.sa_flags = libc::SA_RESTART;
+        if unsafe { libc::sigaction(signum, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    thread::spawn(move || {
+        let mut signum = [0u8];
+        while let Ok(1) = sys_read(fds[0], &mut signum) {
+            on_signal(c_int::from(signum[0]));
+        }
+    });
+    Ok(())
+}
@@ -1,10 +1,10 @@
-extern crate gcc;
+extern crate cc;
 
 fn main() {
-    gcc::Build::new()
+    cc::Build::new()
         .file("src/redirect_io/lib.c")
         .compile("redirect_io");
-    gcc::Build::new()
+    cc::Build::new()
         .file("src/libc_ext/lib.c")
         .compile("libc_ext");
 }
@@ -1,7 +1,16 @@
 use ::raw_process::pid_t;
 
 
-pub const CONTAINERS_DIR: &'static str = "/tmp/aucont/containers";
+pub const CONTAINERS_DIR: &str = "/tmp/aucont/containers";
+
+pub const NETWORKS_DIR: &str = "/tmp/aucont/networks";
+
+// control sockets of the cluster supervisors, see aucont_cluster
+pub const CLUSTERS_DIR: &str = "/tmp/aucont/clusters";
+
+pub fn network_file(name: &str, file: &str) -> String {
+    format!("{}/{}/{}", NETWORKS_DIR, name, file)
+}
 
 pub fn container_dir_suf(cont_pid: pid_t, suf: &str) -> String {
     format!("{}/{}{}", CONTAINERS_DIR, cont_pid, suf)
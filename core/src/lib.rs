@@ -14,6 +14,7 @@ pub mod redirect_io;
 pub mod raw_process;
 pub mod aucont_paths;
 pub mod check;
+pub mod netlink;
 
 pub use self::pipe::*;
 pub use self::raw_process::*;
@@ -7,7 +7,8 @@ use ::libc;
 
 pub use ::libc::{pid_t, c_int, c_void, ssize_t, size_t, c_ulong, uid_t, gid_t};
 pub use ::libc::{ESRCH, ECHILD, EINTR};
-pub use ::libc::{MS_BIND, MS_REC, MS_RDONLY, MS_NOSUID, MS_NODEV, MS_NOEXEC};
+pub use ::libc::{LOCK_SH, LOCK_EX, LOCK_UN};
+pub use ::libc::{MS_BIND, MS_REC, MS_REMOUNT, MS_RDONLY, MS_NOSUID, MS_NODEV, MS_NOEXEC};
 pub use ::libc_ext::*;
 pub use ::nix::unistd::{pivot_root, chroot, chdir, sethostname, getuid, getgid, setsid};
 pub use ::nix::unistd::{Uid, Gid};
@@ -62,6 +63,36 @@ pub fn sys_unshare(flags: c_int) -> io::Result<()> {
     unsafe { sys_return_unit(::libc::unshare(flags)) }
 }
 
+// The syscall is made directly: the libc wrapper applies the change to all the threads
+// of the process, and a process cloned from a multithreaded one, e.g. by aucont_cluster,
+// would wait forever for the threads which it doesn't have
 pub fn sys_setgroups() -> io::Result<()> {
-    unsafe { sys_return_unit(::libc::setgroups(0, null())) }
+    unsafe {
+        sys_return_unit(::libc::syscall(::libc::SYS_setgroups, 0, null::<::libc::gid_t>()) as c_int)
+    }
+}
+
+pub fn sys_setns(fd: RawFd, nstype: c_int) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::setns(fd, nstype)) }
+}
+
+// `operation` is one of LOCK_SH, LOCK_EX, LOCK_UN
+pub fn sys_flock(fd: RawFd, operation: c_int) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::flock(fd, operation)) }
+}
+
+pub fn sys_fchdir(fd: RawFd) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::fchdir(fd)) }
+}
+
+pub fn sys_dup2(old_fd: RawFd, new_fd: RawFd) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::dup2(old_fd, new_fd)) }
+}
+
+pub fn sys_setuid(uid: uid_t) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::setuid(uid)) }
+}
+
+pub fn sys_setgid(gid: gid_t) -> io::Result<()> {
+    unsafe { sys_return_unit(::libc::setgid(gid)) }
 }
@@ -0,0 +1,804 @@
+use ::std::{io, mem, slice, ptr};
+use ::std::time::Duration;
+use ::std::fs::File;
+use ::std::net::IpAddr;
+use ::std::os::unix::io::{RawFd, AsRawFd};
+use ::libc::{self, c_int, pid_t};
+use ::sys_return::*;
+use ::libc_wrappers::{sys_setns, sys_close};
+use ::check::Check;
+
+// Definitions from <linux/netlink.h>, <linux/rtnetlink.h>, <linux/if_link.h>
+// and <linux/pkt_sched.h>
+mod consts {
+    pub const NLMSG_ERROR: u16 = 2;
+    pub const NLM_F_REQUEST: u16 = 0x1;
+    pub const NLM_F_ACK: u16 = 0x4;
+    pub const NLM_F_REPLACE: u16 = 0x100;
+    pub const NLM_F_EXCL: u16 = 0x200;
+    pub const NLM_F_CREATE: u16 = 0x400;
+
+    pub const RTM_NEWLINK: u16 = 16;
+    pub const RTM_DELLINK: u16 = 17;
+    pub const RTM_GETLINK: u16 = 18;
+    pub const RTM_NEWADDR: u16 = 20;
+    pub const RTM_DELADDR: u16 = 21;
+    pub const RTM_NEWROUTE: u16 = 24;
+    pub const RTM_DELROUTE: u16 = 25;
+    pub const RTM_NEWQDISC: u16 = 36;
+    pub const RTM_DELQDISC: u16 = 37;
+    pub const RTM_NEWTFILTER: u16 = 44;
+
+    pub const IFLA_IFNAME: u16 = 3;
+    pub const IFLA_MASTER: u16 = 10;
+    pub const IFLA_LINKINFO: u16 = 18;
+    pub const IFLA_NET_NS_PID: u16 = 19;
+    pub const IFLA_INFO_KIND: u16 = 1;
+    pub const IFLA_INFO_DATA: u16 = 2;
+    pub const VETH_INFO_PEER: u16 = 1;
+
+    pub const IFA_ADDRESS: u16 = 1;
+    pub const IFA_LOCAL: u16 = 2;
+    pub const IFA_F_NODAD: u8 = 0x02;
+
+    pub const RTA_DST: u16 = 1;
+    pub const RTA_OIF: u16 = 4;
+    pub const RTA_GATEWAY: u16 = 5;
+
+    pub const RT_TABLE_MAIN: u8 = 254;
+    pub const RTPROT_BOOT: u8 = 3;
+    pub const RT_SCOPE_UNIVERSE: u8 = 0;
+    pub const RT_SCOPE_LINK: u8 = 253;
+    pub const RTN_UNICAST: u8 = 1;
+
+    pub const IFF_UP: u32 = 0x1;
+
+    pub const TCA_KIND: u16 = 1;
+    pub const TCA_OPTIONS: u16 = 2;
+    pub const TCA_TBF_PARMS: u16 = 1;
+    pub const TCA_TBF_RATE64: u16 = 4;
+    pub const TCA_TBF_BURST: u16 = 6;
+    pub const TC_H_ROOT: u32 = 0xffff_ffff;
+    pub const TC_LINKLAYER_ETHERNET: u8 = 1;
+    // kernel time unit of the qdisc parameters is 2^6 nanoseconds
+    pub const PSCHED_SHIFT: u32 = 6;
+
+    pub const TC_H_CLSACT: u32 = 0xffff_fff1;
+    pub const TC_H_CLSACT_INGRESS: u32 = 0xffff_fff2;
+    pub const TCA_U32_SEL: u16 = 5;
+    pub const TCA_U32_ACT: u16 = 7;
+    pub const TC_U32_TERMINAL: u8 = 1;
+    pub const TCA_ACT_KIND: u16 = 1;
+    pub const TCA_ACT_OPTIONS: u16 = 2;
+    pub const TCA_GACT_PARMS: u16 = 2;
+    pub const TC_ACT_SHOT: i32 = 2;
+    pub const ETH_P_IP: u16 = 0x0800;
+    pub const ETH_P_IPV6: u16 = 0x86dd;
+}
+
+use self::consts::*;
+
+#[repr(C)]
+struct NlMsgHdr {
+    len: u32,
+    kind: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+#[repr(C)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    kind: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+struct IfAddrMsg {
+    family: u8,
+    prefix_len: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+#[repr(C)]
+struct RtMsg {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    protocol: u8,
+    scope: u8,
+    kind: u8,
+    flags: u32,
+}
+
+#[repr(C)]
+struct TcMsg {
+    family: u8,
+    pad1: u8,
+    pad2: u16,
+    index: i32,
+    handle: u32,
+    parent: u32,
+    info: u32,
+}
+
+#[repr(C)]
+struct TcNetemQopt {
+    latency: u32,
+    limit: u32,
+    loss: u32,
+    gap: u32,
+    duplicate: u32,
+    jitter: u32,
+}
+
+#[repr(C)]
+struct TcRateSpec {
+    cell_log: u8,
+    linklayer: u8,
+    overhead: u16,
+    cell_align: i16,
+    mpu: u16,
+    rate: u32,
+}
+
+#[repr(C)]
+struct TcTbfQopt {
+    rate: TcRateSpec,
+    peakrate: TcRateSpec,
+    limit: u32,
+    buffer: u32,
+    mtu: u32,
+}
+
+#[repr(C)]
+struct TcU32Sel {
+    flags: u8,
+    offshift: u8,
+    nkeys: u8,
+    pad: u8,
+    offmask: u16,
+    off: u16,
+    offoff: i16,
+    hoff: i16,
+    hmask: u32,
+}
+
+#[repr(C)]
+struct TcU32Key {
+    mask: u32,
+    val: u32,
+    off: i32,
+    offmask: i32,
+}
+
+#[repr(C)]
+struct TcGact {
+    index: u32,
+    capab: u32,
+    action: i32,
+    refcnt: i32,
+    bindcnt: i32,
+}
+
+// The structs sent to the kernel have explicit padding fields, since implicit padding
+// would be read uninitialised
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+// The buffer must be large enough
+fn read_struct<T>(buf: &[u8]) -> T {
+    assert!(buf.len() >= mem::size_of::<T>());
+    unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn addr_bytes(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+// Builds a request: header, fixed-size message and attributes
+struct Message {
+    buf: Vec<u8>,
+    nested: Vec<usize>,
+}
+
+impl Message {
+    fn new<T>(kind: u16, flags: u16, body: &T) -> Message {
+        let header = NlMsgHdr {
+            len: 0, kind, flags: NLM_F_REQUEST | NLM_F_ACK | flags, seq: 0, pid: 0,
+        };
+        let mut msg = Message { buf: vec![], nested: vec![] };
+        msg.push(as_bytes(&header));
+        msg.push(as_bytes(body));
+        msg
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        let len = align(self.buf.len());
+        self.buf.resize(len, 0);
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) -> &mut Message {
+        let len = (4 + data.len()) as u16;
+        self.push(&[as_bytes(&len), as_bytes(&kind), data].concat());
+        self
+    }
+
+    fn attr_str(&mut self, kind: u16, value: &str) -> &mut Message {
+        self.attr(kind, &[value.as_bytes(), &[0]].concat())
+    }
+
+    fn attr_u32(&mut self, kind: u16, value: u32) -> &mut Message {
+        self.attr(kind, as_bytes(&value))
+    }
+
+    fn attr_u64(&mut self, kind: u16, value: u64) -> &mut Message {
+        self.attr(kind, as_bytes(&value))
+    }
+
+    fn begin_nested(&mut self, kind: u16) -> &mut Message {
+        self.nested.push(self.buf.len());
+        self.attr(kind, &[])
+    }
+
+    fn end_nested(&mut self) -> &mut Message {
+        let start = self.nested.pop().unwrap();
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(as_bytes(&len));
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(as_bytes(&len));
+        self.buf[8..12].copy_from_slice(as_bytes(&seq));
+        self.buf
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub dst: IpAddr,
+    pub dst_len: u8,
+    pub dev: String,
+    pub gateway: Option<IpAddr>,
+}
+
+impl Route {
+    // Route to the directly connected network or host
+    pub fn link(dst: IpAddr, dst_len: u8, dev: &str) -> Route {
+        Route { dst, dst_len, dev: dev.to_string(), gateway: None }
+    }
+
+    pub fn default_via(dev: &str, gateway: Option<IpAddr>, ipv6: bool) -> Route {
+        let dst = if ipv6 { "::".parse().unwrap() } else { "0.0.0.0".parse().unwrap() };
+        Route { dst, dst_len: 0, dev: dev.to_string(), gateway }
+    }
+}
+
+// Conditions of a link emulated by the qdiscs of `Netlink::set_shaping`.
+// They apply to the packets sent through the interface, so a link shaped at both ends
+// has them in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Shaping {
+    // bits per second
+    pub rate: Option<u64>,
+    pub delay: Option<Duration>,
+    // percent of the dropped packets
+    pub loss: Option<f64>,
+}
+
+impl Shaping {
+    pub fn is_empty(&self) -> bool {
+        self.rate.is_none() && self.delay.is_none() && self.loss.is_none()
+    }
+}
+
+// Parses "ADDR/LEN"; a plain address is a host address
+pub fn parse_cidr(s: &str) -> io::Result<(IpAddr, u8)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid address '{}'", s));
+    let mut parts = s.splitn(2, '/');
+    let addr: IpAddr = parts.next().unwrap().parse().map_err(|_| invalid())?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match parts.next() {
+        Some(len) => len.parse().map_err(|_| invalid())?,
+        None => max_len,
+    };
+    if len > max_len {
+        return Err(invalid());
+    }
+    Ok((addr, len))
+}
+
+// Errors returned when removing a resource which doesn't exist anymore
+pub fn is_not_found(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::NotFound || matches!(
+        error.raw_os_error(), Some(libc::ENODEV) | Some(libc::ESRCH) | Some(libc::EADDRNOTAVAIL))
+}
+
+// The kernel reports unknown qdisc kinds, e.g. if the module is missing, with ENOENT
+fn unsupported_qdisc(error: io::Error, kind: &str) -> io::Error {
+    if error.raw_os_error() != Some(libc::ENOENT) {
+        return error;
+    }
+    io::Error::new(io::ErrorKind::Unsupported,
+                   format!("Qdisc '{}' is not supported by the kernel (sch_{} module)", kind, kind))
+}
+
+// rtnetlink socket bound to the network namespace in which it was created
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        sys_close(self.fd).log_error("ERROR closing netlink socket");
+    }
+}
+
+impl Netlink {
+    pub fn new() -> io::Result<Netlink> {
+        let fd = sys_return_same(unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+        })?;
+        let netlink = Netlink { fd, seq: 0 };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        sys_return_unit(unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        })?;
+        Ok(netlink)
+    }
+
+    // Opens a socket in the network namespace of process `pid`.
+    // Only the calling thread enters the namespace, and only while the socket is created.
+    pub fn in_netns_of(pid: pid_t) -> io::Result<Netlink> {
+        let own_ns = File::open("/proc/thread-self/ns/net")?;
+        let target_ns = File::open(format!("/proc/{}/ns/net", pid))?;
+        sys_setns(target_ns.as_raw_fd(), libc::CLONE_NEWNET)?;
+        let res = Netlink::new();
+        sys_setns(own_ns.as_raw_fd(), libc::CLONE_NEWNET)
+            .check("Internal error (returning to own network namespace)");
+        res
+    }
+
+    // Sends the message and waits for the acknowledgement.
+    // Returns the replies received before it.
+    fn request(&mut self, msg: Message) -> io::Result<Vec<Vec<u8>>> {
+        self.seq += 1;
+        let seq = self.seq;
+        let data = msg.finish(seq);
+        sys_return_unit(unsafe {
+            libc::send(self.fd, data.as_ptr() as *const libc::c_void, data.len(), 0)
+        })?;
+
+        let mut replies = vec![];
+        let mut buf = vec![0_u8; 32768];
+        loop {
+            let len: usize = sys_return(unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            })?;
+            let mut pos = 0;
+            while pos + mem::size_of::<NlMsgHdr>() <= len {
+                let header: NlMsgHdr = read_struct(&buf[pos..]);
+                let msg_len = header.len as usize;
+                if msg_len < mem::size_of::<NlMsgHdr>() || pos + msg_len > len {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "Malformed netlink message"));
+                }
+                let payload = &buf[pos + mem::size_of::<NlMsgHdr>()..pos + msg_len];
+                if header.seq == seq {
+                    if header.kind == NLMSG_ERROR {
+                        let error: c_int = read_struct(payload);
+                        return if error == 0 {
+                            Ok(replies)
+                        } else {
+                            Err(io::Error::from_raw_os_error(-error))
+                        };
+                    }
+                    replies.push(payload.to_vec());
+                }
+                pos += align(msg_len);
+            }
+        }
+    }
+
+    fn link_msg(kind: u16, flags: u16, index: i32) -> Message {
+        Message::new(kind, flags, &IfInfoMsg {
+            family: libc::AF_UNSPEC as u8, pad: 0, kind: 0, index, flags: 0, change: 0,
+        })
+    }
+
+    pub fn link_index(&mut self, name: &str) -> io::Result<i32> {
+        let mut msg = Netlink::link_msg(RTM_GETLINK, 0, 0);
+        msg.attr_str(IFLA_IFNAME, name);
+        let replies = self.request(msg)?;
+        match replies.first() {
+            Some(reply) if reply.len() >= mem::size_of::<IfInfoMsg>() =>
+                Ok(read_struct::<IfInfoMsg>(reply).index),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound,
+                                    format!("Network interface '{}' not found", name))),
+        }
+    }
+
+    fn add_link(&mut self, name: &str, kind: &str, peer: Option<&str>) -> io::Result<()> {
+        let mut msg = Netlink::link_msg(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, 0);
+        msg.attr_str(IFLA_IFNAME, name);
+        msg.begin_nested(IFLA_LINKINFO).attr_str(IFLA_INFO_KIND, kind);
+        if let Some(peer) = peer {
+            msg.begin_nested(IFLA_INFO_DATA).begin_nested(VETH_INFO_PEER);
+            msg.push(as_bytes(&IfInfoMsg {
+                family: libc::AF_UNSPEC as u8, pad: 0, kind: 0, index: 0, flags: 0, change: 0,
+            }));
+            msg.attr_str(IFLA_IFNAME, peer);
+            msg.end_nested().end_nested();
+        }
+        msg.end_nested();
+        self.request(msg).map(|_| ())
+    }
+
+    pub fn add_veth(&mut self, name: &str, peer: &str) -> io::Result<()> {
+        self.add_link(name, "veth", Some(peer))
+    }
+
+    pub fn add_bridge(&mut self, name: &str) -> io::Result<()> {
+        self.add_link(name, "bridge", None)
+    }
+
+    pub fn del_link(&mut self, name: &str) -> io::Result<()> {
+        let index = self.link_index(name)?;
+        self.request(Netlink::link_msg(RTM_DELLINK, 0, index)).map(|_| ())
+    }
+
+    fn set_link_attr(&mut self, name: &str, kind: u16, data: &[u8]) -> io::Result<()> {
+        let index = self.link_index(name)?;
+        let mut msg = Netlink::link_msg(RTM_NEWLINK, 0, index);
+        msg.attr(kind, data);
+        self.request(msg).map(|_| ())
+    }
+
+    // Moves the link to the network namespace of process `pid`
+    pub fn set_link_netns_pid(&mut self, name: &str, pid: pid_t) -> io::Result<()> {
+        self.set_link_attr(name, IFLA_NET_NS_PID, as_bytes(&(pid as u32)))
+    }
+
+    pub fn rename_link(&mut self, name: &str, new_name: &str) -> io::Result<()> {
+        self.set_link_attr(name, IFLA_IFNAME, &[new_name.as_bytes(), &[0]].concat())
+    }
+
+    pub fn set_link_master(&mut self, name: &str, master: &str) -> io::Result<()> {
+        let master_index = self.link_index(master)?;
+        self.set_link_attr(name, IFLA_MASTER, as_bytes(&(master_index as u32)))
+    }
+
+    pub fn set_link_up(&mut self, name: &str, up: bool) -> io::Result<()> {
+        let index = self.link_index(name)?;
+        let msg = Message::new(RTM_NEWLINK, 0, &IfInfoMsg {
+            family: libc::AF_UNSPEC as u8, pad: 0, kind: 0, index,
+            flags: if up { IFF_UP } else { 0 },
+            change: IFF_UP,
+        });
+        self.request(msg).map(|_| ())
+    }
+
+    fn addr_msg(&mut self, kind: u16, flags: u16, dev: &str, addr: &IpAddr, prefix_len: u8)
+                -> io::Result<Message> {
+        let index = self.link_index(dev)?;
+        // the address is usable right away instead of after duplicate address detection
+        let addr_flags = if addr.is_ipv6() { IFA_F_NODAD } else { 0 };
+        let mut msg = Message::new(kind, flags, &IfAddrMsg {
+            family: family(addr), prefix_len, flags: addr_flags, scope: RT_SCOPE_UNIVERSE,
+            index: index as u32,
+        });
+        msg.attr(IFA_LOCAL, &addr_bytes(addr)).attr(IFA_ADDRESS, &addr_bytes(addr));
+        Ok(msg)
+    }
+
+    pub fn add_addr(&mut self, dev: &str, addr: &IpAddr, prefix_len: u8) -> io::Result<()> {
+        let msg = self.addr_msg(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, dev, addr, prefix_len)?;
+        self.request(msg).map(|_| ())
+    }
+
+    pub fn del_addr(&mut self, dev: &str, addr: &IpAddr, prefix_len: u8) -> io::Result<()> {
+        let msg = self.addr_msg(RTM_DELADDR, 0, dev, addr, prefix_len)?;
+        self.request(msg).map(|_| ())
+    }
+
+    fn route_msg(&mut self, kind: u16, flags: u16, route: &Route) -> io::Result<Message> {
+        let index = self.link_index(&route.dev)?;
+        let mut msg = Message::new(kind, flags, &RtMsg {
+            family: family(&route.dst),
+            dst_len: route.dst_len,
+            src_len: 0,
+            tos: 0,
+            table: RT_TABLE_MAIN,
+            protocol: RTPROT_BOOT,
+            scope: if route.gateway.is_some() { RT_SCOPE_UNIVERSE } else { RT_SCOPE_LINK },
+            kind: RTN_UNICAST,
+            flags: 0,
+        });
+        if route.dst_len > 0 {
+            msg.attr(RTA_DST, &addr_bytes(&route.dst));
+        }
+        if let Some(ref gateway) = route.gateway {
+            msg.attr(RTA_GATEWAY, &addr_bytes(gateway));
+        }
+        msg.attr_u32(RTA_OIF, index as u32);
+        Ok(msg)
+    }
+
+    pub fn add_route(&mut self, route: &Route) -> io::Result<()> {
+        let msg = self.route_msg(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, route)?;
+        self.request(msg).map(|_| ())
+    }
+
+    pub fn del_route(&mut self, route: &Route) -> io::Result<()> {
+        let msg = self.route_msg(RTM_DELROUTE, 0, route)?;
+        self.request(msg).map(|_| ())
+    }
+
+    fn qdisc_msg(kind: u16, flags: u16, index: i32, handle: u32, parent: u32) -> Message {
+        Message::new(kind, flags, &TcMsg {
+            family: libc::AF_UNSPEC as u8, pad1: 0, pad2: 0, index, handle, parent, info: 0,
+        })
+    }
+
+    // Replaces the filters which drop the packets received by the device, i.e. sent by the
+    // other end of a veth pair, if they are addressed to one of `dsts`
+    pub fn set_drop_filter(&mut self, dev: &str, dsts: &[IpAddr]) -> io::Result<()> {
+        let index = self.link_index(dev)?;
+        // removing the clsact qdisc removes its filters as well
+        let handle = TC_H_CLSACT & 0xffff_0000;
+        let msg = Netlink::qdisc_msg(RTM_DELQDISC, 0, index, handle, TC_H_CLSACT);
+        match self.request(msg) {
+            Err(ref e) if is_not_found(e) || e.raw_os_error() == Some(libc::EINVAL) => {},
+            res => { res?; },
+        }
+        if dsts.is_empty() {
+            return Ok(());
+        }
+
+        let mut msg = Netlink::qdisc_msg(RTM_NEWQDISC, NLM_F_CREATE | NLM_F_EXCL, index,
+                                         handle, TC_H_CLSACT);
+        msg.attr_str(TCA_KIND, "clsact");
+        self.request(msg).map_err(|e| unsupported_qdisc(e, "clsact"))?;
+        for dst in dsts {
+            // u32 keys match 32-bit words of the IP header at the given offsets
+            let (protocol, priority, offset) = match *dst {
+                IpAddr::V4(_) => (ETH_P_IP, 1, 16),
+                IpAddr::V6(_) => (ETH_P_IPV6, 2, 24),
+            };
+            let keys = addr_bytes(dst).chunks(4).enumerate().map(|(i, word)| TcU32Key {
+                mask: !0,
+                val: u32::from_ne_bytes([word[0], word[1], word[2], word[3]]),
+                off: offset + 4 * i as i32,
+                offmask: 0,
+            }).collect::<Vec<_>>();
+            let mut sel = as_bytes(&TcU32Sel {
+                flags: TC_U32_TERMINAL, offshift: 0, nkeys: keys.len() as u8, pad: 0, offmask: 0,
+                off: 0, offoff: 0, hoff: 0, hmask: 0,
+            }).to_vec();
+            for key in &keys {
+                sel.extend_from_slice(as_bytes(key));
+            }
+
+            let mut msg = Message::new(RTM_NEWTFILTER, NLM_F_CREATE | NLM_F_EXCL, &TcMsg {
+                family: libc::AF_UNSPEC as u8, pad1: 0, pad2: 0, index, handle: 0,
+                parent: TC_H_CLSACT_INGRESS,
+                info: (priority << 16) | u32::from(protocol.to_be()),
+            });
+            msg.attr_str(TCA_KIND, "u32").begin_nested(TCA_OPTIONS).attr(TCA_U32_SEL, &sel);
+            msg.begin_nested(TCA_U32_ACT).begin_nested(1).attr_str(TCA_ACT_KIND, "gact");
+            msg.begin_nested(TCA_ACT_OPTIONS).attr(TCA_GACT_PARMS, as_bytes(&TcGact {
+                index: 0, capab: 0, action: TC_ACT_SHOT, refcnt: 0, bindcnt: 0,
+            }));
+            msg.end_nested().end_nested().end_nested().end_nested();
+            self.request(msg).map_err(|e| match e.raw_os_error() {
+                Some(libc::ENOENT) => io::Error::new(io::ErrorKind::Unsupported,
+                    "Filter 'u32' or action 'gact' is not supported by the kernel"),
+                _ => e,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Replaces the root qdisc of the device: tbf for the rate, netem for the delay and the loss.
+    // With both, tbf is the child of netem. Empty shaping restores the default qdisc.
+    pub fn set_shaping(&mut self, dev: &str, shaping: &Shaping) -> io::Result<()> {
+        let index = self.link_index(dev)?;
+        // the old qdiscs are removed first, since they may be of another kind
+        match self.request(Netlink::qdisc_msg(RTM_DELQDISC, 0, index, 0, TC_H_ROOT)) {
+            Err(ref e) if is_not_found(e) || e.raw_os_error() == Some(libc::EINVAL) => {},
+            res => { res?; },
+        }
+        if shaping.is_empty() {
+            return Ok(());
+        }
+
+        let root_handle = 1 << 16;
+        let rate_parent = if shaping.delay.is_some() || shaping.loss.is_some() {
+            let mut msg = Netlink::qdisc_msg(RTM_NEWQDISC, NLM_F_CREATE | NLM_F_REPLACE, index,
+                                             root_handle, TC_H_ROOT);
+            let delay_ns = shaping.delay.map(|delay| delay.as_nanos()).unwrap_or(0);
+            let loss = shaping.loss.unwrap_or(0.0).clamp(0.0, 100.0);
+            msg.attr_str(TCA_KIND, "netem").attr(TCA_OPTIONS, as_bytes(&TcNetemQopt {
+                latency: (delay_ns >> PSCHED_SHIFT).min(u32::MAX as u128) as u32,
+                limit: 1000,
+                loss: (loss / 100.0 * u32::MAX as f64).round() as u32,
+                gap: 0,
+                duplicate: 0,
+                jitter: 0,
+            }));
+            self.request(msg).map_err(|e| unsupported_qdisc(e, "netem"))?;
+            root_handle | 1
+        } else {
+            TC_H_ROOT
+        };
+
+        if let Some(rate) = shaping.rate {
+            let bytes_per_sec = (rate / 8).max(1);
+            // enough for 10ms of traffic, but at least a couple of full-sized frames
+            let burst = (bytes_per_sec / 100).clamp(3200, u32::MAX as u64) as u32;
+            // packets wait in the queue for up to 50ms
+            let limit = (bytes_per_sec / 20).saturating_add(burst as u64).min(u32::MAX as u64);
+            let handle = if rate_parent == TC_H_ROOT { root_handle } else { 2 << 16 };
+            let rate_spec = |rate| TcRateSpec {
+                cell_log: 0, linklayer: TC_LINKLAYER_ETHERNET, overhead: 0, cell_align: 0, mpu: 0,
+                rate,
+            };
+            let mut msg = Netlink::qdisc_msg(RTM_NEWQDISC, NLM_F_CREATE | NLM_F_REPLACE, index,
+                                             handle, rate_parent);
+            msg.attr_str(TCA_KIND, "tbf").begin_nested(TCA_OPTIONS);
+            msg.attr(TCA_TBF_PARMS, as_bytes(&TcTbfQopt {
+                rate: rate_spec(bytes_per_sec.min(u32::MAX as u64) as u32),
+                peakrate: rate_spec(0),
+                limit: limit as u32,
+                buffer: 0,
+                mtu: 0,
+            }));
+            if bytes_per_sec > u32::MAX as u64 {
+                msg.attr_u64(TCA_TBF_RATE64, bytes_per_sec);
+            }
+            msg.attr_u32(TCA_TBF_BURST, burst).end_nested();
+            self.request(msg).map_err(|e| unsupported_qdisc(e, "tbf"))?;
+        }
+        Ok(())
+    }
+}
+
+// Resource in the host network namespace created by a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetResource {
+    // removing a link also removes its addresses and routes
+    Link(String),
+    Addr(String, IpAddr, u8),
+    Route(Route),
+}
+
+impl NetResource {
+    pub fn remove(&self, netlink: &mut Netlink) -> io::Result<()> {
+        match *self {
+            NetResource::Link(ref name) => netlink.del_link(name),
+            NetResource::Addr(ref dev, ref addr, prefix_len) =>
+                netlink.del_addr(dev, addr, prefix_len),
+            NetResource::Route(ref route) => netlink.del_route(route),
+        }
+    }
+}
+
+// Records the created resources and removes them in reverse order
+// if it is dropped without being committed.
+pub struct Transaction<'a> {
+    netlink: &'a mut Netlink,
+    created: Vec<NetResource>,
+    committed: bool,
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for resource in self.created.iter().rev() {
+            resource.remove(self.netlink)
+                .log_error(format!("Error rolling back {:?}", resource));
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(netlink: &'a mut Netlink) -> Transaction<'a> {
+        Transaction { netlink, created: vec![], committed: false }
+    }
+
+    // For operations which don't create resources
+    pub fn netlink(&mut self) -> &mut Netlink {
+        self.netlink
+    }
+
+    pub fn add_veth(&mut self, name: &str, peer: &str) -> io::Result<()> {
+        self.netlink.add_veth(name, peer)?;
+        self.created.push(NetResource::Link(name.to_string()));
+        Ok(())
+    }
+
+    pub fn add_bridge(&mut self, name: &str) -> io::Result<()> {
+        self.netlink.add_bridge(name)?;
+        self.created.push(NetResource::Link(name.to_string()));
+        Ok(())
+    }
+
+    pub fn add_addr(&mut self, dev: &str, addr: &IpAddr, prefix_len: u8) -> io::Result<()> {
+        self.netlink.add_addr(dev, addr, prefix_len)?;
+        self.created.push(NetResource::Addr(dev.to_string(), *addr, prefix_len));
+        Ok(())
+    }
+
+    pub fn add_route(&mut self, route: &Route) -> io::Result<()> {
+        self.netlink.add_route(route)?;
+        self.created.push(NetResource::Route(route.clone()));
+        Ok(())
+    }
+
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Option<(String, u8)> {
+        parse_cidr(s).ok().map(|(addr, len)| (addr.to_string(), len))
+    }
+
+    #[test]
+    fn addresses_without_length_are_hosts() {
+        assert_eq!(cidr("10.1.2.3"), Some(("10.1.2.3".to_string(), 32)));
+        assert_eq!(cidr("fd00::7"), Some(("fd00::7".to_string(), 128)));
+        assert_eq!(cidr("fd00::/64"), Some(("fd00::".to_string(), 64)));
+        assert_eq!(cidr("10.0.0.0/33"), None);
+        assert_eq!(cidr("10.0.0.0/"), None);
+        assert_eq!(cidr("gateway/8"), None);
+    }
+
+    #[test]
+    fn u32_selectors_have_the_kernel_layout() {
+        // struct tc_u32_sel without the keys
+        assert_eq!(mem::size_of::<TcU32Sel>(), 16);
+        let sel = TcU32Sel {
+            flags: 1, offshift: 2, nkeys: 3, pad: 0, offmask: 4, off: 5, offoff: 6, hoff: 7,
+            hmask: 8,
+        };
+        assert_eq!(&as_bytes(&sel)[..4], &[1, 2, 3, 0]);
+        assert_eq!(as_bytes(&sel)[12..], 8u32.to_ne_bytes());
+    }
+}
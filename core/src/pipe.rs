@@ -1,7 +1,7 @@
 use ::std::io::{self, Result};
 use ::std::os::unix::io::RawFd;
 use ::libc_wrappers::{sys_read, sys_write, sys_close};
-use ::libc;
+use ::libc::{self, c_int};
 use ::sys_return::*;
 use ::check::Check;
 
@@ -12,8 +12,13 @@ pub struct Pipe {
 
 impl Drop for Pipe {
     fn drop(&mut self) {
-        sys_close(self.read_fd).log_error("ERROR closing pipe");
-        sys_close(self.write_fd).log_error("ERROR closing pipe");
+        // either end may be closed already, see `close_read` and `close_write`
+        if self.read_fd >= 0 {
+            sys_close(self.read_fd).log_error("ERROR closing pipe");
+        }
+        if self.write_fd >= 0 {
+            sys_close(self.write_fd).log_error("ERROR closing pipe");
+        }
     }
 }
 
@@ -40,4 +45,56 @@ impl Pipe {
         sys_return_unit(res)?;
         Ok(Pipe { read_fd: pipe_fd[0], write_fd: pipe_fd[1] })
     }
+
+    // For the async-signal-safe code, which uses the descriptors directly
+    pub fn read_fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    pub fn write_fd(&self) -> RawFd {
+        self.write_fd
+    }
+
+    // A process closes the end it doesn't use, so that the other one gets EOF
+    // once all the processes using that end have closed it or exited
+    pub fn close_read(&mut self) -> Result<()> {
+        let fd = self.read_fd;
+        self.read_fd = -1;
+        sys_close(fd)
+    }
+
+    pub fn close_write(&mut self) -> Result<()> {
+        let fd = self.write_fd;
+        self.write_fd = -1;
+        sys_close(fd)
+    }
+
+    // Sends a message of two numbers with a single write, which is atomic for a pipe.
+    // Async-signal-safe, so it may be used by a process cloned from a multithreaded one.
+    pub fn send_pair(&self, pair: [c_int; 2]) {
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&pair[0].to_ne_bytes());
+        buf[4..].copy_from_slice(&pair[1].to_ne_bytes());
+        unsafe { libc::write(self.write_fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+    }
+
+    // None once the writing processes have closed the pipe
+    pub fn recv_pair(&mut self) -> Result<Option<[c_int; 2]>> {
+        let mut buf = [0u8; 8];
+        let mut read = 0;
+        while read < buf.len() {
+            match sys_read(self.read_fd, &mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        match read {
+            0 => Ok(None),
+            8 => Ok(Some([c_int::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]),
+                          c_int::from_ne_bytes([buf[4], buf[5], buf[6], buf[7]])])),
+            _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated message in pipe")),
+        }
+    }
 }
@@ -16,17 +16,60 @@ pub struct RawProcess {
     pid: pid_t,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(c_int),
+    // signal number and whether a core dump was produced
+    Signaled(c_int, bool),
+}
+
+impl ExitStatus {
+    pub fn from_raw(status: c_int) -> ExitStatus {
+        if libc::WIFSIGNALED(status) {
+            ExitStatus::Signaled(libc::WTERMSIG(status), libc::WCOREDUMP(status))
+        } else {
+            ExitStatus::Exited(libc::WEXITSTATUS(status))
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+
+    // Exit code as reported by shells: the code itself or 128+N for a process killed by signal N
+    pub fn code(&self) -> c_int {
+        match *self {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled(signum, _) => 128 + signum,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitStatus::Exited(code) => write!(f, "exit code {}", code),
+            ExitStatus::Signaled(signum, false) => write!(f, "killed by signal {}", signum),
+            ExitStatus::Signaled(signum, true) =>
+                write!(f, "killed by signal {} (core dumped)", signum),
+        }
+    }
+}
+
 impl RawProcess {
     pub fn from_pid(pid: pid_t) -> RawProcess {
         RawProcess { pid }
     }
 
+    /// # Safety
+    /// Like fork, the child is a copy of the calling thread only. Until it execs, it may only
+    /// make async-signal-safe calls.
     pub unsafe fn raw_clone(flags: c_int) -> io::Result<Option<RawProcess>> {
         let res = libc::syscall(libc::SYS_clone, flags,
-                                /*stack-ptr*/ 0 as *mut (),
-                                /*ptid*/ 0 as *mut (),
-                                /*ctid*/ 0 as *mut (),
-                                /*regs*/ 0 as *mut ());
+                                /*stack-ptr*/ ptr::null_mut::<()>(),
+                                /*ptid*/ ptr::null_mut::<()>(),
+                                /*ctid*/ ptr::null_mut::<()>(),
+                                /*regs*/ ptr::null_mut::<()>());
         if res == 0 {
             return Ok(None);
         }
@@ -38,12 +81,12 @@ impl RawProcess {
     }
 
     // Not currently used. Complex uid mappings should be written by a process with capabilities
-    pub fn uid_map(&mut self) -> UidMapFactory {
+    pub fn uid_map(&mut self) -> UidMapFactory<'_> {
         UidMapFactory::new(self)
     }
 
     // Not currently used. Gid mappings should be written by a process with capabilities
-    pub fn gid_map(&mut self) -> GidMapFactory {
+    pub fn gid_map(&mut self) -> GidMapFactory<'_> {
         GidMapFactory::new(self)
     }
 
@@ -55,6 +98,28 @@ impl RawProcess {
         }
     }
 
+    // Opens a namespace of the process so that it can be entered later with `sys_setns`
+    pub fn ns_open<S: AsRef<str>>(&self, ns_name: S) -> io::Result<File> {
+        File::open(format!("/proc/{}/ns/{}", self.pid, ns_name.as_ref()))
+    }
+
+    // Opens the root directory of the process
+    pub fn root_open(&self) -> io::Result<File> {
+        File::open(format!("/proc/{}/root", self.pid))
+    }
+
+    // Start time in clock ticks since boot, which tells apart processes with a reused PID
+    pub fn start_time(&self) -> io::Result<u64> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid))?;
+        // the command name may contain spaces and parentheses, so skip past the last ')'.
+        // starttime is field 22, the first field after the name is field 3.
+        let fields = stat.rfind(')').map(|pos| stat[pos + 1..].split_whitespace());
+        fields.and_then(|mut fields| fields.nth(19))
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Unexpected format of /proc/{}/stat", self.pid)))
+    }
+
     pub fn ns_enter<S: AsRef<str>>(&self, ns_name: S) -> io::Result<()> {
         let path = format!("/proc/{}/ns/{}", self.pid, ns_name.as_ref());
         let path_c = CString::new(path.as_str()).unwrap();
@@ -70,11 +135,17 @@ impl RawProcess {
         self.ns_enter("mnt")
     }
 
-    pub fn wait(self) -> io::Result<c_int> {
-        unsafe {
+    pub fn wait(self) -> io::Result<ExitStatus> {
+        loop {
             let mut status: c_int = 0;
-            sys_return_unit(libc::waitpid(self.pid, &mut status, 0))?;
-            Ok(libc::WEXITSTATUS(status))
+            sys_return_unit(unsafe { libc::waitpid(self.pid, &mut status, 0) })?;
+            if !libc::WIFSTOPPED(status) {
+                return Ok(ExitStatus::from_raw(status));
+            }
+            // A traced process (see `ptrace`) reports stops to the tracer.
+            // Resume it, delivering the signal unless this is a ptrace event stop.
+            let signum = if status >> 16 == 0 { libc::WSTOPSIG(status) } else { 0 };
+            sys_return_unit(unsafe { libc::ptrace(libc::PTRACE_CONT, self.pid, 0, signum) })?;
         }
     }
 }
@@ -117,12 +188,13 @@ impl<'a> GidMapFactory<'a> {
     }
 
     pub fn set(self) -> io::Result<()> {
-        File::create(&format!("/proc/{}/setgroups", self.process.get_pid()))?.write_all(b"deny")?;
+        File::create(format!("/proc/{}/setgroups", self.process.get_pid()))?.write_all(b"deny")?;
         self.factory.set(&format!("/proc/{}/gid_map", self.process.get_pid()))
     }
 }
 
 #[must_use]
+#[derive(Default)]
 pub struct IdMapFactory<I> {
     entries: Vec<IdMapEntry<I>>,
 }
@@ -15,7 +15,7 @@ pub fn shell_cmd<I, S1, S2>(cmd: S1, args: I) -> io::Result<()>
         let stdout_string = std::str::from_utf8(&output.stdout).unwrap();
         let error_message = format!("{}\nstderr:\n{}\nstdout:\n{}\n=======",
                                     error_string.as_str(), stderr_string, stdout_string);
-        return Err(io::Error::new(io::ErrorKind::Other, error_message));
+        return Err(io::Error::other(error_message));
     }
 
     Ok(())
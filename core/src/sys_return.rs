@@ -11,7 +11,7 @@ pub fn sys_return_same<T: Integer>(res: T) -> Result<T> {
 }
 
 pub fn sys_return<T: Integer + ToPrimitive, R: NumCast>(res: T) -> Result<R> {
-    return sys_return_same(res).map(|x| NumCast::from(x).unwrap())
+    sys_return_same(res).map(|x| NumCast::from(x).unwrap())
 }
 
 pub fn sys_return_unit<T: Integer + ToPrimitive + NumCast>(res: T) -> Result<()> {
@@ -1,22 +1,80 @@
 extern crate aucont_core as core;
-extern crate aucont_util_cgroup as cgroup;
+extern crate aucont_lib_container as container;
 #[macro_use]
 extern crate clap;
 
-use ::core::{pid_t, getpid};
-use ::core::libc_wrappers::EINTR;
+use ::core::{pid_t, uid_t, gid_t};
 use ::core::check::Check;
-use ::std::process::Command;
-use ::std::process;
+use ::container::Container;
+use ::container::exec::{ExecConfig, DEFAULT_PATH};
+use ::container::info::{read_environment, parse_env_entry};
+use ::std::{process, fs, io};
+use ::std::io::{BufRead, BufReader};
 
+fn read_env_file(path: &str) -> io::Result<Vec<(String, String)>> {
+    let file = fs::File::open(path)?;
+    let mut res = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        res.push(parse_env_entry(line)?);
+    }
+    Ok(res)
+}
+
+fn parse_user(user: &str) -> Result<(uid_t, Option<gid_t>), ::std::num::ParseIntError> {
+    let mut parts = user.splitn(2, ':');
+    let uid = parts.next().unwrap().parse()?;
+    let gid = match parts.next() {
+        Some(gid) => Some(gid.parse()?),
+        None => None,
+    };
+    Ok((uid, gid))
+}
 
 fn main() {
     let matches = clap::App::new("aucont_exec")
         .version("0.1")
         .about("Start command <CMD> with arguments <ARGS> inside running container with id <ID>. \
-        The command is started in interactive mode. \
-        Returns the exit code returned by <CMD>.")
+        The command is started in interactive mode unless -d is given. \
+        Returns the exit code returned by <CMD> (128+N if it is killed by signal N).")
         .setting(clap::AppSettings::AllowLeadingHyphen)
+        .arg(clap::Arg::with_name("env")
+            .short("e")
+            .long("env")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VAL")
+            .help("Set an environment variable. \
+            Overrides the container environment and --env-file."))
+        .arg(clap::Arg::with_name("env_file")
+            .long("env-file")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Read environment variables from a file with KEY=VAL lines."))
+        .arg(clap::Arg::with_name("workdir")
+            .short("w")
+            .long("workdir")
+            .takes_value(true)
+            .value_name("DIR")
+            .default_value("/")
+            .help("Working directory inside the container."))
+        .arg(clap::Arg::with_name("user")
+            .short("u")
+            .long("user")
+            .takes_value(true)
+            .value_name("UID[:GID]")
+            .help("User (and group) to run the command as inside the container."))
+        .arg(clap::Arg::with_name("detach")
+            .short("d")
+            .long("detach")
+            .help("Don't wait for the command to finish. Prints the PID of the started process."))
         .arg(clap::Arg::with_name("pid")
             .index(1)
             .required(true)
@@ -41,24 +99,43 @@ fn main() {
         Some(args) => args.map(|s| s.to_string()).collect(),
         None => vec![],
     };
+    let workdir = matches.value_of("workdir").unwrap().to_string();
+    let user = matches.value_of("user")
+        .map(|user| parse_user(user).check("Can't parse user, expected UID[:GID]"));
+    let detach = matches.is_present("detach");
 
-    // Note: this must be done before entering container namespaces
-    cgroup::cgroup_enter(id, getpid()).check("Error entering cgroup of the container");
+    // later entries override earlier ones
+    // containers created before the environment was recorded have none
+    let mut environment = match read_environment(id) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        res => res.check("Error reading container environment"),
+    };
+    for path in matches.values_of("env_file").into_iter().flatten() {
+        environment.extend(read_env_file(path)
+            .check(format!("Error reading environment file '{}'", path)));
+    }
+    for entry in matches.values_of("env").into_iter().flatten() {
+        environment.push(parse_env_entry(entry).check("Can't parse environment variable"));
+    }
+    if !environment.iter().any(|(key, _)| key == "PATH") {
+        environment.insert(0, ("PATH".to_string(), DEFAULT_PATH.to_string()));
+    }
 
-    let cont_init_proc = ::core::RawProcess::from_pid(id);
-    cont_init_proc.ns_enter("user").check("Error entering user namespace");
-    cont_init_proc.ns_enter("uts").check("Error entering uts namespace");
-    cont_init_proc.ns_enter("net").check("Error entering net namespace");
-    cont_init_proc.ns_enter("ipc").check("Error entering ipc namespace");
-    cont_init_proc.ns_enter("cgroup").check("Error entering cgroup namespace");
-    cont_init_proc.ns_enter("pid").check("Error entering pid namespace");
-    cont_init_proc.ns_enter_mnt().check("Error entering mount namespace");
+    let container = Container::open(id).check("Error accessing container");
+    let handle = container.exec(ExecConfig {
+        cmd,
+        cmd_args,
+        environment,
+        workdir: Some(workdir),
+        uid: user.map(|(uid, _)| uid),
+        gid: user.and_then(|(_, gid)| gid),
+        detach,
+        ..Default::default()
+    }).check("Error starting the command");
 
-    let mut child = Command::new(cmd).args(cmd_args)
-        .spawn().check("Spawn");
-    let exit_status = child.wait().check("Wait");
-    process::exit(match exit_status.code() {
-        Some(code) => code,
-        None => EINTR, // the process is killed by a signal
-    })
+    if detach {
+        println!("{}", handle.get_pid());
+    }
+    let status = handle.wait().check("Wait");
+    process::exit(status.code());
 }
@@ -0,0 +1,17 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+extern crate clap;
+
+use ::core::check::Check;
+
+fn main() {
+    clap::App::new("aucont_gc")
+        .version("0.1")
+        .about("Remove the files, cgroups and network interfaces of containers \
+        which are no longer running (e.g. after aucont_start was killed).")
+        .get_matches();
+
+    for id in container::gc().check("Error collecting containers") {
+        println!("{}", id);
+    }
+}
@@ -0,0 +1,176 @@
+use ::core::aucont_paths::*;
+use ::core::shell::shell_cmd;
+use ::core::check::Check;
+use ::core::raw_process::pid_t;
+use ::std::{fs, io, path};
+use ::std::io::Write;
+use ::cgroup::freezer_cgroup_dir;
+use super::{Result, Error, Container, CommentError, clear};
+use super::factory::{NetworkMode, NAME_FILES};
+use super::info::read_value;
+
+// Layout of a checkpoint directory:
+//   id      -- id of the container, which is preserved by restore (CRIU restores the same PIDs)
+//   info/   -- copy of the container info
+//   rootfs/ -- copy of the container root filesystem
+//   images/ -- process images made by CRIU
+
+pub fn checkpoint_id_file(dir: &str) -> String {
+    format!("{}/id", dir)
+}
+
+pub fn checkpoint_info_dir(dir: &str) -> String {
+    format!("{}/info", dir)
+}
+
+pub fn checkpoint_root_fs(dir: &str) -> String {
+    format!("{}/rootfs", dir)
+}
+
+pub fn checkpoint_images_dir(dir: &str) -> String {
+    format!("{}/images", dir)
+}
+
+pub fn read_checkpoint_id(dir: &str) -> io::Result<pid_t> {
+    let data = fs::read_to_string(checkpoint_id_file(dir))?;
+    data.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Files of the info dir mounted into the container, which CRIU only refers to by the names
+fn name_files(id: pid_t) -> Vec<&'static str> {
+    NAME_FILES.iter().cloned()
+        .filter(|name| path::Path::new(&container_info_file(id, name)).exists())
+        .collect()
+}
+
+// The network namespace is dumped empty: interfaces are configured again on restore.
+fn criu_dump(id: pid_t, dir: &str, leave_running: bool) -> io::Result<()> {
+    let images_dir = checkpoint_images_dir(dir);
+    let id_str = id.to_string();
+    let externals = name_files(id).into_iter()
+        .map(|name| format!("mnt[/etc/{}]:{}", name, name)).collect::<Vec<_>>();
+    let mut args = vec!["criu", "dump",
+                        "--tree", &id_str,
+                        "--images-dir", &images_dir,
+                        "--log-file", "dump.log",
+                        "--manage-cgroups", "full",
+                        "--empty-ns", "net"];
+    for external in &externals {
+        args.push("--external");
+        args.push(external);
+    }
+    let freezer_dir = freezer_cgroup_dir(id)?;
+    if let Some(ref freezer_dir) = freezer_dir {
+        args.push("--freeze-cgroup");
+        args.push(freezer_dir);
+    }
+    if leave_running {
+        args.push("--leave-running");
+    }
+    shell_cmd("sudo", &args)
+}
+
+// The info of the container must be restored already
+pub fn criu_restore(id: pid_t, dir: &str) -> io::Result<()> {
+    let images_dir = checkpoint_images_dir(dir);
+    let root_fs = container_root_fs(id);
+    let externals = name_files(id).into_iter()
+        .map(|name| format!("mnt[{}]:{}", name, container_info_file(id, name)))
+        .collect::<Vec<_>>();
+    let mut args = vec!["criu", "restore",
+                        "--images-dir", &images_dir,
+                        "--log-file", "restore.log",
+                        "--manage-cgroups", "full",
+                        "--root", &root_fs,
+                        "--restore-detached"];
+    for external in &externals {
+        args.push("--external");
+        args.push(external);
+    }
+    shell_cmd("sudo", &args)
+}
+
+// Undoes a failed checkpoint: removes the checkpoint directory and resumes the container
+// if it was paused by the checkpoint. Errors are only logged, since the original error
+// is reported to the caller.
+struct CheckpointGuard<'a> {
+    container: &'a mut Container,
+    dir: &'a str,
+    resume: bool,
+    committed: bool,
+}
+
+impl<'a> Drop for CheckpointGuard<'a> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // the rootfs is copied with the owners of its files
+        sudo!("rm", "--recursive", "--force", self.dir)
+            .log_error("Error removing the checkpoint directory");
+        if self.resume {
+            self.container.resume().log_error("Error resuming the container");
+        }
+    }
+}
+
+// The container must be paused
+fn save(id: pid_t, dir: &str, leave_running: bool) -> Result<()> {
+    fs::create_dir_all(checkpoint_images_dir(dir))
+        .comment_error("Error creating checkpoint directory")?;
+    fs::File::create(checkpoint_id_file(dir))
+        .and_then(|mut f| writeln!(f, "{}", id))
+        .comment_error("Error writing container id")?;
+    shell!("cp", "--recursive", &container_info_dir(id), &checkpoint_info_dir(dir))
+        .comment_error("Error copying container info")?;
+    sudo!("cp", "--recursive", "--preserve", &container_root_fs(id), &checkpoint_root_fs(dir))
+        .comment_error("Error copying rootfs")?;
+    criu_dump(id, dir, leave_running).comment_error("Error dumping container processes")
+}
+
+impl Container {
+    // Saves the container into `dir`, which must not exist.
+    // The container is paused while its state is saved.
+    // Unless `leave_running` is set, the container is stopped and cleared afterwards.
+    // If the checkpoint fails, the container is left as it was and `dir` is removed.
+    pub fn checkpoint(mut self, dir: &str, leave_running: bool) -> Result<()> {
+        let id = self.get_id();
+        if !self.is_daemon() {
+            return Err(Error::simple("Only daemon containers can be checkpointed"));
+        }
+        // the network namespace is recreated on restore, so it must belong to the container
+        let net_mode: NetworkMode = match read_value(id, "net_mode") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => NetworkMode::Private,
+            res => res.comment_error("Cannot read container info (net_mode)")?,
+        };
+        if net_mode != NetworkMode::Private {
+            return Err(Error::simple(format!(
+                "Only containers in the '{}' network mode can be checkpointed, not in '{}'",
+                NetworkMode::Private, net_mode)));
+        }
+        if path::Path::new(dir).exists() {
+            return Err(Error::simple(format!("Checkpoint directory '{}' already exists", dir)));
+        }
+
+        let was_paused = self.is_paused()?;
+        {
+            let mut guard = CheckpointGuard {
+                container: &mut self, dir, resume: false, committed: false,
+            };
+            if !was_paused {
+                guard.container.pause()?;
+                guard.resume = true;
+            }
+            save(id, dir, leave_running)?;
+            guard.committed = true;
+        }
+
+        if leave_running {
+            if !was_paused {
+                self.resume()?;
+            }
+            return Ok(());
+        }
+        clear(id)
+    }
+}
@@ -4,22 +4,72 @@ use ::core::{read_number, Pipe};
 use ::core::redirect_io::*;
 use ::core::raw_process::CLONE_NEWCGROUP;
 use ::core::check::Check;
-use ::std::{fs, process, env};
+use ::std::{fs, process, env, io, path};
 use ::std::os::unix::process::CommandExt;
+use ::std::os::unix::io::{AsRawFd, RawFd};
+use ::std::ffi::CString;
+use ::std::mem;
+use ::libc;
+use super::factory::{BindMount, NAME_FILES};
 
 
 pub struct ContainerInitConfig {
     pub daemonize: bool,
+    // sysfs can only be mounted by the owner of the network namespace
+    pub host_network: bool,
     pub cmd: String,
     pub cmd_args: Vec<String>,
 
     pub environment: Vec<(String, String)>,
+    pub mounts: Vec<BindMount>,
     pub redirect_stderr: Option<String>,
     pub redirect_stdin: Option<String>,
     pub redirect_stdout: Option<String>,
+    pub append_output: bool,
+    pub stderr_fd: Option<RawFd>,
+    pub stdout_fd: Option<RawFd>,
 }
 
 
+// Flags of the mount at `path` which a remount in a user namespace has to keep
+fn locked_mount_flags(path: &str) -> io::Result<c_ulong> {
+    let path_c = CString::new(path).unwrap();
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path_c.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = [
+        (libc::ST_NOSUID, MS_NOSUID),
+        (libc::ST_NODEV, MS_NODEV),
+        (libc::ST_NOEXEC, MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ];
+    Ok(flags.iter().filter(|&&(st, _)| stat.f_flag & st != 0).fold(0, |res, &(_, ms)| res | ms))
+}
+
+fn bind_mount(root_fs: &str, mount: &BindMount) -> io::Result<()> {
+    let target = &format!("{}{}", root_fs, mount.target);
+    sys_mount(&mount.source, target, "ignored", MS_BIND | MS_REC, None)?;
+    if mount.read_only {
+        let flags = MS_BIND | libc::MS_REMOUNT | MS_RDONLY | locked_mount_flags(target)?;
+        sys_mount(&mount.source, target, "ignored", flags, None)?;
+    }
+    Ok(())
+}
+
+// Unlike `redirect_stdout`, keeps what the file already contains
+fn append_output(path: &str, fd: RawFd) -> io::Result<()> {
+    let file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+    sys_dup2(file.as_raw_fd(), fd)
+}
+
+// Applied to every process started in the container: the init process and the exec'd ones
+pub fn apply_security_settings() -> io::Result<()> {
+    sys_setgroups()
+}
+
 pub fn container_init_main(mut pipe: Pipe, config: ContainerInitConfig) -> ! {
 
     let pid_in_host: pid_t = read_number(&mut pipe)
@@ -28,7 +78,7 @@ pub fn container_init_main(mut pipe: Pipe, config: ContainerInitConfig) -> ! {
     // cgroup namespace has to be unshared separately when new cgroup roots are established
     sys_unshare(CLONE_NEWCGROUP).check("Unshare cgroup namespace");
 
-    sys_setgroups().check("Error removing supplementary groups");
+    apply_security_settings().check("Error applying security settings");
 
     if config.daemonize {
         setsid().check("ERROR daemonizing container");
@@ -38,12 +88,26 @@ pub fn container_init_main(mut pipe: Pipe, config: ContainerInitConfig) -> ! {
         fs::File::create(stdin_file).check("ERROR creating stdin file");
         redirect_stdin(stdin_file).check("ERROR redirecting stdin");
 
-        let stdout_file = &config.redirect_stdout
-            .unwrap_or(container_info_file(pid_in_host, "stdout"));
-        redirect_stdout(stdout_file).check("ERROR redirecting stdout");
+        if let Some(fd) = config.stdout_fd {
+            sys_dup2(fd, 1).check("ERROR redirecting stdout");
+        } else {
+            let stdout_file = &config.redirect_stdout
+                .unwrap_or(container_info_file(pid_in_host, "stdout"));
+            if config.append_output {
+                append_output(stdout_file, 1)
+            } else {
+                redirect_stdout(stdout_file)
+            }.check("ERROR redirecting stdout");
+        }
 
-        if let Some(path) = config.redirect_stderr {
-            redirect_stderr(path).check("ERROR redirecting stderr to a file");
+        if let Some(fd) = config.stderr_fd {
+            sys_dup2(fd, 2).check("ERROR redirecting stderr");
+        } else if let Some(path) = config.redirect_stderr {
+            if config.append_output {
+                append_output(&path, 2)
+            } else {
+                redirect_stderr(path)
+            }.check("ERROR redirecting stderr to a file");
         } else {
             redirect_stderr_to_stdout().check("ERROR redirecting stderr to stdout");
         }
@@ -59,15 +123,39 @@ pub fn container_init_main(mut pipe: Pipe, config: ContainerInitConfig) -> ! {
 
     sys_mount(root_fs, root_fs, "ignored", MS_BIND | MS_REC, None)
         .check("Internal error (bind rootfs)");
+    for mount in &config.mounts {
+        bind_mount(root_fs, mount)
+            .check(format!("ERROR mounting '{}' at '{}'", mount.source, mount.target));
+    }
+    for name in NAME_FILES.iter() {
+        let mount = BindMount {
+            source: container_info_file(pid_in_host, name),
+            target: format!("/etc/{}", name),
+            read_only: true,
+        };
+        if path::Path::new(&mount.source).exists() {
+            bind_mount(root_fs, &mount).check(format!("ERROR mounting /etc/{}", name));
+        }
+    }
     chdir(root_fs).check("Internal error (chdir)");
     pivot_root(".", old_root).check("Internal error (pivot_root)");
     chroot(".").check("Internal error (chroot)");
 
     sys_mount("procfs", "/proc/", "proc", 0, None).check("ERROR mounting procfs");
 //    sys_chown("/proc/", 0, 0).check("ERROR setting procfs owner");
-    // options are copied from a docker container
-    sys_mount("sysfs", "/sys/", "sysfs", MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC, None)
-        .check("ERROR mounting sysfs");
+    if config.host_network {
+        sys_mount("/mnt/sys", "/sys/", "ignored", MS_BIND | MS_REC, None)
+            .check("ERROR mounting sysfs of the host");
+        // a bind mount is made read-only by remounting it. The flags locked by the host
+        // have to be kept, and only the top mount changes, not the ones under it.
+        sys_mount("none", "/sys/", "ignored",
+                  MS_BIND | MS_REMOUNT | MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC, None)
+            .check("ERROR making sysfs of the host read-only");
+    } else {
+        // options are copied from a docker container
+        sys_mount("sysfs", "/sys/", "sysfs", MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC, None)
+            .check("ERROR mounting sysfs");
+    }
     // FIXME: should be done by host root,
     // but it's fine not to have ownership over /sys/ for now
     // sys_chown("/sys/", 0, 0).check("ERROR setting siysfs owner");
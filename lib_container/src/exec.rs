@@ -0,0 +1,384 @@
+use ::core::Pipe;
+use ::core::raw_process::*;
+use ::core::libc_wrappers::*;
+use ::std::*;
+use ::std::ffi::CString;
+use ::std::io::Write;
+use ::std::os::raw::c_char;
+use ::std::os::unix::io::AsRawFd;
+use ::std::os::unix::fs::MetadataExt;
+use ::libc;
+use super::{Result, Container, CommentError};
+use super::container_init_main::apply_security_settings;
+use ::cgroup::cgroup_enter_native;
+
+// Namespaces are entered in this order. User namespace goes first to gain capabilities in the
+// other ones, mount namespace goes last because it changes the view of /proc.
+const NAMESPACES: &[(&str, c_int)] = &[
+    ("user", CLONE_NEWUSER),
+    ("uts", CLONE_NEWUTS),
+    ("net", CLONE_NEWNET),
+    ("ipc", CLONE_NEWIPC),
+    ("cgroup", CLONE_NEWCGROUP),
+    ("pid", CLONE_NEWPID),
+    ("mnt", CLONE_NEWNS),
+];
+
+// Used when the container environment doesn't define its own PATH
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// Exit codes of the intermediate process when the command couldn't be started
+const EXIT_SETUP_FAILED: c_int = 125;
+const EXIT_EXEC_FAILED: c_int = 126;
+
+#[derive(Default)]
+pub struct ExecConfig {
+    pub cmd: String,
+    pub cmd_args: Vec<String>,
+    pub environment: Vec<(String, String)>,
+    // relative to the container root, "/" by default
+    pub workdir: Option<String>,
+    pub uid: Option<uid_t>,
+    pub gid: Option<gid_t>,
+    // start the command in a new session and don't mirror its exit status
+    pub detach: bool,
+    // start the command in its own process group, whose ID is the PID of the command
+    pub process_group: bool,
+
+    // host paths; stdin/stdout are /dev/null for detached commands unless redirected
+    pub redirect_stderr: Option<String>,
+    pub redirect_stdin: Option<String>,
+    pub redirect_stdout: Option<String>,
+}
+
+pub struct ExecHandle {
+    // intermediate process, which mirrors the exit status of the command
+    process: RawProcess,
+    cmd_pid: pid_t,
+}
+
+impl ExecHandle {
+    // PID of the command in the host PID namespace
+    pub fn get_pid(&self) -> pid_t {
+        self.cmd_pid
+    }
+
+    pub fn signal(&mut self, signal: c_int) -> Result<()> {
+        RawProcess::from_pid(self.cmd_pid).signal(signal)
+            .comment_error("Error sending signal to the command")
+    }
+
+    // For detached commands returns as soon as the command is started
+    pub fn wait(self) -> Result<ExitStatus> {
+        self.process.wait().comment_error("Waiting for the command to finish")
+    }
+}
+
+struct ExecFiles {
+    namespaces: Vec<(fs::File, c_int)>,
+    root: fs::File,
+    stdin: Option<fs::File>,
+    stdout: Option<fs::File>,
+    stderr: Option<fs::File>,
+}
+
+impl ExecFiles {
+    fn open(cont_id: pid_t, config: &ExecConfig) -> io::Result<ExecFiles> {
+        let init = RawProcess::from_pid(cont_id);
+        let mut namespaces = Vec::new();
+        for &(name, nstype) in NAMESPACES {
+            // e.g. the network namespace of containers in the host network mode,
+            // which can't be entered from the user namespace of the container
+            if is_shared_with_host(&init, name)? {
+                continue;
+            }
+            namespaces.push((init.ns_open(name)?, nstype));
+        }
+
+        Ok(ExecFiles {
+            namespaces,
+            root: init.root_open()?,
+            stdin: open_redirect(&config.redirect_stdin, config.detach, false)?,
+            stdout: open_redirect(&config.redirect_stdout, config.detach, true)?,
+            stderr: open_redirect(&config.redirect_stderr, config.detach, true)?,
+        })
+    }
+}
+
+fn is_shared_with_host(init: &RawProcess, ns_name: &str) -> io::Result<bool> {
+    let own = fs::metadata(format!("/proc/self/ns/{}", ns_name))?;
+    let other = fs::metadata(format!("/proc/{}/ns/{}", init.get_pid(), ns_name))?;
+    Ok(own.dev() == other.dev() && own.ino() == other.ino())
+}
+
+fn open_redirect(path: &Option<String>, detach: bool, output: bool)
+                 -> io::Result<Option<fs::File>> {
+    let path = match *path {
+        Some(ref path) => path.as_str(),
+        None if detach => "/dev/null",
+        None => return Ok(None),
+    };
+    let file = if output {
+        fs::OpenOptions::new().create(true).append(true).open(path)?
+    } else {
+        fs::File::open(path)?
+    };
+    Ok(Some(file))
+}
+
+// Everything the processes created by `exec` need is prepared by the caller.
+// They are cloned from a process which may be multithreaded, so until the command is executed
+// they may only call async-signal-safe functions: no allocations, no locks and no printing.
+struct ExecPlan {
+    files: ExecFiles,
+    // where the command is looked for: the command itself if it contains a slash,
+    // otherwise each directory of PATH, as execvp does
+    programs: Vec<CString>,
+    // pointers into `_args` and `_env`, terminated by a null pointer
+    argv: Vec<*const c_char>,
+    envp: Vec<*const c_char>,
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    workdir: CString,
+    uid: Option<uid_t>,
+    gid: Option<gid_t>,
+    detach: bool,
+    process_group: bool,
+}
+
+// Messages from the intermediate process and the command, a pair of numbers each.
+// The first one is the PID of the command, the others are errors: the stage and errno.
+const MSG_PID: c_int = 0;
+const STAGE_ENTER: c_int = 1;
+const STAGE_SETUP: c_int = 2;
+const STAGE_EXEC: c_int = 3;
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn pointers(strings: &[CString]) -> Vec<*const c_char> {
+    strings.iter().map(|s| s.as_ptr()).chain(iter::once(ptr::null())).collect()
+}
+
+impl ExecPlan {
+    fn new(cont_id: pid_t, config: &ExecConfig) -> io::Result<ExecPlan> {
+        // later entries override earlier ones
+        let mut environment: Vec<(&str, &str)> = Vec::new();
+        for (key, value) in &config.environment {
+            match environment.iter_mut().find(|&&mut (other, _)| other == key) {
+                Some(entry) => entry.1 = value,
+                None => environment.push((key, value)),
+            }
+        }
+        let programs = if config.cmd.contains('/') {
+            vec![c_string(&config.cmd)?]
+        } else {
+            let path = environment.iter().find(|&&(key, _)| key == "PATH")
+                .map(|&(_, value)| value).unwrap_or(DEFAULT_PATH);
+            path.split(':')
+                .map(|dir| c_string(&format!("{}/{}", if dir.is_empty() { "." } else { dir },
+                                             config.cmd)))
+                .collect::<io::Result<_>>()?
+        };
+        let args = iter::once(&config.cmd).chain(&config.cmd_args)
+            .map(|arg| c_string(arg))
+            .collect::<io::Result<Vec<_>>>()?;
+        let env = environment.iter()
+            .map(|&(key, value)| c_string(&format!("{}={}", key, value)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ExecPlan {
+            files: ExecFiles::open(cont_id, config)?,
+            programs,
+            argv: pointers(&args),
+            envp: pointers(&env),
+            _args: args,
+            _env: env,
+            workdir: c_string(config.workdir.as_deref().unwrap_or("/"))?,
+            uid: config.uid,
+            gid: config.gid,
+            detach: config.detach,
+            process_group: config.process_group,
+        })
+    }
+}
+
+fn stage_error(stage: c_int, errno: c_int, cmd: &str) -> super::Error {
+    let comment = match stage {
+        STAGE_ENTER => "Error entering the container".to_string(),
+        STAGE_SETUP => format!("Error preparing the process '{}'", cmd),
+        _ => format!("Error starting the process '{}'", cmd),
+    };
+    match Err::<(), _>(io::Error::from_raw_os_error(errno)).comment_error(comment) {
+        Err(e) => e,
+        Ok(()) => unreachable!(),
+    }
+}
+
+impl Container {
+    // Runs a command inside the container.
+    // The calling process doesn't change any of its namespaces, so it may be multithreaded.
+    // Returns once the command is executed, or fails if it can't be.
+    pub fn exec(&self, config: ExecConfig) -> Result<ExecHandle> {
+        let plan = ExecPlan::new(self.get_id(), &config)
+            .comment_error("Error preparing the command")?;
+        let mut start = Pipe::new().comment_error("ERROR creating pipe")?;
+        let mut messages = Pipe::new().comment_error("ERROR creating pipe")?;
+
+        let process = unsafe { RawProcess::raw_clone(SIGCHLD) }
+            .comment_error("Error creating process")?;
+
+        if process.is_none() {
+            unsafe { exec_intermediate_main(&plan, &start, &messages) };
+        }
+        let process = process.unwrap();
+
+        // the messages end once the processes which may send them have closed the pipe
+        messages.close_write().comment_error("ERROR closing pipe")?;
+        start.close_read().comment_error("ERROR closing pipe")?;
+        // the intermediate process can't do it itself without calling the helper
+        let entered = cgroup_enter_native(self.get_id(), process.get_pid())
+            .and_then(|_| start.write_all(b"1"));
+        // the intermediate process exits unless it was told to start
+        drop(start);
+        if let Err(e) = entered {
+            let _ = process.wait();
+            return Err(e).comment_error("Error entering the container cgroup");
+        }
+
+        let cmd_pid = match messages.recv_pair().comment_error("Internal error (PID from pipe)")? {
+            Some([MSG_PID, pid]) => pid,
+            Some([stage, errno]) => {
+                let _ = process.wait();
+                return Err(stage_error(stage, errno, &config.cmd));
+            },
+            None => {
+                let status = process.wait().comment_error("Waiting for the command to finish")?;
+                return Err(super::Error::simple(
+                    format!("Error starting the command in the container ({})", status)));
+            },
+        };
+        // the pipe is closed on exec
+        if let Some([stage, errno]) = messages.recv_pair()
+            .comment_error("Internal error (reading pipe)")? {
+            let _ = process.wait();
+            return Err(stage_error(stage, errno, &config.cmd));
+        }
+        Ok(ExecHandle { process, cmd_pid })
+    }
+}
+
+fn errno() -> c_int {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn check<T: Into<i64>>(res: T) -> result::Result<(), c_int> {
+    if res.into() < 0 { Err(errno()) } else { Ok(()) }
+}
+
+// Waits until the caller has moved it into the cgroup of the container, enters the container
+// and clones the command, so that it is created in the PID namespace.
+// Sends the PID of the command or an error through `messages`. Async-signal-safe.
+unsafe fn exec_intermediate_main(plan: &ExecPlan, start: &Pipe, messages: &Pipe) -> ! {
+    let mut started = [0u8];
+    if libc::read(start.read_fd(), started.as_mut_ptr() as *mut libc::c_void, 1) != 1 {
+        exit(EXIT_SETUP_FAILED);
+    }
+    reset_signals();
+    let res = plan.files.namespaces.iter()
+        .try_for_each(|&(ref ns, nstype)| check(libc::setns(ns.as_raw_fd(), nstype)))
+        .and_then(|_| RawProcess::raw_clone(SIGCHLD).map_err(|_| errno()));
+
+    let cmd_process = match res {
+        Ok(Some(process)) => process,
+        Ok(None) => exec_cmd_main(plan, messages),
+        Err(errno) => {
+            messages.send_pair([STAGE_ENTER, errno]);
+            exit(EXIT_SETUP_FAILED);
+        }
+    };
+
+    messages.send_pair([MSG_PID, cmd_process.get_pid()]);
+    libc::close(messages.write_fd());
+    if plan.detach {
+        exit(0);
+    }
+
+    match cmd_process.wait() {
+        Ok(ExitStatus::Exited(code)) => exit(code),
+        Ok(ExitStatus::Signaled(signum, _)) => {
+            let action: libc::sigaction = mem::zeroed();
+            libc::sigaction(signum, &action, ptr::null_mut());
+            libc::raise(signum);
+            // the signal is ignored or doesn't terminate the process
+            exit(128 + signum);
+        },
+        Err(_) => exit(EXIT_SETUP_FAILED),
+    }
+}
+
+// The signal mask and the ignored signals are inherited by the command,
+// so they are reset to the defaults, as `process::Command` does
+unsafe fn reset_signals() {
+    let mut set: libc::sigset_t = mem::zeroed();
+    libc::sigemptyset(&mut set);
+    libc::sigprocmask(libc::SIG_SETMASK, &set, ptr::null_mut());
+    let action: libc::sigaction = mem::zeroed();
+    libc::sigaction(libc::SIGPIPE, &action, ptr::null_mut());
+}
+
+// Async-signal-safe until the command is executed
+unsafe fn exec_cmd_main(plan: &ExecPlan, messages: &Pipe) -> ! {
+    if let Err(errno) = setup_cmd(plan) {
+        messages.send_pair([STAGE_SETUP, errno]);
+        exit(EXIT_SETUP_FAILED);
+    }
+    let mut error = libc::ENOENT;
+    for program in &plan.programs {
+        libc::execve(program.as_ptr(), plan.argv.as_ptr(), plan.envp.as_ptr());
+        // keep looking in the other directories of PATH, as execvp does
+        match errno() {
+            libc::ENOENT | libc::ENOTDIR => {},
+            libc::EACCES => error = libc::EACCES,
+            other => {
+                error = other;
+                break;
+            },
+        }
+    }
+    messages.send_pair([STAGE_EXEC, error]);
+    exit(EXIT_EXEC_FAILED);
+}
+
+unsafe fn setup_cmd(plan: &ExecPlan) -> result::Result<(), c_int> {
+    check(libc::fchdir(plan.files.root.as_raw_fd()))?;
+    check(libc::chroot(b".\0".as_ptr() as *const c_char))?;
+    check(libc::chdir(plan.workdir.as_ptr()))?;
+
+    // a new session is a new process group too
+    if plan.detach {
+        check(libc::setsid())?;
+    } else if plan.process_group {
+        check(libc::setpgid(0, 0))?;
+    }
+    for (file, fd) in [(&plan.files.stdin, 0), (&plan.files.stdout, 1), (&plan.files.stderr, 2)] {
+        if let Some(ref file) = *file {
+            check(libc::dup2(file.as_raw_fd(), fd))?;
+        }
+    }
+
+    apply_security_settings().map_err(|e| e.raw_os_error().unwrap_or(0))?;
+    // the libc wrappers would wait for the threads of the caller, see `sys_setgroups`
+    if let Some(gid) = plan.gid {
+        check(libc::syscall(libc::SYS_setgid, gid))?;
+    }
+    if let Some(uid) = plan.uid {
+        check(libc::syscall(libc::SYS_setuid, uid))?;
+    }
+    Ok(())
+}
+
+fn exit(code: c_int) -> ! {
+    // skip destructors and atexit handlers inherited from the parent
+    unsafe { libc::_exit(code) }
+}
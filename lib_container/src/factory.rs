@@ -1,18 +1,140 @@
 use ::core::Pipe;
 use ::core::aucont_paths::*;
+use ::core::check::Check;
 use ::core::raw_process::*;
-use ::core::libc_wrappers::{getuid, getgid};
+use ::core::libc_wrappers::{getuid, getgid, sys_setns};
 use ::std::*;
 use ::std::io::Write;
-use ::std::net::Ipv4Addr;
-use super::{Error, Result, Container, CommentError};
+use ::std::os::unix::io::{AsRawFd, RawFd};
+use ::std::net::IpAddr;
+use super::{Error, Result, Container, CommentError, clear};
 use super::container_init_main::*;
+use super::info::*;
+use super::checkpoint::*;
 use ::cgroup::cgroup_create;
+use ::net::{net_configure, net_loopback_up, net_shape, PortMapping, Shaping};
+use super::ipam::{Network, check_host_name};
+use super::proxy::proxy_start;
+use super::health::{HealthCheck, health_start};
+use super::partition;
 
+// exit code of the init process which failed to set up the container, as in `exec`
+const EXIT_INIT_FAILED: c_int = 125;
+
+pub struct NetworkAddress {
+    pub cont_addr: IpAddr,
+    pub prefix_len: u8,
+    // assigned to the host end of the veth pair
+    pub host_addr: Option<IpAddr>,
+}
+
+impl NetworkAddress {
+    // Address with the full prefix length
+    pub fn host(cont_addr: IpAddr, host_addr: Option<IpAddr>) -> NetworkAddress {
+        NetworkAddress {
+            cont_addr,
+            prefix_len: if cont_addr.is_ipv6() { 128 } else { 32 },
+            host_addr,
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct NetworkConfig {
-    pub cont_addr: Ipv4Addr,
-    pub host_addr: Option<Ipv4Addr>,
+    // at most one address of each family, i.e. both for dual-stack containers
+    pub addrs: Vec<NetworkAddress>,
     pub host_bridge: Option<String>,
+    // let the container connect to other networks through the host
+    pub masquerade: bool,
+    pub publish: Vec<PortMapping>,
+    // forward the published ports with aucont_util_proxy instead of firewall rules
+    pub userland_proxy: bool,
+    // emulated conditions of the link to the host, see `Container::set_shaping`
+    pub shaping: Shaping,
+    // other bridges the container is connected to, by interfaces "eth1", "eth2", ...
+    pub attachments: Vec<Attachment>,
+}
+
+impl NetworkConfig {
+    // None stands for a network without a bridge
+    pub fn is_attached(&self, bridge: Option<&str>) -> bool {
+        self.host_bridge.as_deref() == bridge
+            || self.attachments.iter().any(|attachment| Some(&attachment.bridge[..]) == bridge)
+    }
+
+    // Addresses of the container on all its interfaces
+    pub fn all_addrs(&self) -> Vec<IpAddr> {
+        self.addrs.iter().map(|addr| addr.cont_addr)
+            .chain(self.attachments.iter()
+                .flat_map(|attachment| attachment.addrs.iter().map(|addr| addr.0)))
+            .collect()
+    }
+}
+
+// Only the subnets of the addresses are routed through the bridge,
+// the default route stays on the main interface
+#[derive(Clone)]
+pub struct Attachment {
+    pub bridge: String,
+    // with their prefix lengths
+    pub addrs: Vec<(IpAddr, u8)>,
+}
+
+// Network namespace of the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NetworkMode {
+    // own namespace with loopback, connected to the host if `ContainerConfig::net` is set
+    #[default]
+    Private,
+    // namespace of the host
+    Host,
+    // namespace of another container, e.g. for sidecars. The user namespace is shared as well,
+    // since entering the network namespace requires capabilities in its owner.
+    Container(pid_t),
+}
+
+impl fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetworkMode::Private => write!(f, "private"),
+            NetworkMode::Host => write!(f, "host"),
+            NetworkMode::Container(id) => write!(f, "container:{}", id),
+        }
+    }
+}
+
+impl str::FromStr for NetworkMode {
+    type Err = io::Error;
+
+    // "private", "host" or "container:ID".
+    // "none" is accepted as well, the private mode has only the loopback interface by default.
+    fn from_str(s: &str) -> io::Result<NetworkMode> {
+        match s {
+            "private" | "none" => Ok(NetworkMode::Private),
+            "host" => Ok(NetworkMode::Host),
+            _ => s.strip_prefix("container:")
+                .and_then(|id| id.parse().ok())
+                .map(NetworkMode::Container)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Invalid network mode '{}', expected private, none, host or container:ID", s))),
+        }
+    }
+}
+
+// Address leased from a named network, see `ipam`
+pub struct NetworkLease {
+    pub network: String,
+    // the lowest free address is leased if not specified
+    pub addr: Option<IpAddr>,
+}
+
+// Directory or file of the host mounted into the container
+#[derive(Clone)]
+pub struct BindMount {
+    pub source: String,
+    // absolute path in the container, created if it doesn't exist
+    pub target: String,
+    pub read_only: bool,
 }
 
 #[derive(Default)]
@@ -21,63 +143,170 @@ pub struct ContainerConfig {
     pub image_path: String,
     pub cmd: String,
     pub cmd_args: Vec<String>,
+    pub net_mode: NetworkMode,
+    // only for the private network mode
     pub net: Option<NetworkConfig>,
+    // the leased addresses are added to `net`, at most one network of each family
+    pub leases: Vec<NetworkLease>,
+    // the other containers on the leased networks reach it by this name, see `named_leases`
+    pub name: Option<String>,
     pub cpu_perc: Option<u32>,
+    // in bytes, unlimited if not set
+    pub memory_limit: Option<u64>,
+    // written to /etc/resolv.conf of the container, the image's one is kept if empty
+    pub dns: Vec<IpAddr>,
+    // written to /etc/hosts of the container together with the named peers on the leased networks,
+    // the image's one is kept if there are neither hosts nor leases
+    pub hosts: Vec<(String, IpAddr)>,
+    // run inside the container by aucont_util_health, see `health`
+    pub health_check: Option<HealthCheck>,
+    pub mounts: Vec<BindMount>,
 
     pub environment: Vec<(String, String)>,
     pub redirect_stderr: Option<String>,
     pub redirect_stdin: Option<String>,
     pub redirect_stdout: Option<String>,
+    // the output is appended to the files at the paths instead of replacing them
+    pub append_output: bool,
+    // take precedence over the paths, e.g. the write ends of pipes read by the caller.
+    // The init process inherits them, they aren't closed.
+    pub stderr_fd: Option<RawFd>,
+    pub stdout_fd: Option<RawFd>,
 }
 
 pub struct ContainerFactory {
     config: ContainerConfig,
     process: RawProcess,
     pipe: Pipe,
+    // what has to be undone by `abort`
+    owns_process: bool,
+    created_dir: bool,
 }
 
 
 impl ContainerFactory {
     pub fn new_container(config: ContainerConfig) -> Result<Container> {
         let mut factory = ContainerFactory::new(config)?;
-        factory.map_uid()?;
-        factory.init_dir()?;
-        factory.copy_rootfs()?;
-        factory.record_info()?;
-        factory.set_cpu_limit()?;
-        factory.configure_network()?;
-        factory.start_init()?;
-        factory.finish()
+        let res = factory.map_uid()
+            .and_then(|_| factory.init_dir())
+            .and_then(|_| factory.lease_address())
+            .and_then(|_| factory.copy_rootfs())
+            .and_then(|_| factory.record_info())
+            .and_then(|_| factory.configure_names())
+            .and_then(|_| factory.create_mount_points())
+            .and_then(|_| factory.set_cpu_limit())
+            .and_then(|_| factory.configure_network())
+            .and_then(|_| factory.start_proxy())
+            .and_then(|_| factory.start_init())
+            .and_then(|_| factory.start_health_check());
+        match res {
+            Ok(()) => factory.finish(),
+            Err(e) => {
+                factory.abort();
+                Err(e)
+            },
+        }
+    }
+
+    // Recreates a container saved by `Container::checkpoint`
+    pub fn restore_container(checkpoint_dir: &str) -> Result<Container> {
+        let mut factory = ContainerFactory::from_checkpoint(checkpoint_dir)?;
+        let res = factory.init_dir()
+            .and_then(|_| factory.copy_rootfs())
+            .and_then(|_| factory.restore_info(checkpoint_dir))
+            .and_then(|_| factory.restore_process(checkpoint_dir))
+            .and_then(|_| factory.configure_network())
+            .and_then(|_| factory.start_proxy())
+            .and_then(|_| factory.start_health_check());
+        match res {
+            Ok(()) => factory.finish(),
+            Err(e) => {
+                factory.abort();
+                Err(e)
+            },
+        }
+    }
+
+    // The rest of the config is read by `restore_info`
+    pub fn from_checkpoint(checkpoint_dir: &str) -> Result<Self> {
+        let id = read_checkpoint_id(checkpoint_dir)
+            .comment_error("Error reading checkpoint")?;
+        Ok(ContainerFactory {
+            process: RawProcess::from_pid(id),
+            pipe: Pipe::new().comment_error("ERROR creating pipe")?,
+            config: ContainerConfig {
+                daemonize: true,
+                image_path: checkpoint_root_fs(checkpoint_dir),
+                ..Default::default()
+            },
+            owns_process: false,
+            created_dir: false,
+        })
     }
 
     pub fn new(config: ContainerConfig) -> Result<Self> {
+        let has_network = config.net.is_some() || !config.leases.is_empty();
+        if config.net_mode != NetworkMode::Private && has_network {
+            return Err(Error::simple(format!(
+                "Addresses can't be assigned in the '{}' network mode", config.net_mode)));
+        }
+        if let Some(ref name) = config.name {
+            if config.leases.is_empty() {
+                return Err(Error::simple("Only containers on named networks can have a name"));
+            }
+            check_host_name(name).comment_error("Invalid container name")?;
+        }
+        if config.health_check.as_ref().map(|check| check.cmd.contains('\n')).unwrap_or(false) {
+            return Err(Error::simple("The health check command must be a single line"));
+        }
+        if let NetworkMode::Container(target) = config.net_mode {
+            if !path::Path::new(&container_dir(target)).exists() {
+                return Err(Error::simple(format!("Container {} doesn't exist", target)));
+            }
+        }
+
         let pipe = Pipe::new().comment_error("ERROR creating pipe")?;
 
+        let flags = SIGCHLD | CLONE_NEWNS | CLONE_NEWUTS | CLONE_NEWIPC | CLONE_NEWPID;
         let process = unsafe {
-            RawProcess::raw_clone(SIGCHLD | CLONE_NEWNS | CLONE_NEWUSER | CLONE_NEWUTS |
-                CLONE_NEWIPC | CLONE_NEWPID | CLONE_NEWNET)
+            match config.net_mode {
+                NetworkMode::Private => {
+                    RawProcess::raw_clone(flags | CLONE_NEWUSER | CLONE_NEWNET)
+                },
+                NetworkMode::Host => RawProcess::raw_clone(flags | CLONE_NEWUSER),
+                NetworkMode::Container(target) => clone_in_netns_of(target, flags),
+            }
         }.comment_error("Error creating init process for the container")?;
 
         if process.is_none() {
-            container_init_main(
+            // a panic must not unwind into the code of the caller, e.g. into its thread,
+            // the exit code of which is 0
+            let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| container_init_main(
                 pipe,
                 ContainerInitConfig {
                     daemonize: config.daemonize,
+                    host_network: config.net_mode == NetworkMode::Host,
                     cmd: config.cmd.clone(),
                     cmd_args: config.cmd_args.clone(),
                     environment: config.environment,
+                    mounts: config.mounts.clone(),
                     redirect_stderr: config.redirect_stderr,
                     redirect_stdin: config.redirect_stdin,
                     redirect_stdout: config.redirect_stdout,
+                    append_output: config.append_output,
+                    stderr_fd: config.stderr_fd,
+                    stdout_fd: config.stdout_fd,
                 },
-            );
-            // unreachable
+            )));
+            unsafe { ::libc::_exit(EXIT_INIT_FAILED) };
         }
 
         Ok(ContainerFactory {
             process: process.unwrap(),
             pipe,
             config,
+            owns_process: true,
+            created_dir: false,
         })
     }
 
@@ -86,6 +315,10 @@ impl ContainerFactory {
     }
 
     pub fn map_uid(&mut self) -> Result<()> {
+        // the user namespace of the other container is already mapped
+        if let NetworkMode::Container(_) = self.config.net_mode {
+            return Ok(());
+        }
         shell!("bash", "-c",
             &format!("echo 0 {} 1 | sudo tee /proc/{}/uid_map", getuid(), self.get_id()))
             .comment_error("Error mapping uid")?;
@@ -105,6 +338,50 @@ impl ContainerFactory {
         }
 
         fs::create_dir_all(dir).comment_error("Internal error (create container dir)")?;
+        self.created_dir = true;
+        Ok(())
+    }
+
+    // The leases are owned by the container and released by `clear`
+    pub fn lease_address(&mut self) -> Result<()> {
+        if self.config.leases.is_empty() {
+            return Ok(());
+        }
+        let mut net = self.config.net.take().unwrap_or_default();
+        for (i, lease) in self.config.leases.iter().enumerate() {
+            let network = Network::open(&lease.network).comment_error("Error opening network")?;
+            if net.addrs.iter().any(|addr| addr.cont_addr.is_ipv6() == network.is_ipv6()) {
+                return Err(Error::simple("At most one network of each address family is allowed"));
+            }
+            if i > 0 && net.host_bridge.as_deref() != network.bridge() {
+                return Err(Error::simple(
+                    "Networks attached to different bridges can't be combined"));
+            }
+            if let Some(ref name) = self.config.name {
+                let peers = network.named_leases().comment_error("Error reading network leases")?;
+                if peers.iter().any(|peer| peer.1 == *name) {
+                    return Err(Error::simple(format!(
+                        "Name '{}' is already used in network '{}'", name, network.name())));
+                }
+            }
+
+            let owner = self.get_id().to_string();
+            let cont_addr = match lease.addr {
+                Some(addr) => network.reserve(addr, &owner).map(|_| addr),
+                None => network.allocate(&owner),
+            }.comment_error("Error leasing network address")?;
+            // containers attached to a bridge are reached through it,
+            // otherwise the gateway address is assigned to the host end of the veth pair
+            net.addrs.push(match network.bridge() {
+                Some(_) => NetworkAddress {
+                    cont_addr, prefix_len: network.prefix_len(), host_addr: None,
+                },
+                None => NetworkAddress::host(cont_addr, Some(network.gateway())),
+            });
+            net.host_bridge = network.bridge().map(|bridge| bridge.to_string());
+            net.masquerade |= network.masquerade();
+        }
+        self.config.net = Some(net);
         Ok(())
     }
 
@@ -120,14 +397,133 @@ impl ContainerFactory {
         let info_dir = &container_info_dir(self.get_id());
         fs::create_dir_all(info_dir).comment_error("Internal error (create info dir)")?;
 
-        let mut daemon_file = fs::File::create(&format!("{}/daemon", info_dir))
-            .comment_error("Internal error (open daemon file)")?;
-        writeln!(daemon_file, "{}", if self.config.daemonize { 1 } else { 0 })
+        write_flag(self.get_id(), "daemon", self.config.daemonize)
             .comment_error("Internal error (write daemon file)")?;
+        write_flag(self.get_id(), "paused", false)
+            .comment_error("Internal error (write paused file)")?;
+
+        write_environment(self.get_id(), &self.config.environment)
+            .comment_error("Internal error (write environment file)")?;
+        write_value(self.get_id(), "cpu_perc", self.config.cpu_perc.unwrap_or(100))
+            .comment_error("Internal error (write cpu_perc file)")?;
+        if let Some(memory_limit) = self.config.memory_limit {
+            write_value(self.get_id(), "memory_limit", memory_limit)
+                .comment_error("Internal error (write memory_limit file)")?;
+        }
+        write_value(self.get_id(), "net_mode", self.config.net_mode)
+            .comment_error("Internal error (write net_mode file)")?;
+        write_network(self.get_id(), &self.config.net)
+            .comment_error("Internal error (write net file)")?;
+        write_health_check(self.get_id(), &self.config.health_check)
+            .comment_error("Internal error (write health_check file)")?;
+        if let Some(ref name) = self.config.name {
+            write_value(self.get_id(), "name", name)
+                .comment_error("Internal error (write name file)")?;
+        }
+        if !self.config.leases.is_empty() {
+            let networks = self.config.leases.iter()
+                .map(|lease| lease.network.as_str()).collect::<Vec<_>>();
+            write_value(self.get_id(), "networks", networks.join(","))
+                .comment_error("Internal error (write networks file)")?;
+        }
+
+        self.record_start_time()
+    }
+
+    // Used by `gc` to detect that the container's PID was reused
+    pub fn record_start_time(&mut self) -> Result<()> {
+        let start_time = self.process.start_time()
+            .comment_error("Error reading start time of the init process")?;
+        write_value(self.get_id(), "start_time", start_time)
+            .comment_error("Internal error (write start_time file)")
+    }
 
+    // The files are written to the info dir and mounted over the ones of the image by the init
+    // process, see `NAME_FILES`
+    pub fn configure_names(&mut self) -> Result<()> {
+        let id = self.get_id();
+        if !self.config.dns.is_empty() {
+            let data = self.config.dns.iter()
+                .map(|addr| format!("nameserver {}\n", addr)).collect::<String>();
+            fs::write(container_info_file(id, "resolv.conf"), data)
+                .comment_error("Internal error (write resolv.conf)")?;
+        }
+        if !self.config.hosts.is_empty() || !self.config.leases.is_empty() {
+            write_hosts(id, &self.config.hosts).comment_error("Error writing /etc/hosts")?;
+        }
+        Ok(())
+    }
+
+    // The mounts themselves are done by the init process in its mount namespace.
+    // Nothing has run in the container yet, so the checked paths can't change before the mounts.
+    pub fn create_mount_points(&mut self) -> Result<()> {
+        let id = self.get_id();
+        let mut targets = Vec::new();
+        for mount in &self.config.mounts {
+            let is_dir = fs::metadata(&mount.source)
+                .comment_error(format!("Error accessing mount source '{}'", mount.source))?
+                .is_dir();
+            targets.push((mount.target.clone(), is_dir));
+        }
+        for name in NAME_FILES.iter() {
+            if path::Path::new(&container_info_file(id, name)).exists() {
+                targets.push((format!("/etc/{}", name), false));
+            }
+        }
+        for (target, is_dir) in targets {
+            create_mount_point(&container_root_fs(id), &target, is_dir)
+                .comment_error(format!("Error creating mount point '{}'", target))?;
+        }
+        Ok(())
+    }
+
+    pub fn restore_info(&mut self, checkpoint_dir: &str) -> Result<()> {
+        let id = self.get_id();
+        shell!("cp", "--recursive", &checkpoint_info_dir(checkpoint_dir), &container_info_dir(id))
+            .comment_error("Error copying container info")?;
+        // the container is paused by checkpoint, but the restored processes are running
+        write_flag(id, "paused", false).comment_error("Internal error (write paused file)")?;
+        // the proxy and the health checker of the checkpointed container are already stopped
+        for file in &["proxy_pid", "proxy_stats", "health_pid"] {
+            let _ = fs::remove_file(container_info_file(id, file));
+        }
+
+        self.config.environment = read_environment(id)
+            .comment_error("Cannot read container info (environment)")?;
+        self.config.cpu_perc = Some(read_value(id, "cpu_perc")
+            .comment_error("Cannot read container info (cpu_perc)")?);
+        self.config.memory_limit = match read_value(id, "memory_limit") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            res => Some(res.comment_error("Cannot read container info (memory_limit)")?),
+        };
+        self.config.net = read_network(id).comment_error("Cannot read container info (net)")?;
+        self.config.health_check = read_health_check(id)
+            .comment_error("Cannot read container info (health_check)")?;
+
+        // the addresses were released when the container was checkpointed
+        let networks: String = match read_value(id, "networks") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            res => res.comment_error("Cannot read container info (networks)")?,
+        };
+        let addrs = self.config.net.as_ref().map(NetworkConfig::all_addrs).unwrap_or_default();
+        for name in networks.split(',').filter(|name| !name.is_empty()) {
+            let network = Network::open(name).comment_error("Error opening network")?;
+            if let Some(&addr) = addrs.iter().find(|addr| network.contains(**addr)) {
+                network.reserve(addr, &id.to_string())
+                    .comment_error("Error leasing network address")?;
+            }
+        }
         Ok(())
     }
 
+    // Cgroups with their limits are restored by CRIU
+    pub fn restore_process(&mut self, checkpoint_dir: &str) -> Result<()> {
+        criu_restore(self.get_id(), checkpoint_dir)
+            .comment_error("Error restoring container processes")?;
+        self.owns_process = true;
+        self.record_start_time()
+    }
+
     pub fn start_init(&mut self) -> Result<()> {
         // send the pid to the init process
         write!(self.pipe, "{}", self.process.get_pid())
@@ -137,32 +533,30 @@ impl ContainerFactory {
 
     pub fn set_cpu_limit(&mut self) -> Result<()> {
         let perc = self.config.cpu_perc.unwrap_or(100);
-        cgroup_create(self.get_id(), perc).comment_error("Error setting up cgroups")?;
+        cgroup_create(self.get_id(), perc, self.config.memory_limit)
+            .comment_error("Error setting up cgroups")?;
         Ok(())
     }
 
     fn configure_network_with_io_result(&mut self) -> io::Result<()> {
+        if self.config.net.is_none() && self.config.net_mode == NetworkMode::Private {
+            return net_loopback_up(self.get_id());
+        }
         if let Some(ref conf) = self.config.net {
-            let id = &self.get_id().to_string();
-            let guest_ip = &conf.cont_addr.to_string();
-            let veth_host = &format!("veth{}h", id);
-            let veth_guest = &format!("veth{}g", id);
-
-            sudo!("ip", "link", "add", veth_host, "type", "veth", "peer", "name", veth_guest)?;
-            sudo!("ip", "link", "set", veth_guest, "netns", id)?;
-            sudo!("nsenter", "--net", "-t", id, "ip", "link", "set", "lo", "up")?;
-            sudo!("nsenter", "--net", "-t", id, "ip", "link", "set", veth_guest, "name", "eth0")?;
-            sudo!("nsenter", "--net", "-t", id, "ip", "link", "set", "eth0", "up")?;
-            sudo!("nsenter", "--net", "-t", id, "ip", "addr", "add", guest_ip, "dev", "eth0")?;
-            sudo!("nsenter", "--net", "-t", id, "ip", "route", "add", "default", "dev", "eth0")?;
-            sudo!("ip", "link", "set", veth_host, "up")?;
-            if let Some(host_addr) = conf.host_addr {
-                sudo!("ip", "addr", "add", &host_addr.to_string(), "dev", veth_host)?;
-                sudo!("ip", "route", "add", guest_ip, "dev", veth_host)?;
-            }
-            if let Some(bridge) = conf.host_bridge.clone() {
-                sudo!("ip", "link", "set", veth_host, "master", &bridge)?;
+            let cont_addrs = conf.addrs.iter()
+                .map(|addr| (addr.cont_addr, addr.prefix_len)).collect::<Vec<_>>();
+            let host_addrs = conf.addrs.iter()
+                .filter_map(|addr| addr.host_addr).collect::<Vec<_>>();
+            let publish = if conf.userland_proxy { &[][..] } else { &conf.publish[..] };
+            let attachments = conf.attachments.iter()
+                .map(|attachment| (attachment.bridge.clone(), attachment.addrs.clone()))
+                .collect::<Vec<_>>();
+            net_configure(self.get_id(), &cont_addrs, &host_addrs, conf.host_bridge.as_deref(),
+                          &attachments, conf.masquerade, publish)?;
+            if !conf.shaping.is_empty() {
+                net_shape(self.get_id(), &conf.shaping)?;
             }
+            partition::apply_container(self.get_id())?;
         }
         Ok(())
     }
@@ -171,6 +565,39 @@ impl ContainerFactory {
         self.configure_network_with_io_result().comment_error("Error configuring network")
     }
 
+    // The proxy connects to the IPv4 address of dual-stack containers
+    pub fn start_proxy(&mut self) -> Result<()> {
+        let conf = match self.config.net {
+            Some(ref conf) if conf.userland_proxy && !conf.publish.is_empty() => conf,
+            _ => return Ok(()),
+        };
+        let target = conf.addrs.iter().map(|addr| addr.cont_addr)
+            .min_by_key(|addr| addr.is_ipv6())
+            .ok_or_else(|| Error::simple("Published ports require a network address"))?;
+        proxy_start(self.get_id(), target, &conf.publish).comment_error("Error forwarding ports")
+    }
+
+    // The status starts over for restored containers
+    pub fn start_health_check(&mut self) -> Result<()> {
+        if self.config.health_check.is_none() {
+            return Ok(());
+        }
+        health_start(self.get_id()).comment_error("Error starting health checks")
+    }
+
+    // Undoes the steps made so far after one of them failed.
+    // Errors are only logged, since the original error is reported to the caller.
+    pub fn abort(mut self) {
+        let id = self.get_id();
+        if self.owns_process {
+            self.process.signal(::libc::SIGKILL).log_error("Error killing the container");
+            self.process.wait().log_error("Error waiting for the container");
+        }
+        if self.created_dir {
+            clear(id).log_error("Error cleaning up the container");
+        }
+    }
+
     pub fn finish(self) -> Result<Container> {
         // TODO: wait for init process to finish initialization
         Ok(Container {
@@ -179,3 +606,160 @@ impl ContainerFactory {
         })
     }
 }
+
+// Files of the info dir mounted read-only at "/etc/NAME" in the container if they exist
+pub const NAME_FILES: [&str; 2] = ["hosts", "resolv.conf"];
+
+// Replaces the contents of /etc/hosts of the container. The file is mounted into the
+// container, so it's rewritten in place. The named peers on the networks of the container
+// are added to `hosts`.
+pub fn write_hosts(id: pid_t, hosts: &[(String, IpAddr)]) -> io::Result<()> {
+    let mut data = "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n".to_string();
+    for (name, addr) in hosts {
+        data += &format!("{}\t{}\n", addr, name);
+    }
+    let networks: String = match read_value(id, "networks") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        res => res?,
+    };
+    for name in networks.split(',').filter(|name| !name.is_empty()) {
+        for (addr, name) in Network::open(name)?.named_leases()? {
+            data += &format!("{}\t{}\n", addr, name);
+        }
+    }
+    fs::write(container_info_file(id, "hosts"), data)
+}
+
+// Mount targets are absolute paths of plain names, e.g. "/run/cluster"
+fn target_components(target: &str) -> io::Result<Vec<&str>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if !target.starts_with('/') {
+        return Err(invalid("The mount target isn't an absolute path"));
+    }
+    let components = target.split('/').filter(|name| !name.is_empty()).collect::<Vec<_>>();
+    if components.iter().any(|name| *name == "." || *name == "..") {
+        return Err(invalid("The mount target may not contain '.' or '..'"));
+    }
+    if components.is_empty() {
+        return Err(invalid("The mount target can't be the root directory"));
+    }
+    Ok(components)
+}
+
+// Opens the entry of the directory without following it if it's a symbolic link
+fn open_no_follow(dir: RawFd, name: &str) -> io::Result<fs::File> {
+    let name = ffi::CString::new(name)?;
+    let flags = ::libc::O_PATH | ::libc::O_NOFOLLOW | ::libc::O_CLOEXEC;
+    let fd = unsafe { ::libc::openat(dir, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { <fs::File as os::unix::io::FromRawFd>::from_raw_fd(fd) })
+}
+
+// The existing components of the target are opened one by one, so that a symbolic link in the
+// image can't make the mount point appear outside of the rootfs. Only the missing ones are
+// created, by root, since the rootfs belongs to root.
+fn create_mount_point(root_fs: &str, target: &str, is_dir: bool) -> io::Result<()> {
+    let components = target_components(target)?;
+    let mut dir = fs::File::open(root_fs)?;
+    let mut existing = 0;
+    for (i, name) in components.iter().enumerate() {
+        let entry = match open_no_follow(dir.as_raw_fd(), name) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+            res => res?,
+        };
+        let file_type = entry.metadata()?.file_type();
+        let last = i + 1 == components.len();
+        let valid = if last && !is_dir { file_type.is_file() } else { file_type.is_dir() };
+        if !valid {
+            let expected = if last && !is_dir { "regular file" } else { "directory" };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "'/{}' isn't a {}", components[..=i].join("/"), expected)));
+        }
+        dir = entry;
+        existing += 1;
+    }
+
+    let path_of = |count: usize| format!("{}/{}", root_fs, components[..count].join("/"));
+    let dirs = if is_dir { components.len() } else { components.len() - 1 };
+    for count in existing + 1..=dirs {
+        sudo!("mkdir", &path_of(count))?;
+    }
+    if !is_dir && existing < components.len() {
+        sudo!("touch", &path_of(components.len()))?;
+    }
+    Ok(())
+}
+
+// Clones the init process in the user and network namespaces of the target container.
+// An intermediate process enters them, since a multithreaded process can't enter
+// a user namespace. The init process becomes a child of the caller due to CLONE_PARENT.
+// The intermediate process only makes async-signal-safe calls, see `Container::exec`.
+unsafe fn clone_in_netns_of(target: pid_t, flags: c_int) -> io::Result<Option<RawProcess>> {
+    let target = RawProcess::from_pid(target);
+    let user_ns = target.ns_open("user")?;
+    let net_ns = target.ns_open("net")?;
+    // [0, PID of the init process] or [1, errno]
+    let mut messages = Pipe::new()?;
+
+    let intermediate = match RawProcess::raw_clone(SIGCHLD)? {
+        Some(process) => process,
+        None => {
+            let res = sys_setns(user_ns.as_raw_fd(), CLONE_NEWUSER)
+                .and_then(|_| sys_setns(net_ns.as_raw_fd(), CLONE_NEWNET))
+                .and_then(|_| RawProcess::raw_clone(flags | CLONE_PARENT));
+            let code = match res {
+                Ok(None) => return Ok(None),
+                Ok(Some(init)) => {
+                    messages.send_pair([0, init.get_pid()]);
+                    0
+                },
+                Err(e) => {
+                    messages.send_pair([1, e.raw_os_error().unwrap_or(0)]);
+                    1
+                },
+            };
+            // skip destructors and atexit handlers inherited from the parent
+            ::libc::_exit(code);
+        },
+    };
+
+    // the intermediate process may exit without a message
+    messages.close_write()?;
+    let message = messages.recv_pair();
+    intermediate.wait()?;
+    match message? {
+        Some([0, pid]) => Ok(Some(RawProcess::from_pid(pid))),
+        Some([_, errno]) => {
+            let e = io::Error::from_raw_os_error(errno);
+            Err(io::Error::new(e.kind(), format!(
+                "Error entering the network namespace of the container: {}", e)))
+        },
+        None => Err(io::Error::other("Error entering the network namespace of the container")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_mode_round_trips() {
+        for mode in &[NetworkMode::Private, NetworkMode::Host, NetworkMode::Container(42)] {
+            assert_eq!(mode.to_string().parse::<NetworkMode>().unwrap(), *mode);
+        }
+        assert_eq!("none".parse::<NetworkMode>().unwrap(), NetworkMode::Private);
+        assert!("container:".parse::<NetworkMode>().is_err());
+        assert!("bridge".parse::<NetworkMode>().is_err());
+    }
+
+    #[test]
+    fn mount_targets_are_plain_absolute_paths() {
+        assert_eq!(target_components("/run/cluster").unwrap(), vec!["run", "cluster"]);
+        assert_eq!(target_components("//etc//hosts/").unwrap(), vec!["etc", "hosts"]);
+        for invalid in &["run/cluster", "/", "", "/run/../etc", "/./run", "/run/.."] {
+            assert!(target_components(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use ::core::aucont_paths::{aucont_util, container_info_file};
+use ::core::raw_process::pid_t;
+use ::std::{fmt, fs, io, process, str, thread};
+use ::std::time::Duration;
+use ::libc;
+use ::core::raw_process::RawProcess;
+use super::info::{read_value, write_value};
+
+// Health checks are run inside the container by aucont_util_health, which runs until
+// the container exits or it's stopped by `health_stop`. Its PID is recorded in "health_pid",
+// the status in "health" and the output of the last check in "health_output".
+
+pub struct HealthCheck {
+    // run with "/bin/sh -c", healthy if it exits with 0
+    pub cmd: String,
+    pub interval: Duration,
+    // the check is killed and considered failed after this long
+    pub timeout: Duration,
+    // failed checks in a row before the container is unhealthy
+    pub retries: u32,
+    // failed checks don't count for this long after the start
+    pub start_period: Duration,
+}
+
+impl HealthCheck {
+    // Defaults of the other container tools
+    pub fn new(cmd: String) -> HealthCheck {
+        HealthCheck {
+            cmd,
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            start_period: Duration::from_secs(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStatus {
+    // no check has succeeded yet and the retries aren't exhausted
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HealthStatus::Starting => write!(f, "starting"),
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+impl str::FromStr for HealthStatus {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<HealthStatus> {
+        match s {
+            "starting" => Ok(HealthStatus::Starting),
+            "healthy" => Ok(HealthStatus::Healthy),
+            "unhealthy" => Ok(HealthStatus::Unhealthy),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("Invalid health status '{}'", s))),
+        }
+    }
+}
+
+// "30s", "500ms", "5m" or "1h", seconds if there is no unit
+pub fn parse_duration(s: &str) -> io::Result<Duration> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!(
+        "Invalid duration '{}', expected a number with ms, s, m or h units", s));
+    let pos = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let multiplier = match &s[pos..] {
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(invalid()),
+    };
+    let secs = s[..pos].parse::<f64>().map_err(|_| invalid())? * multiplier;
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+// None for containers without a health check
+pub fn read_health(id: pid_t) -> io::Result<Option<HealthStatus>> {
+    match read_value(id, "health") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        res => res.map(Some),
+    }
+}
+
+// The check is read from the container info, see `write_health_check`
+pub fn health_start(id: pid_t) -> io::Result<()> {
+    write_value(id, "health", HealthStatus::Starting)?;
+    let mut child = process::Command::new(aucont_util("health"))
+        .arg(id.to_string())
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    // reaps the checker when it exits, the caller may live much longer
+    let pid = child.id() as pid_t;
+    thread::spawn(move || child.wait());
+    write_value(id, "health_pid", pid)
+}
+
+// The recorded PID is only signalled if it still belongs to the checker
+pub fn health_stop(id: pid_t) -> io::Result<()> {
+    let pid: pid_t = match read_value(id, "health_pid") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        res => res?,
+    };
+    let is_checker = |pid: pid_t| fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| String::from_utf8_lossy(&cmdline).contains("aucont_util_health"))
+        .unwrap_or(false);
+    if is_checker(pid) {
+        RawProcess::from_pid(pid).signal(libc::SIGTERM)?;
+        // the checker must not write the status after the container files are removed
+        for _ in 0..100 {
+            if !is_checker(pid) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    fs::remove_file(container_info_file(id, "health_pid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_without_unit_are_seconds() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn checks_are_recorded_in_whole_units() {
+        assert_eq!(format_duration(Duration::from_secs(30)), "30s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+        assert_eq!(parse_duration("1500ms").unwrap(), Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,206 @@
+use ::core::aucont_paths::container_info_file;
+use ::core::raw_process::pid_t;
+use ::core::read_number;
+use ::std::io::{self, Read, Write};
+use ::std::fs;
+use ::std::str::FromStr;
+use ::std::fmt::Display;
+use ::core::netlink::parse_cidr;
+use super::factory::{Attachment, NetworkConfig, NetworkAddress};
+use super::health::{HealthCheck, parse_duration, format_duration};
+use ::net::{Shaping, parse_rate, parse_delay, parse_loss, format_rate, format_delay};
+
+// Boolean properties are stored as "0" or "1" in a file named after the property
+pub fn write_flag(id: pid_t, name: &str, value: bool) -> io::Result<()> {
+    let mut file = fs::File::create(container_info_file(id, name))?;
+    writeln!(file, "{}", if value { 1 } else { 0 })
+}
+
+pub fn read_flag(id: pid_t, name: &str) -> io::Result<bool> {
+    let mut file = fs::File::open(container_info_file(id, name))?;
+    Ok(read_number(&mut file)? != 0)
+}
+
+// Entries are stored in the same format as /proc/<pid>/environ: "KEY=VALUE" separated by '\0'
+pub fn write_environment(id: pid_t, environment: &[(String, String)]) -> io::Result<()> {
+    let mut file = fs::File::create(container_info_file(id, "environment"))?;
+    for (key, value) in environment {
+        write!(file, "{}={}\0", key, value)?;
+    }
+    Ok(())
+}
+
+pub fn read_environment(id: pid_t) -> io::Result<Vec<(String, String)>> {
+    let mut data = String::new();
+    fs::File::open(container_info_file(id, "environment"))?.read_to_string(&mut data)?;
+    data.split('\0')
+        .filter(|entry| !entry.is_empty())
+        .map(parse_env_entry)
+        .collect()
+}
+
+pub fn parse_env_entry(entry: &str) -> io::Result<(String, String)> {
+    match entry.find('=') {
+        Some(pos) if pos > 0 => Ok((entry[..pos].to_string(), entry[pos + 1..].to_string())),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid environment entry '{}', expected KEY=VALUE", entry))),
+    }
+}
+
+pub fn write_value<T: Display>(id: pid_t, name: &str, value: T) -> io::Result<()> {
+    let mut file = fs::File::create(container_info_file(id, name))?;
+    writeln!(file, "{}", value)
+}
+
+pub fn read_value<T>(id: pid_t, name: &str) -> io::Result<T>
+    where T: FromStr, T::Err: Display {
+    let data = fs::read_to_string(container_info_file(id, name))?;
+    parse_value(name, data.trim())
+}
+
+fn parse_value<T>(name: &str, value: &str) -> io::Result<T> where T: FromStr, T::Err: Display {
+    value.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Invalid value of '{}': {}", name, e)))
+}
+
+// The file is absent for containers without network.
+// Each address is stored as "addr=CONT_ADDR/PREFIX" or "addr=CONT_ADDR/PREFIX,HOST_ADDR",
+// each address on another bridge as "attach=BRIDGE,CONT_ADDR/PREFIX",
+// each published port as "publish=HOSTPORT:CONTPORT/PROTOCOL".
+// "userland_proxy=1" means the ports are forwarded by aucont_util_proxy.
+// Link conditions are stored as "rate=10mbit", "delay=100ms" and "loss=PERCENT".
+pub fn write_network(id: pid_t, net: &Option<NetworkConfig>) -> io::Result<()> {
+    let conf = match *net {
+        Some(ref conf) => conf,
+        None => return Ok(()),
+    };
+    let mut file = fs::File::create(container_info_file(id, "net"))?;
+    for addr in &conf.addrs {
+        match addr.host_addr {
+            Some(host_addr) => {
+                writeln!(file, "addr={}/{},{}", addr.cont_addr, addr.prefix_len, host_addr)?
+            },
+            None => writeln!(file, "addr={}/{}", addr.cont_addr, addr.prefix_len)?,
+        }
+    }
+    if let Some(ref host_bridge) = conf.host_bridge {
+        writeln!(file, "host_bridge={}", host_bridge)?;
+    }
+    for attachment in &conf.attachments {
+        for &(addr, prefix_len) in &attachment.addrs {
+            writeln!(file, "attach={},{}/{}", attachment.bridge, addr, prefix_len)?;
+        }
+    }
+    if conf.masquerade {
+        writeln!(file, "masquerade=1")?;
+    }
+    for mapping in &conf.publish {
+        writeln!(file, "publish={}", mapping)?;
+    }
+    if conf.userland_proxy {
+        writeln!(file, "userland_proxy=1")?;
+    }
+    if let Some(rate) = conf.shaping.rate {
+        writeln!(file, "rate={}", format_rate(rate))?;
+    }
+    if let Some(delay) = conf.shaping.delay {
+        writeln!(file, "delay={}", format_delay(delay))?;
+    }
+    if let Some(loss) = conf.shaping.loss {
+        writeln!(file, "loss={}", loss)?;
+    }
+    Ok(())
+}
+
+pub fn read_network(id: pid_t) -> io::Result<Option<NetworkConfig>> {
+    let data = match fs::read_to_string(container_info_file(id, "net")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        res => res?,
+    };
+    let mut addrs = Vec::new();
+    let mut host_bridge = None;
+    let mut attachments: Vec<Attachment> = Vec::new();
+    let mut masquerade = false;
+    let mut publish = Vec::new();
+    let mut userland_proxy = false;
+    let mut shaping = Shaping::default();
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        let (key, value) = parse_env_entry(line)?;
+        match key.as_str() {
+            "addr" => {
+                let mut parts = value.splitn(2, ',');
+                let (cont_addr, prefix_len) = parse_cidr(parts.next().unwrap())?;
+                let host_addr = match parts.next() {
+                    Some(host_addr) => Some(parse_value(&key, host_addr)?),
+                    None => None,
+                };
+                addrs.push(NetworkAddress { cont_addr, prefix_len, host_addr });
+            },
+            "host_bridge" => host_bridge = Some(value),
+            "attach" => {
+                let mut parts = value.splitn(2, ',');
+                let bridge = parts.next().unwrap();
+                let addr = parse_cidr(parts.next().unwrap_or(""))?;
+                match attachments.iter_mut().find(|attachment| attachment.bridge == bridge) {
+                    Some(attachment) => attachment.addrs.push(addr),
+                    None => attachments.push(Attachment {
+                        bridge: bridge.to_string(), addrs: vec![addr],
+                    }),
+                }
+            },
+            "masquerade" => masquerade = value == "1",
+            "publish" => publish.push(value.parse()?),
+            "userland_proxy" => userland_proxy = value == "1",
+            "rate" => shaping.rate = Some(parse_rate(&value)?),
+            "delay" => shaping.delay = Some(parse_delay(&value)?),
+            "loss" => shaping.loss = Some(parse_loss(&value)?),
+            _ => {},
+        }
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing 'addr' in network info"));
+    }
+    Ok(Some(NetworkConfig {
+        addrs, host_bridge, masquerade, publish, userland_proxy, shaping, attachments,
+    }))
+}
+
+// The file is absent for containers without a health check.
+// Stored as "cmd=CMD", "interval=30s", "timeout=30s", "retries=N" and "start_period=0s".
+pub fn write_health_check(id: pid_t, check: &Option<HealthCheck>) -> io::Result<()> {
+    let check = match *check {
+        Some(ref check) => check,
+        None => return Ok(()),
+    };
+    let mut file = fs::File::create(container_info_file(id, "health_check"))?;
+    writeln!(file, "cmd={}", check.cmd)?;
+    writeln!(file, "interval={}", format_duration(check.interval))?;
+    writeln!(file, "timeout={}", format_duration(check.timeout))?;
+    writeln!(file, "retries={}", check.retries)?;
+    writeln!(file, "start_period={}", format_duration(check.start_period))
+}
+
+pub fn read_health_check(id: pid_t) -> io::Result<Option<HealthCheck>> {
+    let data = match fs::read_to_string(container_info_file(id, "health_check")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        res => res?,
+    };
+    let mut check = HealthCheck::new(String::new());
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        let (key, value) = parse_env_entry(line)?;
+        match key.as_str() {
+            "cmd" => check.cmd = value,
+            "interval" => check.interval = parse_duration(&value)?,
+            "timeout" => check.timeout = parse_duration(&value)?,
+            "retries" => check.retries = parse_value(&key, &value)?,
+            "start_period" => check.start_period = parse_duration(&value)?,
+            _ => {},
+        }
+    }
+    if check.cmd.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "Missing 'cmd' in health check info"));
+    }
+    Ok(Some(check))
+}
@@ -0,0 +1,461 @@
+use ::core::aucont_paths::{NETWORKS_DIR, network_file, container_dir};
+use ::core::libc_wrappers::{sys_flock, LOCK_EX};
+use ::core::netlink::parse_cidr;
+use ::core::raw_process::pid_t;
+use ::std::{fs, io, path};
+use ::std::io::Write;
+use ::std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use ::std::os::unix::io::AsRawFd;
+use super::info::{parse_env_entry, read_value};
+
+// Named IPv4 or IPv6 networks. Each network is a directory in NETWORKS_DIR:
+//   config -- "subnet=ADDR/N", "gateway=ADDR" and optionally "bridge=NAME" and "masquerade=1"
+//   leases -- allocated addresses, one "ADDR OWNER" per line
+// Containers own their leases under their id, see `release_owner`.
+
+pub struct Network {
+    name: String,
+    subnet: IpAddr,
+    prefix_len: u8,
+    gateway: IpAddr,
+    bridge: Option<String>,
+    // containers can connect to other networks through the host
+    masquerade: bool,
+}
+
+// All networks are modified under a single lock, so that concurrent aucont processes
+// never lease the same address or create overlapping networks
+struct IpamLock {
+    _file: fs::File,
+}
+
+fn lock() -> io::Result<IpamLock> {
+    fs::create_dir_all(NETWORKS_DIR)?;
+    let file = fs::OpenOptions::new().create(true).append(true)
+        .open(format!("{}/.lock", NETWORKS_DIR))?;
+    // released when the file is closed
+    sys_flock(file.as_raw_fd(), LOCK_EX)?;
+    Ok(IpamLock { _file: file })
+}
+
+fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// Addresses of both families are handled as 128-bit numbers
+fn addr_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u128::from(u32::from(addr)),
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+fn addr_from_bits(bits: u128, ipv6: bool) -> IpAddr {
+    if ipv6 {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(bits as u32))
+    }
+}
+
+fn all_ones(ipv6: bool) -> u128 {
+    if ipv6 { !0 } else { u128::from(u32::MAX) }
+}
+
+fn mask(prefix_len: u8, ipv6: bool) -> u128 {
+    let addr_len = if ipv6 { 128 } else { 32 };
+    if prefix_len == 0 { 0 } else { (all_ones(ipv6) << (addr_len - prefix_len)) & all_ones(ipv6) }
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Invalid network name '{}', only letters, digits, '_' and '-' are allowed", name)))
+    }
+}
+
+// Names of the containers on the networks, written to /etc/hosts.
+// Numbers aren't allowed, since the leases of containers are owned by their ids.
+pub fn check_host_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty() && name.parse::<pid_t>().is_err()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Invalid name '{}', expected letters, digits, '-' and '.' but not a number", name)))
+    }
+}
+
+fn read_leases(name: &str) -> io::Result<Vec<(IpAddr, String)>> {
+    let data = match fs::read_to_string(network_file(name, "leases")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next().map(str::parse), fields.next()) {
+                (Some(Ok(addr)), Some(owner)) => Ok((addr, owner.to_string())),
+                _ => Err(invalid_data(format!("Invalid lease '{}' in network '{}'", line, name))),
+            }
+        })
+        .collect()
+}
+
+// The file is replaced atomically, so readers never see a partially written one
+fn write_leases(name: &str, leases: &[(IpAddr, String)]) -> io::Result<()> {
+    let tmp_path = network_file(name, "leases.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    for (addr, owner) in leases {
+        writeln!(file, "{} {}", addr, owner)?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp_path, network_file(name, "leases"))
+}
+
+fn read_network(name: &str) -> io::Result<Network> {
+    let data = match fs::read_to_string(network_file(name, "config")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(io::Error::new(
+            io::ErrorKind::NotFound, format!("Network '{}' doesn't exist", name))),
+        res => res?,
+    };
+    let mut subnet = None;
+    let mut gateway = None;
+    let mut bridge = None;
+    let mut masquerade = false;
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        let (key, value) = parse_env_entry(line)?;
+        match key.as_str() {
+            "subnet" => subnet = Some(parse_subnet(&value)?),
+            "gateway" => gateway = Some(value.parse().map_err(|_| invalid_data(
+                format!("Invalid gateway of network '{}'", name)))?),
+            "bridge" => bridge = Some(value),
+            "masquerade" => masquerade = value == "1",
+            _ => {},
+        }
+    }
+    match (subnet, gateway) {
+        (Some((subnet, prefix_len)), Some(gateway)) => Ok(Network {
+            name: name.to_string(), subnet, prefix_len, gateway, bridge, masquerade,
+        }),
+        _ => Err(invalid_data(format!("Incomplete config of network '{}'", name))),
+    }
+}
+
+// Host bits of the address are cleared.
+// At least two host addresses are required besides the gateway.
+fn parse_subnet(subnet: &str) -> io::Result<(IpAddr, u8)> {
+    let (addr, prefix_len) = parse_cidr(subnet)?;
+    let max_prefix_len = if addr.is_ipv6() { 126 } else { 30 };
+    if prefix_len > max_prefix_len {
+        return Err(invalid_data(format!(
+            "Invalid subnet '{}', the prefix must be at most /{}", subnet, max_prefix_len)));
+    }
+    let bits = addr_bits(addr) & mask(prefix_len, addr.is_ipv6());
+    Ok((addr_from_bits(bits, addr.is_ipv6()), prefix_len))
+}
+
+impl Network {
+    // `gateway` is the first address of the subnet by default.
+    // If `bridge` is set, containers are attached to it instead of getting the gateway address
+    // on their own host interface. The bridge itself is managed by the caller.
+    pub fn create(name: &str, subnet: &str, gateway: Option<IpAddr>, bridge: Option<&str>,
+                  masquerade: bool) -> io::Result<Network> {
+        check_name(name)?;
+        let (subnet, prefix_len) = parse_subnet(subnet)?;
+        let mut network = Network {
+            name: name.to_string(),
+            subnet,
+            prefix_len,
+            gateway: addr_from_bits(addr_bits(subnet) + 1, subnet.is_ipv6()),
+            bridge: bridge.map(|bridge| bridge.to_string()),
+            masquerade,
+        };
+        if let Some(gateway) = gateway {
+            if !network.is_host_addr(gateway) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Gateway {} is not a host address of {}", gateway, network.subnet_str())));
+            }
+            network.gateway = gateway;
+        }
+
+        let _lock = lock()?;
+        for other in Network::list_locked()? {
+            if other.name == name {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("Network '{}' already exists", name)));
+            }
+            if network.overlaps(&other) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "Subnet {} overlaps with network '{}' ({})",
+                    network.subnet_str(), other.name, other.subnet_str())));
+            }
+        }
+
+        fs::create_dir(format!("{}/{}", NETWORKS_DIR, name))?;
+        let mut file = fs::File::create(network_file(name, "config"))?;
+        writeln!(file, "subnet={}", network.subnet_str())?;
+        writeln!(file, "gateway={}", network.gateway)?;
+        if let Some(ref bridge) = network.bridge {
+            writeln!(file, "bridge={}", bridge)?;
+        }
+        if network.masquerade {
+            writeln!(file, "masquerade=1")?;
+        }
+        Ok(network)
+    }
+
+    pub fn open(name: &str) -> io::Result<Network> {
+        check_name(name)?;
+        let _lock = lock()?;
+        read_network(name)
+    }
+
+    pub fn list() -> io::Result<Vec<Network>> {
+        let _lock = lock()?;
+        Network::list_locked()
+    }
+
+    fn list_locked() -> io::Result<Vec<Network>> {
+        let mut networks = Vec::new();
+        for entry in fs::read_dir(NETWORKS_DIR)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                networks.push(read_network(name)?);
+            }
+        }
+        networks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(networks)
+    }
+
+    // Unless `force` is set, fails if some addresses are still leased
+    pub fn delete(name: &str, force: bool) -> io::Result<()> {
+        check_name(name)?;
+        let _lock = lock()?;
+        read_network(name)?;
+        if !force && !read_leases(name)?.is_empty() {
+            return Err(io::Error::other(format!("Network '{}' has leased addresses", name)));
+        }
+        fs::remove_dir_all(format!("{}/{}", NETWORKS_DIR, name))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn subnet_str(&self) -> String {
+        format!("{}/{}", self.subnet, self.prefix_len)
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn gateway(&self) -> IpAddr {
+        self.gateway
+    }
+
+    pub fn bridge(&self) -> Option<&str> {
+        self.bridge.as_deref()
+    }
+
+    pub fn masquerade(&self) -> bool {
+        self.masquerade
+    }
+
+    pub fn leases(&self) -> io::Result<Vec<(IpAddr, String)>> {
+        let _lock = lock()?;
+        read_leases(&self.name)
+    }
+
+    // Leases owned by names rather than by container ids, e.g. cluster replicas, and the leases
+    // of the containers which have a name, see `ContainerConfig::name`.
+    // Containers on the network can reach them by these names, see `ContainerConfig::hosts`.
+    pub fn named_leases(&self) -> io::Result<Vec<(IpAddr, String)>> {
+        let mut leases = Vec::new();
+        for (addr, owner) in self.leases()? {
+            let id = match owner.parse::<pid_t>() {
+                Ok(id) => id,
+                Err(_) => {
+                    leases.push((addr, owner));
+                    continue;
+                },
+            };
+            match read_value::<String>(id, "name") {
+                // containers without a name, or which are being created or cleared
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                res => leases.push((addr, res?)),
+            }
+        }
+        Ok(leases)
+    }
+
+    // Leases the lowest free address
+    pub fn allocate(&self, owner: &str) -> io::Result<IpAddr> {
+        let _lock = lock()?;
+        let mut leases = self.current_leases()?;
+        let addr = self.first_free(&leases).ok_or_else(|| io::Error::other(format!(
+            "No free addresses left in network '{}' ({})", self.name, self.subnet_str())))?;
+        leases.push((addr, owner.to_string()));
+        write_leases(&self.name, &leases)?;
+        Ok(addr)
+    }
+
+    // The lowest host address which is neither the gateway nor leased
+    fn first_free(&self, leases: &[(IpAddr, String)]) -> Option<IpAddr> {
+        let first = addr_bits(self.subnet) + 1;
+        let last = addr_bits(self.subnet) | self.host_mask();
+        (first..last)
+            .map(|bits| addr_from_bits(bits, self.is_ipv6()))
+            .find(|addr| *addr != self.gateway && leases.iter().all(|lease| lease.0 != *addr))
+    }
+
+    // Leases the given address
+    pub fn reserve(&self, addr: IpAddr, owner: &str) -> io::Result<()> {
+        if !self.is_host_addr(addr) || addr == self.gateway {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Address {} can't be leased from network '{}' ({})",
+                addr, self.name, self.subnet_str())));
+        }
+        let _lock = lock()?;
+        let mut leases = self.current_leases()?;
+        if let Some((_, other)) = leases.iter().find(|lease| lease.0 == addr) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("Address {} is already leased by {}", addr, other)));
+        }
+        leases.push((addr, owner.to_string()));
+        write_leases(&self.name, &leases)
+    }
+
+    // Leases of a network which was deleted since it was opened are lost together with it
+    fn current_leases(&self) -> io::Result<Vec<(IpAddr, String)>> {
+        read_network(&self.name)?;
+        read_leases(&self.name)
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.subnet.is_ipv6()
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv6() == self.is_ipv6()
+            && addr_bits(addr) & mask(self.prefix_len, self.is_ipv6()) == addr_bits(self.subnet)
+    }
+
+    fn host_mask(&self) -> u128 {
+        !mask(self.prefix_len, self.is_ipv6()) & all_ones(self.is_ipv6())
+    }
+
+    fn is_host_addr(&self, addr: IpAddr) -> bool {
+        let host_bits = addr_bits(addr) & self.host_mask();
+        self.contains(addr) && host_bits != 0 && host_bits != self.host_mask()
+    }
+
+    fn overlaps(&self, other: &Network) -> bool {
+        let common_mask = mask(self.prefix_len.min(other.prefix_len), self.is_ipv6());
+        self.is_ipv6() == other.is_ipv6()
+            && addr_bits(self.subnet) & common_mask == addr_bits(other.subnet) & common_mask
+    }
+}
+
+// Releases the addresses of `owner` in all networks
+pub fn release_owner(owner: &str) -> io::Result<()> {
+    release_where(|lease_owner| lease_owner == owner)
+}
+
+// Releases the addresses leased by containers which don't exist anymore
+pub fn release_orphaned() -> io::Result<()> {
+    release_where(|owner| owner.parse::<pid_t>()
+        .map(|id| !path::Path::new(&container_dir(id)).exists())
+        .unwrap_or(false))
+}
+
+fn release_where<F: Fn(&str) -> bool>(should_release: F) -> io::Result<()> {
+    if !path::Path::new(NETWORKS_DIR).exists() {
+        return Ok(());
+    }
+    let _lock = lock()?;
+    for network in Network::list_locked()? {
+        let mut leases = read_leases(&network.name)?;
+        let count = leases.len();
+        leases.retain(|lease| !should_release(&lease.1));
+        if leases.len() != count {
+            write_leases(&network.name, &leases)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(subnet: &str, gateway: Option<&str>) -> Network {
+        let (subnet, prefix_len) = parse_subnet(subnet).unwrap();
+        Network {
+            name: "test".to_string(),
+            subnet,
+            prefix_len,
+            gateway: gateway.map(|gateway| gateway.parse().unwrap())
+                .unwrap_or_else(|| addr_from_bits(addr_bits(subnet) + 1, subnet.is_ipv6())),
+            bridge: None,
+            masquerade: false,
+        }
+    }
+
+    fn leased(addrs: &[&str]) -> Vec<(IpAddr, String)> {
+        addrs.iter().map(|addr| (addr.parse().unwrap(), "42".to_string())).collect()
+    }
+
+    #[test]
+    fn subnets_drop_the_host_bits() {
+        assert_eq!(network("10.0.0.5/24", None).subnet_str(), "10.0.0.0/24");
+        assert_eq!(network("192.168.7.9/16", None).subnet_str(), "192.168.0.0/16");
+        assert_eq!(network("fd00::1:2/112", None).subnet_str(), "fd00::1:0/112");
+        assert!(parse_subnet("10.0.0.0/31").is_err());
+        assert!(parse_subnet("fd00::/127").is_err());
+    }
+
+    #[test]
+    fn allocation_skips_the_gateway_and_the_leases() {
+        let net = network("10.0.0.0/24", None);
+        assert_eq!(net.first_free(&[]), "10.0.0.2".parse().ok());
+        assert_eq!(net.first_free(&leased(&["10.0.0.2", "10.0.0.4"])), "10.0.0.3".parse().ok());
+        let net = network("10.0.0.0/24", Some("10.0.0.3"));
+        assert_eq!(net.first_free(&leased(&["10.0.0.1", "10.0.0.2"])), "10.0.0.4".parse().ok());
+
+        // the last address of the subnet is never leased, like the IPv4 broadcast address
+        let net = network("10.0.0.0/30", None);
+        assert_eq!(net.first_free(&leased(&["10.0.0.2"])), None);
+        let net = network("fd00::/126", None);
+        assert_eq!(net.first_free(&[]), "fd00::2".parse().ok());
+        assert_eq!(net.first_free(&leased(&["fd00::2"])), None);
+    }
+
+    #[test]
+    fn gateways_must_be_host_addresses() {
+        let net = network("10.0.0.0/24", None);
+        assert!(net.is_host_addr("10.0.0.254".parse().unwrap()));
+        assert!(!net.is_host_addr("10.0.0.0".parse().unwrap()));
+        assert!(!net.is_host_addr("10.0.0.255".parse().unwrap()));
+        assert!(!net.is_host_addr("10.0.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn nested_subnets_overlap() {
+        let net = network("10.0.0.0/16", None);
+        assert!(net.overlaps(&network("10.0.5.0/24", None)));
+        assert!(network("10.0.5.0/24", None).overlaps(&net));
+        assert!(!net.overlaps(&network("10.1.0.0/16", None)));
+        assert!(!net.overlaps(&network("::/64", None)));
+    }
+}
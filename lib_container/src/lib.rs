@@ -1,21 +1,50 @@
 #[macro_use]
 extern crate aucont_core as core;
 extern crate aucont_util_cgroup as cgroup;
+extern crate aucont_util_net as net;
+extern crate libc;
 
 mod container_init_main;
 pub mod factory;
+pub mod exec;
+pub mod health;
+pub mod checkpoint;
+pub mod info;
+pub mod ipam;
+pub mod partition;
+pub mod proxy;
 pub mod result;
 
-pub use ::core::raw_process::pid_t;
+pub use ::core::raw_process::{pid_t, ExitStatus};
 pub use ::result::{Result, Error, CommentError};
+pub use ::net::{PortMapping, Protocol, Shaping};
+pub use ::net::{parse_rate, parse_delay, parse_loss, format_rate, format_delay};
+pub use ::cgroup::{parse_memory, format_memory, cgroup_stats, CgroupStats};
 
-use ::std::fs;
+use ::std::{fs, io, path};
+use ::core::check::Check;
 use ::core::raw_process::*;
-use ::core::{container_dir, container_info_dir, read_number};
-use ::core::libc_wrappers::{ESRCH, ECHILD};
-use ::cgroup::cgroup_delete;
+use ::core::{container_dir, CONTAINERS_DIR};
+use ::core::aucont_paths::container_info_file;
+use ::core::libc_wrappers::{ESRCH, ECHILD, LOCK_EX, sys_flock};
+use ::std::os::unix::io::AsRawFd;
+use ::std::net::IpAddr;
+use ::factory::write_hosts;
+use ::cgroup::{cgroup_delete, cgroup_freeze, cgroup_thaw, cgroup_set_cpu};
+use ::net::{net_teardown, net_shape};
+use ::info::{read_flag, write_flag, read_value, write_value, read_network, write_network};
 
 
+// How a container ended, see `Container::wait_and_clear_stats`
+#[derive(Debug, Clone, Copy)]
+pub struct Finished {
+    pub status: ExitStatus,
+    // cancelled, e.g. by aucont_stop, or cleared by someone else
+    pub stopped: bool,
+    // the final resource usage recorded in the cgroups of the container
+    pub stats: CgroupStats,
+}
+
 pub struct Container {
     process: RawProcess,
     is_daemon: bool,
@@ -33,39 +62,190 @@ fn suppress_esrch(res: ::std::io::Result<()>) -> ::std::io::Result<()> {
     }
 }
 
+// Removes everything left by a finished container.
+// It may be cleared concurrently, e.g. by aucont_stop and by the process waiting for it,
+// so its directory is locked and the second one finds it removed.
+fn clear(id: pid_t) -> Result<()> {
+    let dir = match fs::File::open(container_dir(id)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        res => res.comment_error("Error opening container directory")?,
+    };
+    sys_flock(dir.as_raw_fd(), LOCK_EX).comment_error("Error locking container directory")?;
+    if !path::Path::new(&container_dir(id)).exists() {
+        return Ok(());
+    }
+    proxy::proxy_stop(id).comment_error("Error stopping port forwarder")?;
+    health::health_stop(id).comment_error("Error stopping health checks")?;
+    if path::Path::new(&container_info_file(id, "net")).exists() {
+        net_teardown(id).comment_error("Error removing network resources")?;
+    }
+    cgroup_delete(id).comment_error("Error removing cgroup")?;
+    ipam::release_owner(&id.to_string()).comment_error("Error releasing network addresses")?;
+    fs::remove_dir_all(container_dir(id)).comment_error("Removing container files")?;
+    Ok(())
+}
+
+// A container is alive while its init process exists and has the recorded start time.
+// Containers without a recorded start time are considered alive while the PID exists.
+fn is_alive(id: pid_t) -> io::Result<bool> {
+    let start_time = match RawProcess::from_pid(id).start_time() {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        res => res?,
+    };
+    match read_value::<u64>(id, "start_time") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        res => Ok(res? == start_time),
+    }
+}
+
+// Clears the containers left by crashed or killed aucont processes.
+// Returns ids of the removed containers.
+pub fn gc() -> Result<Vec<pid_t>> {
+    let entries = match fs::read_dir(CONTAINERS_DIR) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res.comment_error("Error listing containers")?,
+    };
+    let mut removed = Vec::new();
+    for entry in entries {
+        let entry = entry.comment_error("Error listing containers")?;
+        let id = match entry.file_name().to_str().and_then(|name| name.parse::<pid_t>().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        if !is_alive(id).comment_error(format!("Error checking container {}", id))? {
+            clear(id)?;
+            removed.push(id);
+        }
+    }
+    ipam::release_orphaned().comment_error("Error releasing network addresses")?;
+    Ok(removed)
+}
+
 impl Container {
+    // Attaches to the container in order to wait for it. Use `open` for other operations.
     pub fn connect(id: pid_t) -> Result<Container> {
-        let mut daemon_file = fs::File::open(container_info_dir(id) + "/daemon")
+        let res = Container::open(id)?;
+        suppress_esrch(res.process.ptrace()).comment_error("Ptrace")?;
+        Ok(res)
+    }
+
+    pub fn open(id: pid_t) -> Result<Container> {
+        let is_daemon = read_flag(id, "daemon")
             .comment_error("Cannot read container info (is_daemon)")?;
-        let daemon_number = read_number(&mut daemon_file)
-            .comment_error("Parsing daemon file")?;
 
-        let res = Container {
+        Ok(Container {
             process: RawProcess::from_pid(id),
-            is_daemon: daemon_number != 0,
-        };
-        suppress_esrch(res.process.ptrace()).comment_error("Ptrace")?;
-
-        Ok(res)
+            is_daemon,
+        })
     }
 
+    // Resumes the container if it's paused, so that it can handle the signal.
+    // The container is marked as cancelled, see `wait_and_clear_cancelled`.
     pub fn cancel(&mut self, signal: c_int) -> Result<()> {
+        match write_flag(self.get_id(), "cancelled", true) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            res => res.comment_error("Error recording cancelled state")?,
+        }
+        self.kill(signal)
+    }
+
+    // Like `cancel`, but the container isn't marked as cancelled, as if it crashed
+    pub fn kill(&mut self, signal: c_int) -> Result<()> {
         suppress_esrch(self.process.signal(signal)).comment_error("Error killing process")?;
-        Ok(())
+        // the info is gone once the container has exited and been cleared
+        match read_flag(self.get_id(), "paused") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Ok(true) => self.resume(),
+            res => res.map(|_| ()).comment_error("Cannot read container info (is_paused)"),
+        }
     }
 
-    pub fn wait_and_clear(self) -> Result<c_int> {
+    pub fn pause(&mut self) -> Result<()> {
+        cgroup_freeze(self.get_id()).comment_error("Error freezing cgroup")?;
+        write_flag(self.get_id(), "paused", true).comment_error("Error recording paused state")
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        cgroup_thaw(self.get_id()).comment_error("Error thawing cgroup")?;
+        write_flag(self.get_id(), "paused", false).comment_error("Error recording paused state")
+    }
+
+    pub fn is_paused(&self) -> Result<bool> {
+        read_flag(self.get_id(), "paused").comment_error("Cannot read container info (is_paused)")
+    }
+
+    pub fn set_cpu_perc(&mut self, perc: u32) -> Result<()> {
+        cgroup_set_cpu(self.get_id(), perc).comment_error("Error changing cpu limit")?;
+        write_value(self.get_id(), "cpu_perc", perc).comment_error("Error recording cpu limit")
+    }
+
+    // None for containers without a virtual network
+    pub fn shaping(&self) -> Result<Option<Shaping>> {
+        let net = read_network(self.get_id()).comment_error("Cannot read container info (net)")?;
+        Ok(net.map(|net| net.shaping))
+    }
+
+    // Replaces the emulated conditions of the link between the host and the container
+    pub fn set_shaping(&mut self, shaping: Shaping) -> Result<()> {
+        let id = self.get_id();
+        let mut net = match read_network(id).comment_error("Cannot read container info (net)")? {
+            Some(net) => net,
+            None => return Err(Error::simple("The container has no virtual network")),
+        };
+        if let Err(e) = net_shape(id, &shaping) {
+            // the old qdiscs are already removed
+            net_shape(id, &net.shaping).log_error("Error restoring network shaping");
+            return Err(e).comment_error("Error shaping network traffic");
+        }
+        net.shaping = shaping;
+        write_network(id, &Some(net)).comment_error("Error recording network shaping")
+    }
+
+    // Replaces the hosts given by `ContainerConfig::hosts`, e.g. when the peers of the container
+    // change. Only for containers with a generated /etc/hosts.
+    pub fn set_hosts(&mut self, hosts: &[(String, IpAddr)]) -> Result<()> {
+        let id = self.get_id();
+        if !path::Path::new(&container_info_file(id, "hosts")).exists() {
+            return Err(Error::simple("The container has no generated /etc/hosts"));
+        }
+        write_hosts(id, hosts).comment_error("Error writing /etc/hosts")
+    }
+
+    pub fn wait_and_clear(self) -> Result<ExitStatus> {
+        self.wait_and_clear_cancelled().map(|(status, _)| status)
+    }
+
+    // Also tells whether the container was cancelled, e.g. by aucont_stop.
+    // A container cleared by someone else, e.g. by aucont_stop, is considered cancelled too.
+    pub fn wait_and_clear_cancelled(self) -> Result<(ExitStatus, bool)> {
+        self.wait_and_clear_stats().map(|finished| (finished.status, finished.stopped))
+    }
+
+    // Also returns the final resource usage of the container
+    pub fn wait_and_clear_stats(self) -> Result<Finished> {
         let id = self.get_id();
         let ret = match self.process.wait() {
             Err(e) => match e.raw_os_error() {
-                Some(ECHILD) => { 0 },
+                Some(ECHILD) => { ExitStatus::Exited(0) },
                 _ => return Err(e).comment_error("Waiting for process to finish"),
             },
-            Ok(code) => code,
+            Ok(status) => status,
+        };
+        let cancelled = match read_flag(id, "cancelled") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if !path::Path::new(&container_dir(id)).exists() {
+                    return Ok(Finished {
+                        status: ret, stopped: true, stats: CgroupStats::default(),
+                    });
+                }
+                false
+            },
+            res => res.comment_error("Cannot read container info (cancelled)")?,
         };
-        cgroup_delete(id).comment_error("Error removing cgroup")?;
-        fs::remove_dir_all(&container_dir(id)).comment_error("Removing container files")?;
-        Ok(ret)
+        let stats = cgroup_stats(id).log_error("Error reading container resource usage")
+            .unwrap_or_default();
+        clear(id)?;
+        Ok(Finished { status: ret, stopped: cancelled, stats })
     }
 
     pub fn get_id(&self) -> pid_t {
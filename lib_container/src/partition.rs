@@ -0,0 +1,237 @@
+use ::core::aucont_paths::{NETWORKS_DIR, network_file};
+use ::core::libc_wrappers::{sys_flock, LOCK_EX};
+use ::core::raw_process::pid_t;
+use ::core::CONTAINERS_DIR;
+use ::std::{fs, io, path};
+use ::std::io::Write;
+use ::std::net::IpAddr;
+use ::std::os::unix::io::AsRawFd;
+use ::net::net_isolate;
+use super::info::read_network;
+use super::factory::NetworkConfig;
+use super::ipam::Network;
+use super::is_alive;
+
+// Network partitions between the containers of a named network, e.g. cluster replicas.
+// Blocked links are stored in NETWORKS_DIR/NAME/partitions, one "SRC DST" per line.
+// The packets from SRC to DST are dropped by a filter on the host end of the veth of SRC,
+// so that a link may be blocked in one direction only.
+// Containers started later, e.g. restarted replicas, get their filters from `apply_container`.
+
+pub struct Partitions {
+    network: Network,
+}
+
+struct PartitionLock {
+    _file: fs::File,
+}
+
+fn lock(name: &str) -> io::Result<PartitionLock> {
+    let file = fs::OpenOptions::new().create(true).append(true)
+        .open(network_file(name, "partitions.lock"))?;
+    // released when the file is closed
+    sys_flock(file.as_raw_fd(), LOCK_EX)?;
+    Ok(PartitionLock { _file: file })
+}
+
+fn read_links(name: &str) -> io::Result<Vec<(IpAddr, IpAddr)>> {
+    let data = match fs::read_to_string(network_file(name, "partitions")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace().map(str::parse);
+            match (fields.next(), fields.next()) {
+                (Some(Ok(src)), Some(Ok(dst))) => Ok((src, dst)),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "Invalid partition '{}' in network '{}'", line, name))),
+            }
+        })
+        .collect()
+}
+
+// The file is replaced atomically, so readers never see a partially written one
+fn write_links(name: &str, links: &[(IpAddr, IpAddr)]) -> io::Result<()> {
+    let tmp_path = network_file(name, "partitions.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    for (src, dst) in links {
+        writeln!(file, "{} {}", src, dst)?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp_path, network_file(name, "partitions"))
+}
+
+// Running containers which have an address in the network, with that address
+fn network_containers(network: &Network) -> io::Result<Vec<(pid_t, NetworkConfig, IpAddr)>> {
+    let entries = match fs::read_dir(CONTAINERS_DIR) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+    let mut containers = Vec::new();
+    for entry in entries {
+        let id = match entry?.file_name().to_str().and_then(|name| name.parse::<pid_t>().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        // the container may exit at any moment, its files are removed then
+        let net = match read_network(id) {
+            Ok(Some(net)) => net,
+            _ => continue,
+        };
+        if !net.is_attached(network.bridge()) || !is_alive(id).unwrap_or(false) {
+            continue;
+        }
+        let addr = net.all_addrs().into_iter().find(|addr| network.contains(*addr));
+        if let Some(addr) = addr {
+            containers.push((id, net, addr));
+        }
+    }
+    Ok(containers)
+}
+
+// Destinations blocked for any of the addresses of the container, in all its networks
+fn blocked_dsts(net: &NetworkConfig) -> io::Result<Vec<IpAddr>> {
+    let mut dsts = Vec::new();
+    if !path::Path::new(NETWORKS_DIR).exists() {
+        return Ok(dsts);
+    }
+    let addrs = net.all_addrs();
+    for network in Network::list()? {
+        if !net.is_attached(network.bridge()) {
+            continue;
+        }
+        let _lock = lock(network.name())?;
+        for (src, dst) in read_links(network.name())? {
+            if addrs.contains(&src) && !dsts.contains(&dst) {
+                dsts.push(dst);
+            }
+        }
+    }
+    Ok(dsts)
+}
+
+// Sets up the filters of a new container, e.g. of a restarted replica
+pub fn apply_container(id: pid_t) -> io::Result<()> {
+    let dsts = match read_network(id)? {
+        Some(net) => blocked_dsts(&net)?,
+        None => return Ok(()),
+    };
+    // a new container has no filters yet
+    if dsts.is_empty() {
+        return Ok(());
+    }
+    net_isolate(id, &dsts)
+}
+
+fn block(links: &mut Vec<(IpAddr, IpAddr)>, from: &[IpAddr], to: &[IpAddr]) {
+    for &src in from {
+        for &dst in to.iter().filter(|dst| **dst != src) {
+            if !links.contains(&(src, dst)) {
+                links.push((src, dst));
+            }
+        }
+    }
+}
+
+impl Partitions {
+    pub fn open(network: &str) -> io::Result<Partitions> {
+        Ok(Partitions { network: Network::open(network)? })
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    // Members are given by addresses or by the owners of their leases, e.g. "replica-0"
+    pub fn resolve(&self, member: &str) -> io::Result<IpAddr> {
+        if let Ok(addr) = member.parse::<IpAddr>() {
+            if !self.network.contains(addr) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Address {} doesn't belong to network '{}'", addr, self.network.name())));
+            }
+            return Ok(addr);
+        }
+        self.network.leases()?.into_iter()
+            .find(|lease| lease.1 == member)
+            .map(|lease| lease.0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!(
+                "Network '{}' has no member '{}'", self.network.name(), member)))
+    }
+
+    // Blocked links as (SRC, DST) pairs
+    pub fn links(&self) -> io::Result<Vec<(IpAddr, IpAddr)>> {
+        let _lock = lock(self.network.name())?;
+        read_links(self.network.name())
+    }
+
+    // Blocks the links between the sets in both directions
+    pub fn cut(&self, a: &[IpAddr], b: &[IpAddr]) -> io::Result<()> {
+        self.update(|links| {
+            block(links, a, b);
+            block(links, b, a);
+        })
+    }
+
+    // Drops the packets sent from `from` to `to`, the packets in the other direction still pass
+    pub fn cut_one_way(&self, from: &[IpAddr], to: &[IpAddr]) -> io::Result<()> {
+        self.update(|links| block(links, from, to))
+    }
+
+    // Unblocks the links between the sets in both directions
+    pub fn heal(&self, a: &[IpAddr], b: &[IpAddr]) -> io::Result<()> {
+        self.update(|links| links.retain(|&(src, dst)| {
+            !(a.contains(&src) && b.contains(&dst) || b.contains(&src) && a.contains(&dst))
+        }))
+    }
+
+    pub fn heal_all(&self) -> io::Result<()> {
+        self.update(|links| links.clear())
+    }
+
+    // The filters of the running containers whose links were changed are replaced
+    fn update<F: FnOnce(&mut Vec<(IpAddr, IpAddr)>)>(&self, change: F) -> io::Result<()> {
+        let name = self.network.name();
+        if !path::Path::new(&network_file(name, "config")).exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("Network '{}' doesn't exist", name)));
+        }
+        let (old_links, links) = {
+            let _lock = lock(name)?;
+            let old_links = read_links(name)?;
+            let mut links = old_links.clone();
+            change(&mut links);
+            write_links(name, &links)?;
+            (old_links, links)
+        };
+
+        // the filters are built from all the networks of the container, which are locked then
+        let mut updated = Vec::new();
+        for (id, net, addr) in network_containers(&self.network)? {
+            let blocked = |links: &[(IpAddr, IpAddr)]| links.iter()
+                .filter(|link| link.0 == addr).map(|link| link.1).collect::<Vec<_>>();
+            if blocked(&links) == blocked(&old_links) {
+                continue;
+            }
+            if let Err(e) = blocked_dsts(&net).and_then(|dsts| net_isolate(id, &dsts)) {
+                // the links are restored, so that the file matches the filters
+                let restored = {
+                    let _lock = lock(name)?;
+                    write_links(name, &old_links)
+                };
+                let restored = restored.and_then(|_| {
+                    updated.iter().chain(Some(&(id, net))).try_for_each(|(id, net)| {
+                        blocked_dsts(net).and_then(|dsts| net_isolate(*id, &dsts))
+                    })
+                });
+                if let Err(e) = restored {
+                    eprintln!("Error restoring network partitions: {}", e);
+                }
+                return Err(e);
+            }
+            updated.push((id, net));
+        }
+        Ok(())
+    }
+}
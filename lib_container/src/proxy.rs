@@ -0,0 +1,89 @@
+use ::core::aucont_paths::{aucont_util, container_info_file};
+use ::core::raw_process::pid_t;
+use ::std::{fs, io, process, thread};
+use ::std::io::BufRead;
+use ::std::net::IpAddr;
+use ::std::time::Duration;
+use ::net::PortMapping;
+use super::info::{read_value, write_value};
+
+// Ports published without firewall rules are forwarded by aucont_util_proxy.
+// The proxy runs until the container exits or it's stopped by `proxy_stop`.
+// Its PID is recorded in "proxy_pid", connection counts in "proxy_stats".
+
+pub struct ProxyStats {
+    pub mapping: PortMapping,
+    pub active: usize,
+    pub total: usize,
+}
+
+// Returns once all the ports are bound
+pub fn proxy_start(id: pid_t, target: IpAddr, publish: &[PortMapping]) -> io::Result<()> {
+    let mut cmd = process::Command::new("sudo");
+    cmd.arg(aucont_util("proxy"))
+        .arg(id.to_string())
+        .arg("--target").arg(target.to_string());
+    for mapping in publish {
+        cmd.arg("--publish").arg(mapping.to_string());
+    }
+    let mut child = cmd.stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+
+    let mut line = String::new();
+    io::BufReader::new(child.stdout.take().unwrap()).read_line(&mut line)?;
+    let pid = match line.trim().parse::<pid_t>() {
+        Ok(pid) => pid,
+        Err(_) => {
+            let status = child.wait()?;
+            return Err(io::Error::other(format!("Port forwarder failed to start ({})", status)));
+        },
+    };
+    // reaps the proxy when it exits, the caller may live much longer
+    thread::spawn(move || child.wait());
+    write_value(id, "proxy_pid", pid)
+}
+
+// The recorded PID is only signalled if it still belongs to the proxy
+pub fn proxy_stop(id: pid_t) -> io::Result<()> {
+    let pid: pid_t = match read_value(id, "proxy_pid") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        res => res?,
+    };
+    let is_proxy = |pid: pid_t| fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| String::from_utf8_lossy(&cmdline).contains("aucont_util_proxy"))
+        .unwrap_or(false);
+    if is_proxy(pid) {
+        sudo!("kill", "-TERM", &pid.to_string())?;
+        // the proxy must not write its stats after the container files are removed
+        for _ in 0..100 {
+            if !is_proxy(pid) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    fs::remove_file(container_info_file(id, "proxy_pid"))
+}
+
+// Lines are written by the proxy as "HOSTPORT:CONTPORT/PROTOCOL active=N total=N".
+// Empty for containers without the proxy.
+pub fn read_proxy_stats(id: pid_t) -> io::Result<Vec<ProxyStats>> {
+    let data = match fs::read_to_string(container_info_file(id, "proxy_stats")) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+    data.lines().filter(|line| !line.is_empty()).map(|line| {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("Invalid proxy stats '{}'", line));
+        let mut fields = line.split_whitespace();
+        let mapping = fields.next().ok_or_else(invalid)?.parse()?;
+        let mut counter = |name: &str| fields.next()
+            .and_then(|field| field.strip_prefix(name))
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(invalid);
+        let active = counter("active=")?;
+        let total = counter("total=")?;
+        Ok(ProxyStats { mapping, active, total })
+    }).collect()
+}
@@ -4,7 +4,7 @@ use ::std::marker::Send;
 
 pub struct Error {
     comment: String,
-    cause: Option<Box<error::Error + Send + 'static>>,
+    cause: Option<Box<dyn error::Error + Send + 'static>>,
 }
 
 impl Error {
@@ -20,15 +20,15 @@ impl Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.cause {
-            &Some(ref cause) => {
+        match self.cause {
+            Some(ref cause) => {
 //                if self.comment == "" {
 //                    write!(f, "{}", cause)
 //                } else {
                     write!(f, "{}: {}", self.comment, cause)
 //                }
             }
-            &None => {
+            None => {
                 write!(f, "{}", self.comment)
             }
         }
@@ -46,8 +46,8 @@ impl error::Error for Error {
         "Container error"
     }
 
-    fn cause(&self) -> Option<&error::Error> {
-        self.cause.as_ref().map(|e| e.as_ref() as &error::Error)
+    fn cause(&self) -> Option<&dyn error::Error> {
+        self.cause.as_ref().map(|e| e.as_ref() as &dyn error::Error)
     }
 }
 
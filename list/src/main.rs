@@ -1,15 +1,40 @@
 extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+extern crate clap;
 
 use ::core::check::Check;
+use ::core::pid_t;
+use ::container::info::read_flag;
+use ::container::health::read_health;
 use ::std::fs;
 
 fn main() {
+    let matches = clap::App::new("aucont_list")
+        .version("0.1")
+        .about("List ids of the running containers.")
+        .arg(clap::Arg::with_name("long")
+            .short("l")
+            .long("long")
+            .help("Also print the state of each container and its health, if it's checked."))
+        .get_matches();
+
     // create dir if it doesn't exist
     fs::create_dir_all(::core::CONTAINERS_DIR)
         .check("Error accessing containers directory");
     let conts = ::core::list_dir_files(::core::CONTAINERS_DIR)
         .check("Error listing containers");
     for cont in conts {
-        println!("{}", cont);
+        if !matches.is_present("long") {
+            println!("{}", cont);
+            continue;
+        }
+        let id = cont.parse::<pid_t>().ok();
+        let paused = id.and_then(|id| read_flag(id, "paused").ok()).unwrap_or(false);
+        let state = if paused { "paused" } else { "running" };
+        // only the containers with a health check have the third column
+        match id.and_then(|id| read_health(id).ok()).flatten() {
+            Some(health) => println!("{}\t{}\t{}", cont, state, health),
+            None => println!("{}\t{}", cont, state),
+        }
     }
 }
@@ -0,0 +1,71 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::core::*;
+use ::container::{Container, Shaping, parse_rate, parse_delay, parse_loss, format_rate,
+                  format_delay};
+
+fn print_shaping(shaping: &Shaping) {
+    let unlimited = || "-".to_string();
+    println!("rate\t{}", shaping.rate.map(format_rate).unwrap_or_else(unlimited));
+    println!("delay\t{}", shaping.delay.map(format_delay).unwrap_or_else(unlimited));
+    println!("loss\t{}", shaping.loss.map(|loss| format!("{}%", loss)).unwrap_or_else(unlimited));
+}
+
+fn main() {
+    let matches = clap::App::new("aucont_net")
+        .version("0.1")
+        .about("Show or change the conditions of the link between the host and a container. \
+        Options which are not given keep their current values.")
+        .arg(clap::Arg::with_name("pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start."))
+        .arg(clap::Arg::with_name("rate")
+            .long("rate")
+            .takes_value(true)
+            .value_name("RATE")
+            .help("Bandwidth in each direction, e.g. 10mbit."))
+        .arg(clap::Arg::with_name("delay")
+            .long("delay")
+            .takes_value(true)
+            .value_name("DELAY")
+            .help("Delay in each direction, e.g. 100ms. Round trips are delayed twice as much."))
+        .arg(clap::Arg::with_name("loss")
+            .long("loss")
+            .takes_value(true)
+            .value_name("PERCENT")
+            .help("Percent of the packets lost in each direction. \
+            Round trips are lost about twice as often."))
+        .arg(clap::Arg::with_name("clear")
+            .long("clear")
+            .help("Remove the current conditions before applying the given ones."))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("pid"), pid_t);
+    let mut container = Container::open(id).check("Error accessing container");
+    let mut shaping = container.shaping().check("Error reading network shaping")
+        .unwrap_or_else(|| panic!("Container with id '{}' has no virtual network", id));
+
+    let changed = ["rate", "delay", "loss", "clear"].iter().any(|arg| matches.is_present(arg));
+    if changed {
+        if matches.is_present("clear") {
+            shaping = Shaping::default();
+        }
+        if let Some(rate) = matches.value_of("rate") {
+            shaping.rate = Some(parse_rate(rate).check("Invalid rate"));
+        }
+        if let Some(delay) = matches.value_of("delay") {
+            shaping.delay = Some(parse_delay(delay).check("Invalid delay"));
+        }
+        if let Some(loss) = matches.value_of("loss") {
+            shaping.loss = Some(parse_loss(loss).check("Invalid loss"));
+        }
+        container.set_shaping(shaping).check("Error changing network shaping");
+    }
+    print_shaping(&shaping);
+}
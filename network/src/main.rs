@@ -0,0 +1,78 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::container::ipam::Network;
+use ::std::net::IpAddr;
+
+fn main() {
+    let name_arg = || clap::Arg::with_name("name")
+        .index(1)
+        .required(true)
+        .value_name("NAME")
+        .help("Name of the network");
+
+    let matches = clap::App::new("aucont_network")
+        .version("0.1")
+        .about("Manage named networks. Containers started with --network NAME get their \
+        addresses from the network.")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(clap::SubCommand::with_name("create")
+            .about("Create a network")
+            .arg(name_arg())
+            .arg(clap::Arg::with_name("subnet")
+                .long("subnet")
+                .required(true)
+                .value_name("CIDR")
+                .help("IPv4 or IPv6 subnet of the network, e.g. 10.1.0.0/16 or fd00:1::/64"))
+            .arg(clap::Arg::with_name("gateway")
+                .long("gateway")
+                .value_name("IP")
+                .help("Host address in the network, the first address of the subnet by default"))
+            .arg(clap::Arg::with_name("masquerade")
+                .long("masquerade")
+                .help("Let the containers connect to other networks through the host")))
+        .subcommand(clap::SubCommand::with_name("delete")
+            .about("Delete a network")
+            .arg(name_arg())
+            .arg(clap::Arg::with_name("force")
+                .long("force")
+                .help("Delete the network even if some of its addresses are leased")))
+        .subcommand(clap::SubCommand::with_name("list")
+            .about("Print the networks: name, subnet, gateway and the number of leases"))
+        .subcommand(clap::SubCommand::with_name("leases")
+            .about("Print the leased addresses of a network and their owners")
+            .arg(name_arg()))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("create", Some(args)) => {
+            let gateway = args.value_of("gateway")
+                .map(|_| value_t_or_exit!(args.value_of("gateway"), IpAddr));
+            Network::create(args.value_of("name").unwrap(), args.value_of("subnet").unwrap(),
+                            gateway, None, args.is_present("masquerade"))
+                .check("Error creating network");
+        },
+        ("delete", Some(args)) => {
+            Network::delete(args.value_of("name").unwrap(), args.is_present("force"))
+                .check("Error deleting network");
+        },
+        ("list", Some(_)) => {
+            for network in Network::list().check("Error listing networks") {
+                let leases = network.leases().check("Error reading leases");
+                println!("{}\t{}\t{}\t{}", network.name(), network.subnet_str(),
+                         network.gateway(), leases.len());
+            }
+        },
+        ("leases", Some(args)) => {
+            let network = Network::open(args.value_of("name").unwrap())
+                .check("Error opening network");
+            for (addr, owner) in network.leases().check("Error reading leases") {
+                println!("{}\t{}", addr, owner);
+            }
+        },
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,88 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+extern crate clap;
+
+use ::core::check::Check;
+use ::container::partition::Partitions;
+use ::std::net::IpAddr;
+
+// "replica-0,replica-1,10.0.0.5"
+fn parse_group(partitions: &Partitions, group: &str) -> Vec<IpAddr> {
+    group.split(',').filter(|member| !member.is_empty())
+        .map(|member| partitions.resolve(member).check("Invalid group member"))
+        .collect()
+}
+
+fn main() {
+    let name_arg = || clap::Arg::with_name("network")
+        .index(1)
+        .required(true)
+        .value_name("NETWORK")
+        .help("Name of the network, e.g. cluster-PID for the replicas of aucont_cluster");
+    let groups_arg = || clap::Arg::with_name("groups")
+        .index(2)
+        .multiple(true)
+        .value_name("GROUP")
+        .help("Comma-separated members: addresses or lease owners, e.g. replica-0,replica-1");
+
+    let matches = clap::App::new("aucont_partition")
+        .version("0.1")
+        .about("Cut and heal the links between the containers of a named network. \
+        Packets are dropped on the host end of the veth pair of the sender, \
+        restarted containers keep their links cut.")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(clap::SubCommand::with_name("cut")
+            .about("Cut the links between each pair of the groups")
+            .arg(name_arg())
+            .arg(groups_arg().min_values(2).required(true))
+            .arg(clap::Arg::with_name("one_way")
+                .long("one-way")
+                .help("Only drop the packets sent from the first group to the others")))
+        .subcommand(clap::SubCommand::with_name("heal")
+            .about("Restore the links between each pair of the groups, all of them by default")
+            .arg(name_arg())
+            .arg(groups_arg().min_values(2)))
+        .subcommand(clap::SubCommand::with_name("list")
+            .about("Print the cut links as pairs of the source and the destination address")
+            .arg(name_arg()))
+        .get_matches();
+
+    let (command, args) = matches.subcommand();
+    let args = args.unwrap();
+    let partitions = Partitions::open(args.value_of("network").unwrap())
+        .check("Error opening network");
+    let groups = args.values_of("groups")
+        .map(|groups| groups.map(|group| parse_group(&partitions, group)).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    match command {
+        "cut" if args.is_present("one_way") => {
+            for group in &groups[1..] {
+                partitions.cut_one_way(&groups[0], group).check("Error cutting links");
+            }
+        },
+        "cut" => {
+            for (i, a) in groups.iter().enumerate() {
+                for b in &groups[i + 1..] {
+                    partitions.cut(a, b).check("Error cutting links");
+                }
+            }
+        },
+        "heal" if groups.is_empty() => {
+            partitions.heal_all().check("Error healing links");
+        },
+        "heal" => {
+            for (i, a) in groups.iter().enumerate() {
+                for b in &groups[i + 1..] {
+                    partitions.heal(a, b).check("Error healing links");
+                }
+            }
+        },
+        "list" => {
+            for (src, dst) in partitions.links().check("Error reading cut links") {
+                println!("{}\t{}", src, dst);
+            }
+        },
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,28 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::core::pid_t;
+use ::container::Container;
+
+fn main() {
+    let matches = clap::App::new("aucont_pause")
+        .version("0.1")
+        .about("Freeze all processes of a container. Use aucont_resume to continue them.")
+        .arg(clap::Arg::with_name("pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start."))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("pid"), pid_t);
+    let mut container = Container::open(id).check("Error accessing container");
+    if container.is_paused().check("Error accessing container") {
+        panic!("Container with id '{}' is already paused", container.get_id());
+    }
+
+    container.pause().check("Error pausing container");
+}
@@ -0,0 +1,26 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+extern crate clap;
+
+use ::core::check::Check;
+use ::container::factory::ContainerFactory;
+
+fn main() {
+    let matches = clap::App::new("aucont_restore")
+        .version("0.1")
+        .about("Start a container saved by aucont_checkpoint. The container keeps its id, \
+        which must not be used by another process. \
+        Prints the ID of the restored container to the standard output. Requires CRIU.")
+        .arg(clap::Arg::with_name("dir")
+            .index(1)
+            .required(true)
+            .value_name("DIR")
+            .help("Directory with the checkpoint."))
+        .get_matches();
+
+    let dir = matches.value_of("dir").unwrap();
+    let container = ContainerFactory::restore_container(dir)
+        .check("ERROR restoring container");
+
+    println!("{}", container.get_id());
+}
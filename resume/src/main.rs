@@ -0,0 +1,28 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::core::pid_t;
+use ::container::Container;
+
+fn main() {
+    let matches = clap::App::new("aucont_resume")
+        .version("0.1")
+        .about("Continue a container paused by aucont_pause.")
+        .arg(clap::Arg::with_name("pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start."))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("pid"), pid_t);
+    let mut container = Container::open(id).check("Error accessing container");
+    if !container.is_paused().check("Error accessing container") {
+        panic!("Container with id '{}' is not paused", container.get_id());
+    }
+
+    container.resume().check("Error resuming container");
+}
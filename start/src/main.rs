@@ -6,7 +6,44 @@ extern crate clap;
 use ::core::check::Check;
 use ::container::factory::*;
 use ::std::process;
-use ::std::net::Ipv4Addr;
+use ::std::net::{IpAddr, Ipv4Addr};
+use ::core::netlink::parse_cidr;
+use ::container::ipam::Network;
+use ::container::health::{HealthCheck, parse_duration};
+use ::container::{PortMapping, Shaping, parse_rate, parse_delay, parse_loss, parse_memory};
+
+// The host gets the next address in the subnet for IPv4, IPv6 containers use the link-local
+// gateway. Without a prefix the link is point-to-point and any next address will do.
+fn derive_host_addr(cont_addr: IpAddr, prefix_len: u8) -> Option<IpAddr> {
+    let cont_addr = match cont_addr {
+        IpAddr::V4(addr) => u32::from(addr),
+        IpAddr::V6(_) => return None,
+    };
+    let host_bits = if prefix_len == 32 { 32 } else { 32 - u32::from(prefix_len) };
+    let host_mask = if host_bits == 32 { !0 } else { (1 << host_bits) - 1 };
+    let network = cont_addr & !host_mask;
+    let host_addr = network | (cont_addr.wrapping_add(1) & host_mask);
+    // /31 subnets have no network and broadcast addresses
+    let reserved = host_bits >= 2 && (host_addr == network || host_addr == network | host_mask);
+    if host_addr == cont_addr || reserved {
+        panic!("Can't derive host address from {}/{}, use --network instead",
+               Ipv4Addr::from(cont_addr), prefix_len);
+    }
+    Some(IpAddr::V4(Ipv4Addr::from(host_addr)))
+}
+
+// "NAME:IP", the address may contain colons itself
+fn parse_host_entry(entry: &str) -> (String, IpAddr) {
+    let mut parts = entry.splitn(2, ':');
+    let name = parts.next().unwrap();
+    let addr = parts.next().and_then(|addr| addr.parse().ok());
+    match addr {
+        Some(addr) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+            (name.to_string(), addr)
+        },
+        _ => panic!("Invalid host entry '{}', expected NAME:IP", entry),
+    }
+}
 
 fn main() {
     let matches = clap::App::new("aucont_start")
@@ -18,12 +55,131 @@ fn main() {
             .takes_value(true)
             .value_name("CPU_PERC")
             .help("Percent of cpu resources allocated for container (0..100)."))
+        .arg(clap::Arg::with_name("memory")
+            .long("memory")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Memory limit of the container in bytes, or with a unit: 512k, 256M or 1G."))
         .arg(clap::Arg::with_name("net")
             .long("net")
             .takes_value(true)
-            .value_name("IP")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("IP[/PREFIX]")
             .help("Create virtual network between host and container.\n\
-            IP — container ip address, IP+1 — host ip address."))
+            IP — container ip address, IP+1 — host ip address for IPv4. \
+            IPv6 containers use the link-local address fe80::1 of the host as the gateway. \
+            May be given twice for an IPv4 and an IPv6 address."))
+        .arg(clap::Arg::with_name("net_mode")
+            .long("net-mode")
+            .takes_value(true)
+            .value_name("MODE")
+            .conflicts_with_all(&["net", "network", "publish"])
+            .help("Network namespace of the container instead of a virtual network: \
+            none (or private) — own namespace with only the loopback interface, \
+            host — namespace of the host, container:ID — namespace of another container."))
+        .arg(clap::Arg::with_name("network")
+            .long("network")
+            .takes_value(true)
+            .value_name("NAME")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("net")
+            .help("Connect the container to a network created with aucont_network. \
+            The address is allocated automatically unless --ip is given. \
+            May be given twice for an IPv4 and an IPv6 network."))
+        .arg(clap::Arg::with_name("ip")
+            .long("ip")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("IP")
+            .requires("network")
+            .help("Address of the container in one of the networks given by --network."))
+        .arg(clap::Arg::with_name("publish")
+            .long("publish")
+            .short("p")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("HOSTPORT:CONTPORT[/tcp|udp]")
+            .help("Forward a port of the host to the container. Requires --net or --network."))
+        .arg(clap::Arg::with_name("userland_proxy")
+            .long("userland-proxy")
+            .requires("publish")
+            .help("Forward the published ports with a userspace proxy instead of firewall rules. \
+            Connection counts are shown by aucont_stats."))
+        .arg(clap::Arg::with_name("net_rate")
+            .long("net-rate")
+            .takes_value(true)
+            .value_name("RATE")
+            .help("Bandwidth of the link to the host in each direction, e.g. 10mbit. \
+            Requires --net or --network, may be changed with aucont_net."))
+        .arg(clap::Arg::with_name("net_delay")
+            .long("net-delay")
+            .takes_value(true)
+            .value_name("DELAY")
+            .help("Delay of the link to the host in each direction, e.g. 100ms. \
+            Round trips are delayed twice as much."))
+        .arg(clap::Arg::with_name("net_loss")
+            .long("net-loss")
+            .takes_value(true)
+            .value_name("PERCENT")
+            .help("Percent of the packets lost in each direction. \
+            Round trips are lost about twice as often."))
+        .arg(clap::Arg::with_name("dns")
+            .long("dns")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("IP")
+            .help("Name server written to /etc/resolv.conf of the container."))
+        .arg(clap::Arg::with_name("add_host")
+            .long("add-host")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME:IP")
+            .help("Entry added to /etc/hosts of the container. \
+            Containers on named networks also get entries for the named peers, \
+            e.g. cluster replicas."))
+        .arg(clap::Arg::with_name("name")
+            .long("name")
+            .takes_value(true)
+            .value_name("NAME")
+            .requires("network")
+            .help("Name by which the other containers on the networks reach the container. \
+            Must be unique within each of the networks."))
+        .arg(clap::Arg::with_name("health_cmd")
+            .long("health-cmd")
+            .takes_value(true)
+            .value_name("CMD")
+            .help("Command run inside the container with /bin/sh -c to check its health. \
+            The status (starting, healthy or unhealthy) is shown by aucont_list -l."))
+        .arg(clap::Arg::with_name("health_interval")
+            .long("health-interval")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("health_cmd")
+            .help("Time between the health checks, e.g. 30s (default), 500ms or 1m."))
+        .arg(clap::Arg::with_name("health_timeout")
+            .long("health-timeout")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("health_cmd")
+            .help("A health check running longer is killed and considered failed (30s)."))
+        .arg(clap::Arg::with_name("health_retries")
+            .long("health-retries")
+            .takes_value(true)
+            .value_name("N")
+            .requires("health_cmd")
+            .help("Failed health checks in a row before the container is unhealthy (3)."))
+        .arg(clap::Arg::with_name("health_start_period")
+            .long("health-start-period")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("health_cmd")
+            .help("Failed health checks don't count for this long after the start (0s)."))
         .arg(clap::Arg::with_name("daemonize")
             .short("d")
             .long("daemonize")
@@ -46,11 +202,77 @@ fn main() {
             .help("Arguments for <CMD>."))
         .get_matches();
 
-    let net_config = matches.value_of("net").map(|addr_str| {
-        let cont_addr: Ipv4Addr = addr_str.parse().check("Can't parse ip address");
-        let octets = cont_addr.octets();
-        let host_addr = Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3] + 1));
-        NetworkConfig { cont_addr, host_addr, host_bridge: None }
+    let publish = match matches.values_of("publish") {
+        Some(_) => values_t_or_exit!(matches.values_of("publish"), PortMapping),
+        None => vec![],
+    };
+    if !publish.is_empty() && !matches.is_present("net") && !matches.is_present("network") {
+        panic!("--publish requires --net or --network");
+    }
+    let shaping = Shaping {
+        rate: matches.value_of("net_rate").map(|rate| parse_rate(rate).check("Invalid rate")),
+        delay: matches.value_of("net_delay")
+            .map(|delay| parse_delay(delay).check("Invalid delay")),
+        loss: matches.value_of("net_loss").map(|loss| parse_loss(loss).check("Invalid loss")),
+    };
+    if !shaping.is_empty() && !matches.is_present("net") && !matches.is_present("network") {
+        panic!("--net-rate, --net-delay and --net-loss require --net or --network");
+    }
+    // addresses are added to the config when leased from the networks
+    let addrs = matches.values_of("net").map(|addrs| addrs.map(|addr| {
+        let (cont_addr, prefix_len) = parse_cidr(addr).check("Can't parse ip address");
+        NetworkAddress { cont_addr, prefix_len, host_addr: derive_host_addr(cont_addr, prefix_len) }
+    }).collect::<Vec<_>>()).unwrap_or_default();
+    if addrs.iter().filter(|addr| addr.cont_addr.is_ipv4()).count() > 1
+        || addrs.iter().filter(|addr| addr.cont_addr.is_ipv6()).count() > 1 {
+        panic!("--net may be given once for an IPv4 and once for an IPv6 address");
+    }
+    let net_config = if matches.is_present("net") || !publish.is_empty() || !shaping.is_empty() {
+        Some(NetworkConfig {
+            addrs,
+            publish,
+            shaping,
+            userland_proxy: matches.is_present("userland_proxy"),
+            ..Default::default()
+        })
+    } else {
+        None
+    };
+
+    let ips = match matches.values_of("ip") {
+        Some(_) => values_t_or_exit!(matches.values_of("ip"), IpAddr),
+        None => vec![],
+    };
+    let networks = matches.values_of("network").map(|names| names.map(|name| {
+        Network::open(name).check("Error opening network")
+    }).collect::<Vec<_>>()).unwrap_or_default();
+    if networks.iter().filter(|network| !network.is_ipv6()).count() > 1
+        || networks.iter().filter(|network| network.is_ipv6()).count() > 1 {
+        panic!("--network may be given once for an IPv4 and once for an IPv6 network");
+    }
+    let leases = networks.iter().map(|network| NetworkLease {
+        network: network.name().to_string(),
+        addr: ips.iter().cloned().find(|ip| network.contains(*ip)),
+    }).collect::<Vec<_>>();
+    if let Some(ip) = ips.iter().find(|ip| !leases.iter().any(|lease| lease.addr == Some(**ip))) {
+        panic!("Address {} doesn't belong to any of the networks", ip);
+    }
+
+    let health_check = matches.value_of("health_cmd").map(|cmd| {
+        let duration = |name: &str, default| matches.value_of(name)
+            .map(|value| parse_duration(value).check("Invalid health check duration"))
+            .unwrap_or(default);
+        let check = HealthCheck::new(cmd.to_string());
+        HealthCheck {
+            interval: duration("health_interval", check.interval),
+            timeout: duration("health_timeout", check.timeout),
+            retries: match matches.value_of("health_retries") {
+                Some(_) => value_t_or_exit!(matches.value_of("health_retries"), u32),
+                None => check.retries,
+            },
+            start_period: duration("health_start_period", check.start_period),
+            ..check
+        }
     });
 
     let container = ContainerFactory::new_container(
@@ -62,9 +284,25 @@ fn main() {
                 Some(args) => args.map(|s| s.to_string()).collect(),
                 None => vec![],
             },
+            net_mode: match matches.value_of("net_mode") {
+                Some(_) => value_t_or_exit!(matches.value_of("net_mode"), NetworkMode),
+                None => NetworkMode::Private,
+            },
             net: net_config,
+            leases,
+            dns: match matches.values_of("dns") {
+                Some(_) => values_t_or_exit!(matches.values_of("dns"), IpAddr),
+                None => vec![],
+            },
+            name: matches.value_of("name").map(|name| name.to_string()),
+            hosts: matches.values_of("add_host")
+                .map(|entries| entries.map(parse_host_entry).collect())
+                .unwrap_or_default(),
             cpu_perc: matches.value_of("cpu")
                 .map(|_| value_t_or_exit!(matches.value_of("cpu"), u32)),
+            memory_limit: matches.value_of("memory")
+                .map(|memory| parse_memory(memory).check("Invalid memory limit")),
+            health_check,
             ..Default::default()
         }
     ).check("ERROR creating container");
@@ -73,6 +311,42 @@ fn main() {
 
     if !container.is_daemon() {
         let ret = container.wait_and_clear().check("Internal error (join)");
-        process::exit(ret);
+        process::exit(ret.code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_addr(cont_addr: &str, prefix_len: u8) -> Option<IpAddr> {
+        derive_host_addr(cont_addr.parse().unwrap(), prefix_len)
+    }
+
+    #[test]
+    fn host_addr_is_next_in_subnet() {
+        assert_eq!(host_addr("10.0.0.2", 24), Some("10.0.0.3".parse().unwrap()));
+        assert_eq!(host_addr("10.0.0.2", 32), Some("10.0.0.3".parse().unwrap()));
+        assert_eq!(host_addr("10.0.0.255", 32), Some("10.0.1.0".parse().unwrap()));
+        assert_eq!(host_addr("10.0.0.1", 31), Some("10.0.0.0".parse().unwrap()));
+        assert_eq!(host_addr("fd00::2", 64), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn host_addr_is_not_broadcast() {
+        host_addr("10.0.0.254", 24);
+    }
+
+    #[test]
+    #[should_panic]
+    fn host_addr_is_not_network() {
+        host_addr("10.0.0.255", 24);
+    }
+
+    #[test]
+    #[should_panic]
+    fn host_addr_is_not_limited_broadcast() {
+        host_addr("255.255.255.254", 32);
     }
 }
@@ -0,0 +1,29 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+#[macro_use]
+extern crate clap;
+
+use ::core::check::Check;
+use ::core::*;
+use ::std::path;
+use ::container::proxy::read_proxy_stats;
+
+fn main() {
+    let matches = clap::App::new("aucont_stats")
+        .version("0.1")
+        .about("Print connection counts of the ports published with --userland-proxy.")
+        .arg(clap::Arg::with_name("pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start."))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("pid"), pid_t);
+    if !path::Path::new(&container_dir(id)).exists() {
+        panic!("Container with id '{}' doesn't exist", id);
+    }
+    for stats in read_proxy_stats(id).check("Error reading proxy stats") {
+        println!("{}\tactive={}\ttotal={}", stats.mapping, stats.active, stats.total);
+    }
+}
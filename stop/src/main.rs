@@ -34,5 +34,5 @@ fn main() {
 
     container.cancel(signal).check("Error cancelling container");
     let ret = container.wait_and_clear().check("Internal error (join)");
-    process::exit(ret);
+    process::exit(ret.code());
 }
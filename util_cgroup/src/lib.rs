@@ -2,15 +2,24 @@
 extern crate aucont_core as core;
 
 use ::core::raw_process::pid_t;
-use ::std::io;
+use ::std::{io, fs};
+use ::std::time::Duration;
+use ::std::io::Write;
 use ::core::{getuid, getgid};
 use ::core::aucont_paths::aucont_util;
+use ::core::shell::shell_cmd;
 
-pub fn cgroup_create(cont_id: pid_t, perc: u32) -> io::Result<()> {
-   sudo!(&aucont_util("cgroup"), &cont_id.to_string(), "create",
-       "--perc", &perc.to_string(),
-       "--uid", &getuid().to_string(),
-       "--gid", &getgid().to_string())
+// `memory` is the limit in bytes
+pub fn cgroup_create(cont_id: pid_t, perc: u32, memory: Option<u64>) -> io::Result<()> {
+    let mut args = vec![aucont_util("cgroup"), cont_id.to_string(), "create".to_string(),
+                        "--perc".to_string(), perc.to_string(),
+                        "--uid".to_string(), getuid().to_string(),
+                        "--gid".to_string(), getgid().to_string()];
+    if let Some(memory) = memory {
+        args.push("--memory".to_string());
+        args.push(memory.to_string());
+    }
+    shell_cmd("sudo", &args)
 }
 
 pub fn cgroup_enter(cont_id: pid_t, target: pid_t) -> io::Result<()> {
@@ -18,6 +27,255 @@ pub fn cgroup_enter(cont_id: pid_t, target: pid_t) -> io::Result<()> {
        "--target", &target.to_string())
 }
 
+// Moves `target` into the cgroups of the container without calling the helper.
+// Uses the mount points of the hierarchies on the host, since the cgroups are owned by the user.
+// Falls back to the helper if a hierarchy isn't mounted or can't be written to.
+pub fn cgroup_enter_native(cont_id: pid_t, target: pid_t) -> io::Result<()> {
+    let freezer = match cgroup_version("freezer")? {
+        CgroupVersion::V1 => (Some("freezer"), "tasks"),
+        CgroupVersion::V2 => (None, "cgroup.procs"),
+    };
+    let mut hierarchies = vec![(Some("cpu"), "tasks"), freezer];
+    // containers without a memory limit stay in the memory cgroup of their parent
+    if cgroup_version("memory")? == CgroupVersion::V1 {
+        let own = find_cgroup_dir(cont_id, Some("memory"))?
+            .map(|dir| dir.ends_with(&format!("/aucont_{}", cont_id)))
+            .unwrap_or(false);
+        if own {
+            hierarchies.push((Some("memory"), "tasks"));
+        }
+    }
+    for &(controller, file) in &hierarchies {
+        let res = match find_cgroup_dir(cont_id, controller)? {
+            Some(dir) => fs::OpenOptions::new().write(true).open(format!("{}/{}", dir, file))
+                .and_then(|mut f| f.write_all(target.to_string().as_bytes())),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+        if res.is_err() {
+            return cgroup_enter(cont_id, target);
+        }
+    }
+    Ok(())
+}
+
+// Changes the cpu limit of a running container
+pub fn cgroup_set_cpu(cont_id: pid_t, perc: u32) -> io::Result<()> {
+   sudo!(&aucont_util("cgroup"), &cont_id.to_string(), "set-cpu", "--perc", &perc.to_string())
+}
+
+pub fn cgroup_freeze(cont_id: pid_t) -> io::Result<()> {
+   sudo!(&aucont_util("cgroup"), &cont_id.to_string(), "freeze")
+}
+
+pub fn cgroup_thaw(cont_id: pid_t) -> io::Result<()> {
+   sudo!(&aucont_util("cgroup"), &cont_id.to_string(), "thaw")
+}
+
+// Host path of the freezer cgroup of the container, if the hierarchy is mounted
+pub fn freezer_cgroup_dir(cont_id: pid_t) -> io::Result<Option<String>> {
+    match cgroup_version("freezer")? {
+        CgroupVersion::V1 => find_cgroup_dir(cont_id, Some("freezer")),
+        CgroupVersion::V2 => find_cgroup_dir(cont_id, None),
+    }
+}
+
+// Version of the hierarchy a controller is used through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    // a hierarchy of its own, e.g. freezer.state in the "freezer" hierarchy
+    V1,
+    // the unified hierarchy, e.g. cgroup.freeze
+    V2,
+}
+
+// A v1 controller is used if the system has it attached to a hierarchy.
+// With v2, all the controllers share the cgroup of the container.
+pub fn cgroup_version(controller: &str) -> io::Result<CgroupVersion> {
+    // lines look like "freezer 6 1 1": name, hierarchy id, number of cgroups, enabled
+    let cgroups = fs::read_to_string("/proc/cgroups")?;
+    let v1 = cgroups.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .any(|fields| fields.len() == 4 && fields[0] == controller
+            && fields[1] != "0" && fields[3] == "1");
+    Ok(if v1 { CgroupVersion::V1 } else { CgroupVersion::V2 })
+}
+
+// Sizes in bytes with optional binary units: "65536", "512k", "256M" or "1G"
+pub fn parse_memory(s: &str) -> io::Result<u64> {
+    let lower = s.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => Some(1),
+        "k" | "kb" | "kib" => Some(1 << 10),
+        "m" | "mb" | "mib" => Some(1 << 20),
+        "g" | "gb" | "gib" => Some(1 << 30),
+        _ => None,
+    };
+    multiplier.and_then(|multiplier| digits.parse::<u64>().ok()
+        .and_then(|value| value.checked_mul(multiplier)))
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "Invalid memory size '{}', expected a number of bytes with an optional unit k, M or G",
+            s)))
+}
+
+// The largest unit which represents the size exactly
+pub fn format_memory(bytes: u64) -> String {
+    let units = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "k")];
+    match units.iter().find(|&&(size, _)| bytes.is_multiple_of(size)) {
+        Some(&(size, unit)) => format!("{}{}", bytes / size, unit),
+        None => bytes.to_string(),
+    }
+}
+
+// Returns the host path of the cgroup of process `pid` in the hierarchy with `controller`.
+// `None` stands for the unified (v2) hierarchy.
+fn find_cgroup_dir(pid: pid_t, controller: Option<&str>) -> io::Result<Option<String>> {
+    let matches_hierarchy = |list: &str| match controller {
+        Some(controller) => list.split(',').any(|c| c == controller),
+        None => list.is_empty(),
+    };
+
+    // lines look like "4:cpu,cpuacct:/aucont_42" or "0::/aucont_42" for the unified hierarchy
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+    let cgroup_path = cgroups.lines()
+        .map(|line| line.splitn(3, ':').collect::<Vec<_>>())
+        .find(|fields| fields.len() == 3 && matches_hierarchy(fields[1]))
+        .map(|fields| fields[2].to_string());
+    match cgroup_path {
+        Some(path) => mounted_cgroup_dir(controller, &path),
+        None => Ok(None),
+    }
+}
+
+// Returns the host path of `cgroup_path` in the hierarchy with `controller`
+fn mounted_cgroup_dir(controller: Option<&str>, cgroup_path: &str) -> io::Result<Option<String>> {
+    let fstype = if controller.is_some() { "cgroup" } else { "cgroup2" };
+
+    // see proc(5) for the format of mountinfo
+    let mounts = fs::read_to_string("/proc/self/mountinfo")?;
+    for line in mounts.lines() {
+        let fields: Vec<_> = line.split(' ').collect();
+        let sep = match fields.iter().position(|&f| f == "-") {
+            Some(sep) if fields.len() > sep + 3 => sep,
+            _ => continue,
+        };
+        if fields[sep + 1] != fstype {
+            continue;
+        }
+        if let Some(controller) = controller {
+            if !fields[sep + 3].split(',').any(|c| c == controller) {
+                continue;
+            }
+        }
+        let (mount_root, mount_point) = (fields[3], fields[4]);
+        let rel_path = match cgroup_path.strip_prefix(mount_root) {
+            _ if mount_root == "/" => cgroup_path,
+            Some(rel_path) => rel_path,
+            None => continue,
+        };
+        return Ok(Some(format!("{}{}", mount_point, rel_path)));
+    }
+    Ok(None)
+}
+
+// The cgroup of the container in the hierarchy with `controller`, if it has one of its own.
+// Once the init process has exited, the cgroup is looked up at the root of the hierarchy,
+// where the helper creates it.
+fn own_cgroup_dir(cont_id: pid_t, controller: Option<&str>) -> io::Result<Option<String>> {
+    let name = format!("/aucont_{}", cont_id);
+    match find_cgroup_dir(cont_id, controller) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(mounted_cgroup_dir(controller, &name)?
+                .filter(|dir| ::std::path::Path::new(dir).is_dir()))
+        },
+        res => Ok(res?.filter(|dir| dir.ends_with(&name))),
+    }
+}
+
+// Resource usage of a container, None if it isn't accounted in a cgroup of its own
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgroupStats {
+    pub cpu_time: Option<Duration>,
+    // in bytes
+    pub memory_usage: Option<u64>,
+    // the highest memory usage so far, if the kernel records it
+    pub memory_peak: Option<u64>,
+}
+
+fn read_counter(dir: &str, file: &str) -> io::Result<Option<u64>> {
+    match fs::read_to_string(format!("{}/{}", dir, file)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        res => res?.trim().parse::<u64>().map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+// Lines look like "usage_usec 1234"
+fn read_keyed_counter(dir: &str, file: &str, key: &str) -> io::Result<Option<u64>> {
+    let data = match fs::read_to_string(format!("{}/{}", dir, file)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        res => res?,
+    };
+    Ok(data.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() == 2 && fields[0] == key)
+        .and_then(|fields| fields[1].parse::<u64>().ok()))
+}
+
+// Works until the cgroups of the container are deleted, even after its init process has exited
+pub fn cgroup_stats(cont_id: pid_t) -> io::Result<CgroupStats> {
+    let own_dir = |controller: Option<&str>| own_cgroup_dir(cont_id, controller);
+    let mut stats = CgroupStats::default();
+
+    if let Some(dir) = own_dir(Some("cpuacct"))? {
+        stats.cpu_time = read_counter(&dir, "cpuacct.usage")?.map(Duration::from_nanos);
+    }
+    let unified = own_dir(None)?;
+    if let (None, Some(dir)) = (stats.cpu_time, &unified) {
+        stats.cpu_time = read_keyed_counter(dir, "cpu.stat", "usage_usec")?
+            .map(Duration::from_micros);
+    }
+
+    match cgroup_version("memory")? {
+        CgroupVersion::V1 => if let Some(dir) = own_dir(Some("memory"))? {
+            stats.memory_usage = read_counter(&dir, "memory.usage_in_bytes")?;
+            stats.memory_peak = read_counter(&dir, "memory.max_usage_in_bytes")?;
+        },
+        CgroupVersion::V2 => if let Some(dir) = &unified {
+            // the files exist if the memory controller is enabled for the cgroup
+            stats.memory_usage = read_counter(dir, "memory.current")?;
+            stats.memory_peak = read_counter(dir, "memory.peak")?;
+        },
+    }
+    Ok(stats)
+}
+
 pub fn cgroup_delete(cont_id: pid_t) -> io::Result<()> {
    sudo!(&aucont_util("cgroup"), &cont_id.to_string(), "delete")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_units_are_binary() {
+        assert_eq!(parse_memory("64").unwrap(), 64);
+        assert_eq!(parse_memory("512k").unwrap(), 524_288);
+        assert_eq!(parse_memory("256MiB").unwrap(), 268_435_456);
+        assert_eq!(parse_memory("1G").unwrap(), 1_073_741_824);
+        assert!(parse_memory("0").is_err());
+        assert!(parse_memory("1.5G").is_err());
+        assert!(parse_memory("2T").is_err());
+        // 2^64 bytes
+        assert!(parse_memory("17179869184G").is_err());
+    }
+
+    #[test]
+    fn memory_is_shown_in_the_largest_exact_unit() {
+        assert_eq!(format_memory(1536 << 20), "1536M");
+        assert_eq!(format_memory(2 << 30), "2G");
+        assert_eq!(format_memory(1000), "1000");
+    }
+}
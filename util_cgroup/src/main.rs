@@ -1,15 +1,17 @@
 extern crate aucont_core as core;
+extern crate aucont_util_cgroup as cgroup;
 #[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate scopeguard;
 
-use ::std::fs;
+use ::std::{fs, thread, time};
 use ::std::path::Path;
 use ::core::container_dir_suf;
 use ::core::{pid_t, sys_mount, sys_umount, sys_chown, uid_t, gid_t, get_nprocs};
 use ::std::io::{self, Write};
 use ::core::check::Check;
+use ::cgroup::{cgroup_version, CgroupVersion};
 
 fn add_task_to_cgroup(cgroup_path: &str, pid: pid_t) -> io::Result<()> {
     let pid_str = pid.to_string();
@@ -17,6 +19,91 @@ fn add_task_to_cgroup(cgroup_path: &str, pid: pid_t) -> io::Result<()> {
         .and_then(|mut f| f.write_all(pid_str.as_bytes()))
 }
 
+// cgroup v2 has no "tasks" file
+fn add_proc_to_cgroup(cgroup_path: &str, pid: pid_t) -> io::Result<()> {
+    let pid_str = pid.to_string();
+    fs::File::create(format!("{}/cgroup.procs", cgroup_path))
+        .and_then(|mut f| f.write_all(pid_str.as_bytes()))
+}
+
+fn write_cgroup_file(cgroup_path: &str, file: &str, value: &str) -> io::Result<()> {
+    fs::File::create(format!("{}/{}", cgroup_path, file))
+        .and_then(|mut f| f.write_all(value.as_bytes()))
+}
+
+// `perc` of all the cpus
+fn set_cpu_quota(cgroup_path: &str, perc: u32) {
+    let nprocs = get_nprocs();
+    let period = 100000;
+    // the kernel rejects quotas below 1ms
+    let quota = (period * perc * nprocs / 100).max(1000);
+
+    fs::File::create(format!("{}/cpu.cfs_period_us", cgroup_path))
+        .and_then(|mut f| f.write_all(period.to_string().as_bytes()))
+        .check("Error setting cgroup cpu period");
+
+    fs::File::create(format!("{}/cpu.cfs_quota_us", cgroup_path))
+        .and_then(|mut f| f.write_all(quota.to_string().as_bytes()))
+        .check("Error setting cgroup cpu quota");
+}
+
+fn remove_cgroup_rec<P: AsRef<Path>>(path: P) {
+    for entry in fs::read_dir(path.as_ref()).check("Error opening cgroup dir") {
+        let path = entry.check("Error accessing cgroup data").path();
+        if path.is_dir() {
+            remove_cgroup_rec(path);
+        }
+    }
+    fs::remove_dir(path).check("Error deleting cgroup");
+}
+
+// Mounts a cgroup hierarchy inside the container directory for the duration of `f`.
+// `f` receives the path of the container's cgroup in this hierarchy.
+fn with_hierarchy<F: FnOnce(&str)>(id: pid_t, name: &str, fstype: &str, options: Option<&str>,
+                                   f: F) {
+    let mount_path = &container_dir_suf(id, &format!("/{}_cgroup", name)) as &str;
+    let cgroup_path = &format!("{}/aucont_{}", mount_path, id) as &str;
+
+    fs::create_dir(mount_path)
+        .check("Error creating directory for cgroup mount");
+    defer! {{ fs::remove_dir(mount_path).log_error("Error removing cgroup directory"); }}
+    sys_mount(&format!("aucont_{}_cgroup", name), mount_path, fstype, 0, options)
+        .check("Error mounting cgroup filesystem");
+    defer! {{ sys_umount(mount_path).log_error("Error unmounting cgroup"); }}
+
+    f(cgroup_path);
+}
+
+fn with_freezer_hierarchy<F: FnOnce(&str, CgroupVersion)>(id: pid_t, f: F) {
+    let version = cgroup_version("freezer").check("Error detecting freezer cgroup version");
+    match version {
+        CgroupVersion::V1 => with_hierarchy(id, "freezer", "cgroup", Some("freezer"),
+                                             |path| f(path, version)),
+        CgroupVersion::V2 => with_hierarchy(id, "unified", "cgroup2", None,
+                                             |path| f(path, version)),
+    }
+}
+
+// Freezing is asynchronous, so wait until the state settles
+fn set_frozen(cgroup_path: &str, version: CgroupVersion, frozen: bool) -> io::Result<()> {
+    let (file, value, state_file, expected) = match version {
+        CgroupVersion::V1 => ("freezer.state", if frozen { "FROZEN" } else { "THAWED" },
+                               "freezer.state", if frozen { "FROZEN" } else { "THAWED" }),
+        CgroupVersion::V2 => ("cgroup.freeze", if frozen { "1" } else { "0" },
+                               "cgroup.events", if frozen { "frozen 1" } else { "frozen 0" }),
+    };
+    write_cgroup_file(cgroup_path, file, value)?;
+
+    for _ in 0..100 {
+        let state = fs::read_to_string(format!("{}/{}", cgroup_path, state_file))?;
+        if state.lines().any(|line| line.trim() == expected) {
+            return Ok(());
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "cgroup freezer state didn't settle"))
+}
+
 fn main() {
     let matches = clap::App::new("aucont_util_cgroup")
         .version("0.1")
@@ -31,12 +118,16 @@ fn main() {
             .index(2)
             .required(true)
             .value_name("COMMAND")
-            .possible_values(&["create", "delete", "enter"]))
+            .possible_values(&["create", "delete", "enter", "freeze", "thaw", "set-cpu"]))
         .arg(clap::Arg::with_name("perc")
             .long("perc")
-            .required_if("cmd", "create")
+            .required_ifs(&[("cmd", "create"), ("cmd", "set-cpu")])
             .value_name("CPU_PERC")
-            .help("For create command"))
+            .help("For create and set-cpu commands"))
+        .arg(clap::Arg::with_name("memory")
+            .long("memory")
+            .value_name("BYTES")
+            .help("For create command: memory limit of the container"))
         .arg(clap::Arg::with_name("uid")
             .long("uid")
             .required_if("cmd", "create")
@@ -58,16 +149,6 @@ fn main() {
     let id = value_t_or_exit!(matches.value_of("cont_pid"), pid_t);
     let cmd = matches.value_of("cmd").unwrap();
 
-    let mount_path = &container_dir_suf(id, "/cpu_cgroup") as &str;
-    let cgroup_path = &format!("{}/aucont_{}", mount_path, id) as &str;
-
-    fs::create_dir(mount_path)
-        .check("Error creating directory for cgroup mount");
-    defer! {{ fs::remove_dir(mount_path).log_error("Error removing cgroup directory"); }}
-    sys_mount("aucont_cpu_cgroup", mount_path, "cgroup", 0, Some("cpu,cpuacct"))
-        .check("Error mounting cgroup filesystem");
-    defer! {{ sys_umount(mount_path).log_error("Error unmounting cgroup"); }}
-
     if cmd == "create" {
         let perc = value_t_or_exit!(matches.value_of("perc"), u32);
         let uid = value_t_or_exit!(matches.value_of("uid"), uid_t);
@@ -76,43 +157,127 @@ fn main() {
         if perc > 100 {
             panic!("Percent of cpu must not be greater than 100");
         }
+        let memory = matches.value_of("memory")
+            .map(|_| value_t_or_exit!(matches.value_of("memory"), u64));
+        let memory_version = cgroup_version("memory")
+            .check("Error detecting memory cgroup version");
+        let freezer_version = cgroup_version("freezer")
+            .check("Error detecting freezer cgroup version");
+        if memory.is_some() && memory_version == CgroupVersion::V2
+            && freezer_version == CgroupVersion::V1 {
+            panic!("Memory limits require the memory controller in the unified hierarchy \
+            together with the freezer, or in its own v1 hierarchy");
+        }
 
-        fs::create_dir(cgroup_path).check("Error creating cgroup");
-        defer_on_unwind!
-            {{ fs::remove_dir(cgroup_path).log_error("Error removing cgroup directory"); }}
-        sys_chown(cgroup_path, uid, gid).check("Error setting owner of cgroup");
-        add_task_to_cgroup(cgroup_path, id).check("Error adding process to cgroup");
-
-        let nprocs = get_nprocs();
-        let period = 100000;
-        let quota = period * perc * nprocs / 100;
+        with_hierarchy(id, "cpu", "cgroup", Some("cpu,cpuacct"), |cgroup_path| {
+            fs::create_dir(cgroup_path).check("Error creating cgroup");
+            defer_on_unwind!
+                {{ fs::remove_dir(cgroup_path).log_error("Error removing cgroup directory"); }}
+            sys_chown(cgroup_path, uid, gid).check("Error setting owner of cgroup");
+            // allows the owner to move processes into the cgroup without this utility
+            for file in &["tasks", "cgroup.procs"] {
+                sys_chown(&format!("{}/{}", cgroup_path, file), uid, gid)
+                    .check("Error setting owner of cgroup");
+            }
+            add_task_to_cgroup(cgroup_path, id).check("Error adding process to cgroup");
+            set_cpu_quota(cgroup_path, perc);
+        });
 
-        fs::File::create(format!("{}/cpu.cfs_period_us", cgroup_path))
-            .and_then(|mut f| f.write_all(period.to_string().as_bytes()))
-            .check("Error setting cgroup cpu period");
+        if let (Some(memory), CgroupVersion::V1) = (memory, memory_version) {
+            with_hierarchy(id, "memory", "cgroup", Some("memory"), |cgroup_path| {
+                fs::create_dir(cgroup_path).check("Error creating memory cgroup");
+                defer_on_unwind!
+                    {{ fs::remove_dir(cgroup_path).log_error("Error removing cgroup directory"); }}
+                for file in &["tasks", "cgroup.procs"] {
+                    sys_chown(&format!("{}/{}", cgroup_path, file), uid, gid)
+                        .check("Error setting owner of cgroup");
+                }
+                write_cgroup_file(cgroup_path, "memory.limit_in_bytes", &memory.to_string())
+                    .check("Error setting cgroup memory limit");
+                add_task_to_cgroup(cgroup_path, id).check("Error adding process to memory cgroup");
+            });
+        }
 
-        fs::File::create(format!("{}/cpu.cfs_quota_us", cgroup_path))
-            .and_then(|mut f| f.write_all(quota.to_string().as_bytes()))
-            .check("Error setting cgroup cpu quota");
+        with_freezer_hierarchy(id, |cgroup_path, version| {
+            fs::create_dir(cgroup_path).check("Error creating freezer cgroup");
+            defer_on_unwind!
+                {{ fs::remove_dir(cgroup_path).log_error("Error removing cgroup directory"); }}
+            if let (Some(memory), CgroupVersion::V2) = (memory, version) {
+                // the controller has to be enabled in the parent, i.e. the root of the hierarchy
+                let root = Path::new(cgroup_path).parent().unwrap().to_str().unwrap();
+                write_cgroup_file(root, "cgroup.subtree_control", "+memory")
+                    .check("Error enabling memory controller");
+                write_cgroup_file(cgroup_path, "memory.max", &memory.to_string())
+                    .check("Error setting cgroup memory limit");
+            }
+            for file in &["tasks", "cgroup.procs"] {
+                let path = format!("{}/{}", cgroup_path, file);
+                if Path::new(&path).exists() {
+                    sys_chown(&path, uid, gid).check("Error setting owner of cgroup");
+                }
+            }
+            add_proc_to_cgroup(cgroup_path, id).check("Error adding process to freezer cgroup");
+        });
     }
 
     if cmd == "delete" {
-        fn remove_cgroup_rec<P: AsRef<Path>>(path: P) {
-            for entry in fs::read_dir(path.as_ref()).check("Error opening cgroup dir") {
-                let path = entry.check("Error accessing cgroup data").path();
-                if path.is_dir() {
-                    remove_cgroup_rec(path);
-                }
+        with_hierarchy(id, "cpu", "cgroup", Some("cpu,cpuacct"), |cgroup_path| {
+            if Path::new(cgroup_path).exists() {
+                remove_cgroup_rec(cgroup_path);
             }
-            fs::remove_dir(path).check("Error deleting cgroup");
-        }
-        if Path::new(cgroup_path).exists() {
-            remove_cgroup_rec(cgroup_path);
+        });
+        with_freezer_hierarchy(id, |cgroup_path, _| {
+            if Path::new(cgroup_path).exists() {
+                remove_cgroup_rec(cgroup_path);
+            }
+        });
+        let memory_version = cgroup_version("memory")
+            .check("Error detecting memory cgroup version");
+        if memory_version == CgroupVersion::V1 {
+            with_hierarchy(id, "memory", "cgroup", Some("memory"), |cgroup_path| {
+                if Path::new(cgroup_path).exists() {
+                    remove_cgroup_rec(cgroup_path);
+                }
+            });
         }
     }
 
     if cmd == "enter" {
         let target = value_t_or_exit!(matches.value_of("target_pid"), pid_t);
-        add_task_to_cgroup(cgroup_path, target).check("Error entering container cgroup");
+        with_hierarchy(id, "cpu", "cgroup", Some("cpu,cpuacct"), |cgroup_path| {
+            add_task_to_cgroup(cgroup_path, target).check("Error entering container cgroup");
+        });
+        with_freezer_hierarchy(id, |cgroup_path, _| {
+            add_proc_to_cgroup(cgroup_path, target)
+                .check("Error entering container freezer cgroup");
+        });
+        let memory_version = cgroup_version("memory")
+            .check("Error detecting memory cgroup version");
+        if memory_version == CgroupVersion::V1 {
+            with_hierarchy(id, "memory", "cgroup", Some("memory"), |cgroup_path| {
+                // containers without a memory limit have no memory cgroup
+                if Path::new(cgroup_path).exists() {
+                    add_task_to_cgroup(cgroup_path, target)
+                        .check("Error entering container memory cgroup");
+                }
+            });
+        }
+    }
+
+    if cmd == "set-cpu" {
+        let perc = value_t_or_exit!(matches.value_of("perc"), u32);
+        if perc > 100 {
+            panic!("Percent of cpu must not be greater than 100");
+        }
+        with_hierarchy(id, "cpu", "cgroup", Some("cpu,cpuacct"), |cgroup_path| {
+            set_cpu_quota(cgroup_path, perc);
+        });
+    }
+
+    if cmd == "freeze" || cmd == "thaw" {
+        with_freezer_hierarchy(id, |cgroup_path, version| {
+            set_frozen(cgroup_path, version, cmd == "freeze")
+                .check("Error changing freezer state");
+        });
     }
 }
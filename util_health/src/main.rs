@@ -0,0 +1,105 @@
+extern crate aucont_core as core;
+extern crate aucont_lib_container as container;
+extern crate libc;
+#[macro_use]
+extern crate clap;
+
+use ::core::pid_t;
+use ::core::check::Check;
+use ::core::raw_process::RawProcess;
+use ::core::aucont_paths::container_info_file;
+use ::std::{io, fs, thread};
+use ::std::sync::mpsc;
+use ::std::time::Instant;
+use ::container::{Container, ExitStatus};
+use ::container::exec::{ExecConfig, DEFAULT_PATH};
+use ::container::health::{HealthCheck, HealthStatus};
+use ::container::info::{read_environment, read_health_check, write_value};
+
+// Runs the health check of a container until the container exits.
+// Started by aucont for the containers with a health check, see `health_start`.
+
+// The output of the check goes to "health_output", which is replaced once the check finishes
+fn run_check(id: pid_t, check: &HealthCheck) -> io::Result<ExitStatus> {
+    let mut environment = read_environment(id)?;
+    if !environment.iter().any(|(key, _)| key == "PATH") {
+        environment.insert(0, ("PATH".to_string(), DEFAULT_PATH.to_string()));
+    }
+    let output_path = container_info_file(id, "health_output");
+    let tmp_path = format!("{}.tmp", output_path);
+    let _ = fs::remove_file(&tmp_path);
+
+    let handle = Container::open(id)
+        .and_then(|container| container.exec(ExecConfig {
+            cmd: "/bin/sh".to_string(),
+            cmd_args: vec!["-c".to_string(), check.cmd.clone()],
+            environment,
+            redirect_stdin: Some("/dev/null".to_string()),
+            redirect_stdout: Some(tmp_path.clone()),
+            redirect_stderr: Some(tmp_path.clone()),
+            process_group: true,
+            ..Default::default()
+        }))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let group = handle.get_pid();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(handle.wait()));
+    let status = match receiver.recv_timeout(check.timeout) {
+        Ok(status) => status,
+        // the check may have started other processes, which would keep running
+        Err(_) => {
+            if unsafe { libc::kill(-group, libc::SIGKILL) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            receiver.recv().unwrap()
+        },
+    }.map_err(|e| io::Error::other(e.to_string()))?;
+    fs::rename(tmp_path, output_path)?;
+    Ok(status)
+}
+
+// Failed checks only count after the start period, unless one of the checks has succeeded
+fn run(id: pid_t) -> io::Result<()> {
+    let start_time = RawProcess::from_pid(id).start_time()?;
+    let check = read_health_check(id)?.ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound, "The container has no health check"))?;
+    let started = Instant::now();
+    let mut status = HealthStatus::Starting;
+    let mut failures = 0;
+    loop {
+        thread::sleep(check.interval);
+        match RawProcess::from_pid(id).start_time() {
+            Ok(time) if time == start_time => {},
+            _ => return Ok(()),
+        }
+
+        // e.g. the container is paused or exits while the check is being started
+        let healthy = run_check(id, &check).map(|status| status.success()).unwrap_or(false);
+        if healthy {
+            failures = 0;
+            status = HealthStatus::Healthy;
+        } else if status != HealthStatus::Starting || started.elapsed() >= check.start_period {
+            failures += 1;
+            if failures >= check.retries {
+                status = HealthStatus::Unhealthy;
+            }
+        }
+        write_value(id, "health", status)?;
+    }
+}
+
+fn main() {
+    let matches = clap::App::new("aucont_util_health")
+        .version("0.1")
+        .about("Utility tool used by aucont to run the health check of a container.")
+        .arg(clap::Arg::with_name("cont_pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start"))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("cont_pid"), pid_t);
+    run(id).check("Error running health checks");
+}
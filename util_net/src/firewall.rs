@@ -0,0 +1,187 @@
+use ::core::pid_t;
+use ::core::shell::shell_cmd;
+use ::std::{fs, io};
+use ::std::net::IpAddr;
+use ::net::{PortMapping, Protocol};
+
+// Firewall rules are managed with iptables and ip6tables.
+// Rules of a container are kept in chains of its own, one per built-in chain they belong to,
+// which is jumped to from the built-in chain. The chains are named after the container id,
+// so they are removed even if the setup was interrupted, without recording anything.
+
+// Built-in chains the rules of a container may belong to
+const HOOKS: [(&str, &str); 4] = [
+    ("nat", "PREROUTING"),
+    ("nat", "OUTPUT"),
+    ("nat", "POSTROUTING"),
+    ("filter", "FORWARD"),
+];
+
+// At most 26 characters, while iptables allows 28
+fn container_chain(id: pid_t, hook: &str) -> String {
+    format!("AUCONT-{}-{}", id, hook)
+}
+
+fn iptables(ipv6: bool, args: &[&str]) -> io::Result<()> {
+    shell_cmd(if ipv6 { "ip6tables" } else { "iptables" }, args)
+}
+
+fn chain_exists(ipv6: bool, table: &str, chain: &str) -> bool {
+    iptables(ipv6, &["--table", table, "--list-rules", chain]).is_ok()
+}
+
+pub struct Rule {
+    ipv6: bool,
+    table: String,
+    chain: String,
+    spec: Vec<String>,
+}
+
+impl Rule {
+    pub fn new(ipv6: bool, table: &str, chain: &str, spec: &[&str]) -> Rule {
+        Rule {
+            ipv6,
+            table: table.to_string(),
+            chain: chain.to_string(),
+            spec: spec.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    fn run(&self, action: &str) -> io::Result<()> {
+        let mut args = vec!["--table", &self.table, action, &self.chain];
+        args.extend(self.spec.iter().map(|arg| arg.as_str()));
+        iptables(self.ipv6, &args)
+    }
+
+    // Rules are inserted at the beginning of the chain to take precedence over the host's rules
+    pub fn insert(&self) -> io::Result<()> {
+        self.run("--insert")
+    }
+
+    pub fn append(&self) -> io::Result<()> {
+        self.run("--append")
+    }
+
+    pub fn delete(&self) -> io::Result<()> {
+        self.run("--delete")
+    }
+
+    pub fn exists(&self) -> bool {
+        self.run("--check").is_ok()
+    }
+}
+
+pub struct ContainerRules {
+    id: pid_t,
+    // (ipv6, hook) of the chains created so far
+    chains: Vec<(bool, &'static str)>,
+}
+
+impl ContainerRules {
+    pub fn new(id: pid_t) -> ContainerRules {
+        ContainerRules { id, chains: Vec::new() }
+    }
+
+    // The rule is moved to the chain of the container. Rules of other chains are rejected.
+    pub fn add(&mut self, rule: Rule) -> io::Result<()> {
+        let &(table, hook) = HOOKS.iter()
+            .find(|&&(table, hook)| table == rule.table && hook == rule.chain)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "Firewall rules of chain {} of table {} are not supported",
+                rule.chain, rule.table)))?;
+        let chain = &container_chain(self.id, hook);
+        if !self.chains.contains(&(rule.ipv6, hook)) {
+            iptables(rule.ipv6, &["--table", table, "--new-chain", chain])?;
+            self.chains.push((rule.ipv6, hook));
+            Rule::new(rule.ipv6, table, hook, &["--jump", chain]).insert()?;
+        }
+        Rule { chain: chain.clone(), ..rule }.append()
+    }
+
+    // Removes the chains of the container and the jumps to them.
+    // Every chain is tried even if removing some of them fails.
+    pub fn remove(id: pid_t) -> io::Result<()> {
+        let mut result = Ok(());
+        for &ipv6 in &[false, true] {
+            for &(table, hook) in &HOOKS {
+                let chain = &container_chain(id, hook);
+                if !chain_exists(ipv6, table, chain) {
+                    continue;
+                }
+                let jump = Rule::new(ipv6, table, hook, &["--jump", chain]);
+                let res = (if jump.exists() { jump.delete() } else { Ok(()) })
+                    .and_then(|_| iptables(ipv6, &["--table", table, "--flush", chain]))
+                    .and_then(|_| iptables(ipv6, &["--table", table, "--delete-chain", chain]));
+                if result.is_ok() {
+                    result = res;
+                }
+            }
+        }
+        result
+    }
+}
+
+// Forwarding is never disabled again, since other users of the host may rely on it
+pub fn enable_forwarding(ipv6: bool) -> io::Result<()> {
+    let path = if ipv6 {
+        "/proc/sys/net/ipv6/conf/all/forwarding"
+    } else {
+        "/proc/sys/net/ipv4/ip_forward"
+    };
+    fs::write(path, "1")
+}
+
+// Outbound connections of the container appear to come from the host.
+// `dev` is the host interface the container is reached through.
+pub fn masquerade_rules(addr: &IpAddr, dev: &str) -> Vec<Rule> {
+    let ipv6 = addr.is_ipv6();
+    let addr = &addr.to_string();
+    vec![
+        Rule::new(ipv6, "nat", "POSTROUTING", &["--source", addr, "!", "--out-interface", dev,
+            "--jump", "MASQUERADE"]),
+        Rule::new(ipv6, "filter", "FORWARD", &["--source", addr, "--in-interface", dev,
+            "--jump", "ACCEPT"]),
+        Rule::new(ipv6, "filter", "FORWARD", &["--destination", addr, "--out-interface", dev,
+            "--match", "conntrack", "--ctstate", "RELATED,ESTABLISHED", "--jump", "ACCEPT"]),
+    ]
+}
+
+// Connections to the port of any local address of the host are forwarded to the container.
+// Loopback addresses are excluded, since the kernel doesn't route them to other interfaces.
+pub fn publish_rules(addr: &IpAddr, dev: &str, mapping: &PortMapping) -> Vec<Rule> {
+    let ipv6 = addr.is_ipv6();
+    let loopback = if ipv6 { "::1" } else { "127.0.0.0/8" };
+    let protocol = match mapping.protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    };
+    let host_port = &mapping.host_port.to_string();
+    let cont_port = &mapping.cont_port.to_string();
+    let destination = &match *addr {
+        IpAddr::V4(addr) => format!("{}:{}", addr, cont_port),
+        IpAddr::V6(addr) => format!("[{}]:{}", addr, cont_port),
+    };
+    let addr = &addr.to_string();
+    let dnat = |chain| Rule::new(ipv6, "nat", chain, &["--protocol", protocol,
+        "--match", "addrtype", "--dst-type", "LOCAL", "!", "--destination", loopback,
+        "--dport", host_port, "--jump", "DNAT", "--to-destination", destination]);
+    vec![
+        dnat("PREROUTING"),
+        dnat("OUTPUT"),
+        Rule::new(ipv6, "filter", "FORWARD", &["--destination", addr, "--out-interface", dev,
+            "--protocol", protocol, "--dport", cont_port, "--jump", "ACCEPT"]),
+    ]
+}
+
+// Replies of the container to the connections forwarded by `publish_rules`.
+// Not needed with masquerading, which accepts everything from the container.
+pub fn reply_rule(addr: &IpAddr, dev: &str) -> Rule {
+    Rule::new(addr.is_ipv6(), "filter", "FORWARD", &["--source", &addr.to_string(),
+        "--in-interface", dev, "--match", "conntrack", "--ctstate", "RELATED,ESTABLISHED",
+        "--jump", "ACCEPT"])
+}
+
+// Lets the containers attached to the bridge reach other networks
+pub fn bridge_forward_rule(bridge: &str, ipv6: bool) -> Rule {
+    Rule::new(ipv6, "filter", "FORWARD", &["--in-interface", bridge, "--jump", "ACCEPT"])
+}
@@ -0,0 +1,308 @@
+extern crate aucont_core as core;
+
+use ::core::raw_process::pid_t;
+use ::core::shell::shell_cmd;
+use ::core::aucont_paths::aucont_util;
+use ::std::{io, fmt};
+use ::std::net::IpAddr;
+use ::std::str::FromStr;
+use ::std::time::Duration;
+
+pub use ::core::netlink::Shaping;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// Port of the host forwarded to a port of the container, "HOSTPORT:CONTPORT[/tcp|udp]"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub cont_port: u16,
+    pub protocol: Protocol,
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        write!(f, "{}:{}/{}", self.host_port, self.cont_port, protocol)
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = io::Error;
+
+    // The protocol is TCP by default
+    fn from_str(s: &str) -> io::Result<PortMapping> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Invalid port mapping '{}', expected HOSTPORT:CONTPORT[/tcp|udp]", s));
+        let (ports, protocol) = match s.find('/') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, "tcp"),
+        };
+        let protocol = match protocol {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return Err(invalid()),
+        };
+        let mut ports = ports.splitn(2, ':').map(|port| port.parse::<u16>().ok());
+        match (ports.next(), ports.next()) {
+            (Some(Some(host_port)), Some(Some(cont_port))) if host_port > 0 && cont_port > 0 => {
+                Ok(PortMapping { host_port, cont_port, protocol })
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Splits "10mbit" into the number and the unit
+fn split_unit(s: &str) -> (&str, &str) {
+    let pos = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    (&s[..pos], &s[pos..])
+}
+
+fn invalid_value(kind: &str, s: &str, expected: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Invalid {} '{}', expected {}", kind, s, expected))
+}
+
+// Bits per second, the same units as used by tc: "bit", "kbit", "mbit", "gbit"
+// or "bps", "kbps", "mbps", "gbps" for bytes. Plain numbers are bits.
+pub fn parse_rate(s: &str) -> io::Result<u64> {
+    let invalid = || invalid_value("rate", s, "a number with bit, kbit, mbit, gbit or bps units");
+    let (number, unit) = split_unit(s);
+    let multiplier = match unit.to_lowercase().as_str() {
+        "" | "bit" => 1.0,
+        "kbit" => 1e3,
+        "mbit" => 1e6,
+        "gbit" => 1e9,
+        "bps" => 8.0,
+        "kbps" => 8e3,
+        "mbps" => 8e6,
+        "gbps" => 8e9,
+        _ => return Err(invalid()),
+    };
+    let rate = number.parse::<f64>().map_err(|_| invalid())? * multiplier;
+    if rate < 8.0 || rate > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(rate as u64)
+}
+
+// Uses the largest unit which represents the rate exactly
+pub fn format_rate(rate: u64) -> String {
+    let units = [(1_000_000_000, "gbit"), (1_000_000, "mbit"), (1_000, "kbit"), (1, "bit")];
+    let &(size, unit) = units.iter().find(|&&(size, _)| rate.is_multiple_of(size)).unwrap();
+    format!("{}{}", rate / size, unit)
+}
+
+// "us", "ms" or "s"; plain numbers are milliseconds
+pub fn parse_delay(s: &str) -> io::Result<Duration> {
+    let invalid = || invalid_value("delay", s, "a number with us, ms or s units");
+    let (number, unit) = split_unit(s);
+    let multiplier = match unit {
+        "us" => 1e-6,
+        "" | "ms" => 1e-3,
+        "s" => 1.0,
+        _ => return Err(invalid()),
+    };
+    let secs = number.parse::<f64>().map_err(|_| invalid())? * multiplier;
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+pub fn format_delay(delay: Duration) -> String {
+    let us = delay.as_micros();
+    let units = [(1_000_000, "s"), (1_000, "ms"), (1, "us")];
+    let &(size, unit) = units.iter().find(|&&(size, _)| us.is_multiple_of(size)).unwrap();
+    format!("{}{}", us / size, unit)
+}
+
+// Percent of the dropped packets, "1.5%" or "1.5"
+pub fn parse_loss(s: &str) -> io::Result<f64> {
+    let loss = s.trim_end_matches('%').parse::<f64>().ok()
+        .filter(|loss| (0.0..=100.0).contains(loss));
+    loss.ok_or_else(|| invalid_value("loss", s, "a percentage from 0 to 100"))
+}
+
+fn util_net<S: AsRef<str>>(args: &[S]) -> io::Result<()> {
+    let mut full_args = vec![aucont_util("net")];
+    full_args.extend(args.iter().map(|arg| arg.as_ref().to_string()));
+    shell_cmd("sudo", &full_args)
+}
+
+// `cont_addrs` are addresses of the container with their prefix lengths.
+// With `masquerade` the container can open connections to other networks through the host.
+// Each of the `attachments` is another bridge with the container addresses on it.
+pub fn net_configure(cont_id: pid_t, cont_addrs: &[(IpAddr, u8)], host_addrs: &[IpAddr],
+                     bridge: Option<&str>, attachments: &[(String, Vec<(IpAddr, u8)>)],
+                     masquerade: bool, publish: &[PortMapping]) -> io::Result<()> {
+    let mut args = vec!["configure".to_string(), cont_id.to_string()];
+    if masquerade {
+        args.push("--masquerade".to_string());
+    }
+    for mapping in publish {
+        args.push("--publish".to_string());
+        args.push(mapping.to_string());
+    }
+    for &(addr, prefix_len) in cont_addrs {
+        args.push("--cont-addr".to_string());
+        args.push(format!("{}/{}", addr, prefix_len));
+    }
+    for host_addr in host_addrs {
+        args.push("--host-addr".to_string());
+        args.push(host_addr.to_string());
+    }
+    if let Some(bridge) = bridge {
+        args.push("--bridge".to_string());
+        args.push(bridge.to_string());
+    }
+    for (bridge, addrs) in attachments {
+        for &(addr, prefix_len) in addrs {
+            args.push("--attach".to_string());
+            args.push(format!("{}:{}/{}", bridge, addr, prefix_len));
+        }
+    }
+    util_net(&args)
+}
+
+// For containers with their own network namespace but no connection to the host
+pub fn net_loopback_up(cont_id: pid_t) -> io::Result<()> {
+    util_net(&["loopback-up", &cont_id.to_string()])
+}
+
+// Emulates the link conditions in both directions between the host and the container
+pub fn net_shape(cont_id: pid_t, shaping: &Shaping) -> io::Result<()> {
+    let mut args = vec!["shape".to_string(), cont_id.to_string()];
+    if let Some(rate) = shaping.rate {
+        args.push("--rate".to_string());
+        args.push(format_rate(rate));
+    }
+    if let Some(delay) = shaping.delay {
+        args.push("--delay".to_string());
+        args.push(format_delay(delay));
+    }
+    if let Some(loss) = shaping.loss {
+        args.push("--loss".to_string());
+        args.push(loss.to_string());
+    }
+    util_net(&args)
+}
+
+// Drops the packets sent by the container to `dsts`, replacing the previous list
+pub fn net_isolate(cont_id: pid_t, dsts: &[IpAddr]) -> io::Result<()> {
+    let mut args = vec!["isolate".to_string(), cont_id.to_string()];
+    for dst in dsts {
+        args.push("--drop-dst".to_string());
+        args.push(dst.to_string());
+    }
+    util_net(&args)
+}
+
+pub fn net_teardown(cont_id: pid_t) -> io::Result<()> {
+    util_net(&["teardown", &cont_id.to_string()])
+}
+
+// With `forward` the firewall lets the attached containers reach other networks
+pub fn bridge_create(name: &str, addr: Option<IpAddr>, forward: bool) -> io::Result<()> {
+    let mut args = vec!["bridge-add".to_string(), name.to_string()];
+    if let Some(addr) = addr {
+        args.push("--addr".to_string());
+        args.push(addr.to_string());
+    }
+    if forward {
+        args.push("--forward".to_string());
+    }
+    util_net(&args)
+}
+
+pub fn bridge_delete(name: &str) -> io::Result<()> {
+    util_net(&["bridge-del", name])
+}
+
+pub fn route_add(dst: IpAddr, dev: &str) -> io::Result<()> {
+    util_net(&["route-add", &dst.to_string(), dev])
+}
+
+pub fn route_del(dst: IpAddr, dev: &str) -> io::Result<()> {
+    util_net(&["route-del", &dst.to_string(), dev])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_mappings_default_to_tcp() {
+        let cases = [
+            ("8080:80", Some((8080, 80, Protocol::Tcp))),
+            ("53:5353/udp", Some((53, 5353, Protocol::Udp))),
+            ("8080:80/sctp", None),
+            ("0:80", None),
+            ("65536:80", None),
+            ("80", None),
+        ];
+        for &(s, expected) in &cases {
+            let parsed = s.parse::<PortMapping>().ok()
+                .map(|mapping| (mapping.host_port, mapping.cont_port, mapping.protocol));
+            assert_eq!(parsed, expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn port_mappings_are_recorded_with_the_protocol() {
+        let mapping = PortMapping { host_port: 8080, cont_port: 80, protocol: Protocol::Tcp };
+        assert_eq!(mapping.to_string(), "8080:80/tcp");
+        assert_eq!(mapping.to_string().parse::<PortMapping>().unwrap(), mapping);
+    }
+
+    #[test]
+    fn rates_are_parsed_in_bits() {
+        let cases = [
+            ("10mbit", Some(10_000_000)),
+            ("1.5kbit", Some(1500)),
+            ("2MBps", Some(16_000_000)),
+            ("800", Some(800)),
+            ("mbit", None),
+            ("10mb", None),
+            ("1bit", None),
+            ("-1mbit", None),
+            ("1e3bit", None),
+        ];
+        for &(s, expected) in &cases {
+            assert_eq!(parse_rate(s).ok(), expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rates_are_shown_in_the_largest_exact_unit() {
+        assert_eq!(format_rate(10_000_000), "10mbit");
+        assert_eq!(format_rate(1500), "1500bit");
+        assert_eq!(parse_rate(&format_rate(2_500_000_000)).unwrap(), 2_500_000_000);
+        assert_eq!(parse_rate(&format_rate(1_000_000_001)).unwrap(), 1_000_000_001);
+    }
+
+    #[test]
+    fn delays_are_parsed_in_milliseconds_by_default() {
+        assert_eq!(parse_delay("100").unwrap(), Duration::from_millis(100));
+        assert_eq!(parse_delay("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_delay("250us").unwrap(), Duration::from_micros(250));
+        assert!(parse_delay("10m").is_err());
+        assert!(parse_delay("-1ms").is_err());
+        assert_eq!(format_delay(Duration::from_millis(100)), "100ms");
+        assert_eq!(parse_delay(&format_delay(Duration::from_micros(250))).unwrap(),
+                   Duration::from_micros(250));
+    }
+
+    #[test]
+    fn losses_are_percentages() {
+        assert_eq!(parse_loss("1.5%").unwrap(), 1.5);
+        assert_eq!(parse_loss("100").unwrap(), 100.0);
+        assert!(parse_loss("101").is_err());
+        assert!(parse_loss("5%%x").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+extern crate aucont_core as core;
+extern crate aucont_util_net as net;
+#[macro_use]
+extern crate clap;
+
+mod firewall;
+
+use ::core::pid_t;
+use ::core::check::Check;
+use ::core::netlink::{Netlink, Transaction, Route, is_not_found, parse_cidr};
+use ::std::io;
+use ::std::net::IpAddr;
+use ::net::{PortMapping, Shaping, parse_rate, parse_delay, parse_loss};
+use firewall::ContainerRules;
+
+// The addresses and routes added on the host belong to the host ends of the veth pairs,
+// so they are removed together with them. The pairs are found by the container id, see
+// `veth_pairs`, and removed in reverse order, so that teardown can be repeated if it's
+// interrupted. Resources which are already removed (e.g. together with the container
+// namespace) are skipped.
+fn teardown(id: pid_t) -> io::Result<()> {
+    // the interfaces are removed even if some of the rules can't be
+    let firewall_res = ContainerRules::remove(id);
+    let mut netlink = Netlink::new()?;
+    for (host, _) in veth_pairs(id, &mut netlink)?.iter().rev() {
+        match netlink.del_link(host) {
+            Err(ref e) if is_not_found(e) => {},
+            res => res?,
+        }
+    }
+    firewall_res
+}
+
+fn host_prefix_len(addr: &IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+// Link-local address of the host end of the veth pair, used as the IPv6 gateway.
+// Every container gets the same one, since link-local addresses only matter within the link.
+fn ipv6_gateway() -> IpAddr {
+    "fe80::1".parse().unwrap()
+}
+
+// Creates a veth pair: "eth0" inside the container and "veth<ID>h" on the host.
+// Each address of the container may be paired with a host address of the same family.
+// Unless the host end is attached to a bridge, routes to the container are added on the host.
+// Each of the `attachments` gets another pair, "ethN" and "veth<ID>hN" with N from 1, attached
+// to its bridge. Only the subnets of their addresses are routed through them.
+// Either everything is configured or nothing is left on the host.
+fn configure(id: pid_t, cont_addrs: &[(IpAddr, u8)], host_addrs: &[IpAddr], bridge: Option<&str>,
+             attachments: &[(String, Vec<(IpAddr, u8)>)], masquerade: bool,
+             publish: &[PortMapping]) -> io::Result<()> {
+    let (veth_host, _) = &veth_names(id, 0);
+    let veth_guest = &format!("veth{}g", id);
+
+    let mut cont_netlink = Netlink::in_netns_of(id)?;
+    let mut host_netlink = Netlink::new()?;
+    let mut transaction = Transaction::new(&mut host_netlink);
+
+    transaction.add_veth(veth_host, veth_guest)?;
+    transaction.netlink().set_link_netns_pid(veth_guest, id)?;
+    cont_netlink.set_link_up("lo", true)?;
+    cont_netlink.rename_link(veth_guest, "eth0")?;
+    cont_netlink.set_link_up("eth0", true)?;
+    transaction.netlink().set_link_up(veth_host, true)?;
+
+    let has_ipv6 = cont_addrs.iter().any(|&(addr, _)| addr.is_ipv6());
+    if has_ipv6 && bridge.is_none() {
+        transaction.add_addr(veth_host, &ipv6_gateway(), 64)?;
+    }
+    for &(cont_addr, prefix_len) in cont_addrs {
+        cont_netlink.add_addr("eth0", &cont_addr, prefix_len)?;
+        let gateway = if cont_addr.is_ipv6() && bridge.is_none() {
+            Some(ipv6_gateway())
+        } else {
+            None
+        };
+        cont_netlink.add_route(&Route::default_via("eth0", gateway, cont_addr.is_ipv6()))?;
+
+        let host_addr = host_addrs.iter().find(|addr| addr.is_ipv6() == cont_addr.is_ipv6());
+        if let Some(host_addr) = host_addr {
+            transaction.add_addr(veth_host, host_addr, host_prefix_len(host_addr))?;
+        }
+        if bridge.is_none() {
+            let route = Route::link(cont_addr, host_prefix_len(&cont_addr), veth_host);
+            transaction.add_route(&route)?;
+        }
+    }
+    if let Some(bridge) = bridge {
+        transaction.netlink().set_link_master(veth_host, bridge)?;
+    }
+
+    for (i, (attach_bridge, addrs)) in attachments.iter().enumerate() {
+        let (attach_host, attach_cont) = &veth_names(id, i + 1);
+        let attach_guest = &format!("veth{}g{}", id, i + 1);
+        transaction.add_veth(attach_host, attach_guest)?;
+        transaction.netlink().set_link_netns_pid(attach_guest, id)?;
+        cont_netlink.rename_link(attach_guest, attach_cont)?;
+        cont_netlink.set_link_up(attach_cont, true)?;
+        for &(cont_addr, prefix_len) in addrs {
+            cont_netlink.add_addr(attach_cont, &cont_addr, prefix_len)?;
+        }
+        transaction.netlink().set_link_master(attach_host, attach_bridge)?;
+        transaction.netlink().set_link_up(attach_host, true)?;
+    }
+
+    let host_dev = bridge.unwrap_or(veth_host);
+    if let Err(e) = add_firewall_rules(id, cont_addrs, host_dev, masquerade, publish) {
+        ContainerRules::remove(id).log_error("Error removing firewall rules");
+        return Err(e);
+    }
+    transaction.commit();
+    Ok(())
+}
+
+fn add_firewall_rules(id: pid_t, cont_addrs: &[(IpAddr, u8)], host_dev: &str, masquerade: bool,
+                      publish: &[PortMapping]) -> io::Result<()> {
+    if !masquerade && publish.is_empty() {
+        return Ok(());
+    }
+    let mut rules = ContainerRules::new(id);
+    for &(cont_addr, _) in cont_addrs {
+        firewall::enable_forwarding(cont_addr.is_ipv6())?;
+        if masquerade {
+            for rule in firewall::masquerade_rules(&cont_addr, host_dev) {
+                rules.add(rule)?;
+            }
+        }
+        for mapping in publish {
+            for rule in firewall::publish_rules(&cont_addr, host_dev, mapping) {
+                rules.add(rule)?;
+            }
+        }
+        if !masquerade && !publish.is_empty() {
+            rules.add(firewall::reply_rule(&cont_addr, host_dev))?;
+        }
+    }
+    Ok(())
+}
+
+// Host and container ends of the Nth veth pair of the container, see `configure`
+fn veth_names(id: pid_t, n: usize) -> (String, String) {
+    if n == 0 {
+        (format!("veth{}h", id), "eth0".to_string())
+    } else {
+        (format!("veth{}h{}", id, n), format!("eth{}", n))
+    }
+}
+
+// All the veth pairs created by `configure`
+fn veth_pairs(id: pid_t, netlink: &mut Netlink) -> io::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    loop {
+        let (host, cont) = veth_names(id, pairs.len());
+        match netlink.link_index(&host) {
+            Err(ref e) if is_not_found(e) => return Ok(pairs),
+            res => { res?; },
+        }
+        pairs.push((host, cont));
+    }
+}
+
+// The host ends shape the traffic to the container, the container ends the traffic from it.
+// Each packet passes one of them, so the conditions apply to each direction separately,
+// as on a real link: a round trip is delayed twice and loses about twice as many packets.
+fn shape(id: pid_t, shaping: &Shaping) -> io::Result<()> {
+    let mut host_netlink = Netlink::new()?;
+    let mut cont_netlink = Netlink::in_netns_of(id)?;
+    for (host, cont) in veth_pairs(id, &mut host_netlink)? {
+        host_netlink.set_shaping(&host, shaping)?;
+        cont_netlink.set_shaping(&cont, shaping)?;
+    }
+    Ok(())
+}
+
+// The host ends of the veth pairs receive everything the container sends
+fn isolate(id: pid_t, dsts: &[IpAddr]) -> io::Result<()> {
+    let mut netlink = Netlink::new()?;
+    for (host, _) in veth_pairs(id, &mut netlink)? {
+        netlink.set_drop_filter(&host, dsts)?;
+    }
+    Ok(())
+}
+
+fn bridge_add(name: &str, addr: Option<IpAddr>, forward: bool) -> io::Result<()> {
+    let mut netlink = Netlink::new()?;
+    let mut transaction = Transaction::new(&mut netlink);
+    transaction.add_bridge(name)?;
+    transaction.netlink().set_link_up(name, true)?;
+    if let Some(addr) = addr {
+        transaction.add_addr(name, &addr, host_prefix_len(&addr))?;
+    }
+    if forward {
+        let ipv6 = addr.map(|addr| addr.is_ipv6()).unwrap_or(false);
+        firewall::enable_forwarding(ipv6)?;
+        firewall::bridge_forward_rule(name, ipv6).insert()?;
+    }
+    transaction.commit();
+    Ok(())
+}
+
+// The forward rule is removed if it was added by bridge_add
+fn bridge_del(name: &str) -> io::Result<()> {
+    for &ipv6 in &[false, true] {
+        let rule = firewall::bridge_forward_rule(name, ipv6);
+        if rule.exists() {
+            rule.delete()?;
+        }
+    }
+    Netlink::new()?.del_link(name)
+}
+
+fn main() {
+    let id_arg = || clap::Arg::with_name("cont_pid")
+        .index(1)
+        .required(true)
+        .value_name("ID")
+        .help("Container id as returned by aucont_start");
+    let bridge_arg = || clap::Arg::with_name("bridge")
+        .index(1)
+        .required(true)
+        .value_name("BRIDGE")
+        .help("Name of the bridge");
+
+    let matches = clap::App::new("aucont_util_net")
+        .version("0.1")
+        .about("Utility tool used by aucont to manage network interfaces. Requires CAP_NET_ADMIN.")
+        .setting(clap::AppSettings::SubcommandRequired)
+        .subcommand(clap::SubCommand::with_name("configure")
+            .about("Connect the container to the host with a veth pair")
+            .arg(id_arg())
+            .arg(clap::Arg::with_name("cont_addr")
+                .long("cont-addr")
+                .required(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("IP[/PREFIX]")
+                .help("Address of the container, at most one of each family"))
+            .arg(clap::Arg::with_name("host_addr")
+                .long("host-addr")
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("IP")
+                .help("Address of the host end of the veth pair, at most one of each family"))
+            .arg(clap::Arg::with_name("bridge")
+                .long("bridge")
+                .value_name("BRIDGE")
+                .help("Attach the host end of the veth pair to the bridge"))
+            .arg(clap::Arg::with_name("attach")
+                .long("attach")
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("BRIDGE:IP/PREFIX")
+                .help("Address of the container on another bridge, which is connected \
+                with a veth pair of its own"))
+            .arg(clap::Arg::with_name("masquerade")
+                .long("masquerade")
+                .help("Let the container connect to other networks through the host"))
+            .arg(clap::Arg::with_name("publish")
+                .long("publish")
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("HOSTPORT:CONTPORT[/tcp|udp]")
+                .help("Forward a port of the host to the container")))
+        .subcommand(clap::SubCommand::with_name("loopback-up")
+            .about("Bring up the loopback interface of the container")
+            .arg(id_arg()))
+        .subcommand(clap::SubCommand::with_name("shape")
+            .about("Set the conditions of the link between the host and the container, \
+            which is reset if none are given")
+            .arg(id_arg())
+            .arg(clap::Arg::with_name("rate")
+                .long("rate")
+                .value_name("RATE")
+                .help("Bandwidth in each direction, e.g. 10mbit"))
+            .arg(clap::Arg::with_name("delay")
+                .long("delay")
+                .value_name("DELAY")
+                .help("Delay in each direction, e.g. 100ms"))
+            .arg(clap::Arg::with_name("loss")
+                .long("loss")
+                .value_name("PERCENT")
+                .help("Percent of the packets dropped in each direction")))
+        .subcommand(clap::SubCommand::with_name("isolate")
+            .about("Drop the packets sent by the container to the given addresses, \
+            which replaces the previous list. All are let through if none are given.")
+            .arg(id_arg())
+            .arg(clap::Arg::with_name("drop_dst")
+                .long("drop-dst")
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("IP")
+                .help("Destination address of the dropped packets")))
+        .subcommand(clap::SubCommand::with_name("teardown")
+            .about("Remove the host resources created by configure")
+            .arg(id_arg()))
+        .subcommand(clap::SubCommand::with_name("bridge-add")
+            .about("Create a bridge and bring it up")
+            .arg(bridge_arg())
+            .arg(clap::Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP")
+                .help("Address of the bridge"))
+            .arg(clap::Arg::with_name("forward")
+                .long("forward")
+                .help("Let the attached containers reach other networks")))
+        .subcommand(clap::SubCommand::with_name("bridge-del")
+            .about("Delete a bridge")
+            .arg(bridge_arg()))
+        .subcommand(clap::SubCommand::with_name("route-add")
+            .about("Add a route to the host through a device")
+            .arg(clap::Arg::with_name("dst")
+                .index(1)
+                .required(true)
+                .value_name("IP"))
+            .arg(clap::Arg::with_name("dev")
+                .index(2)
+                .required(true)
+                .value_name("DEV")))
+        .subcommand(clap::SubCommand::with_name("route-del")
+            .about("Delete a route added by route-add")
+            .arg(clap::Arg::with_name("dst")
+                .index(1)
+                .required(true)
+                .value_name("IP"))
+            .arg(clap::Arg::with_name("dev")
+                .index(2)
+                .required(true)
+                .value_name("DEV")))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("configure", Some(args)) => {
+            let id = value_t_or_exit!(args.value_of("cont_pid"), pid_t);
+            let cont_addrs = args.values_of("cont_addr").unwrap()
+                .map(|addr| parse_cidr(addr).check("Invalid container address"))
+                .collect::<Vec<_>>();
+            let host_addrs = match args.values_of("host_addr") {
+                Some(_) => values_t_or_exit!(args.values_of("host_addr"), IpAddr),
+                None => vec![],
+            };
+            let publish = match args.values_of("publish") {
+                Some(_) => values_t_or_exit!(args.values_of("publish"), PortMapping),
+                None => vec![],
+            };
+            let mut attachments: Vec<(String, Vec<(IpAddr, u8)>)> = Vec::new();
+            for attach in args.values_of("attach").into_iter().flatten() {
+                let (bridge, addr) = attach.split_once(':')
+                    .unwrap_or_else(|| panic!("Invalid attachment '{}'", attach));
+                let addr = parse_cidr(addr).check("Invalid attachment address");
+                match attachments.iter_mut().find(|attachment| attachment.0 == bridge) {
+                    Some(attachment) => attachment.1.push(addr),
+                    None => attachments.push((bridge.to_string(), vec![addr])),
+                }
+            }
+            configure(id, &cont_addrs, &host_addrs, args.value_of("bridge"), &attachments,
+                      args.is_present("masquerade"), &publish)
+                .check("Error configuring network");
+        },
+        ("loopback-up", Some(args)) => {
+            let id = value_t_or_exit!(args.value_of("cont_pid"), pid_t);
+            Netlink::in_netns_of(id)
+                .and_then(|mut netlink| netlink.set_link_up("lo", true))
+                .check("Error bringing up loopback interface");
+        },
+        ("shape", Some(args)) => {
+            let id = value_t_or_exit!(args.value_of("cont_pid"), pid_t);
+            let shaping = Shaping {
+                rate: args.value_of("rate").map(|rate| parse_rate(rate).check("Invalid rate")),
+                delay: args.value_of("delay")
+                    .map(|delay| parse_delay(delay).check("Invalid delay")),
+                loss: args.value_of("loss").map(|loss| parse_loss(loss).check("Invalid loss")),
+            };
+            shape(id, &shaping).check("Error shaping network traffic");
+        },
+        ("isolate", Some(args)) => {
+            let id = value_t_or_exit!(args.value_of("cont_pid"), pid_t);
+            let dsts = match args.values_of("drop_dst") {
+                Some(_) => values_t_or_exit!(args.values_of("drop_dst"), IpAddr),
+                None => vec![],
+            };
+            isolate(id, &dsts).check("Error setting packet filters");
+        },
+        ("teardown", Some(args)) => {
+            let id = value_t_or_exit!(args.value_of("cont_pid"), pid_t);
+            teardown(id).check("Error removing network resources");
+        },
+        ("bridge-add", Some(args)) => {
+            let addr = args.value_of("addr")
+                .map(|_| value_t_or_exit!(args.value_of("addr"), IpAddr));
+            bridge_add(args.value_of("bridge").unwrap(), addr, args.is_present("forward"))
+                .check("Error setting up network bridge");
+        },
+        ("bridge-del", Some(args)) => {
+            bridge_del(args.value_of("bridge").unwrap()).check("Error deleting network bridge");
+        },
+        ("route-add", Some(args)) => {
+            let dst = value_t_or_exit!(args.value_of("dst"), IpAddr);
+            let dev = args.value_of("dev").unwrap();
+            let route = Route::link(dst, host_prefix_len(&dst), dev);
+            Netlink::new()
+                .and_then(|mut netlink| netlink.add_route(&route))
+                .check("Error adding network route");
+        },
+        ("route-del", Some(args)) => {
+            let dst = value_t_or_exit!(args.value_of("dst"), IpAddr);
+            let dev = args.value_of("dev").unwrap();
+            let route = Route::link(dst, host_prefix_len(&dst), dev);
+            Netlink::new()
+                .and_then(|mut netlink| netlink.del_route(&route))
+                .check("Error deleting network route");
+        },
+        _ => unreachable!(),
+    }
+}
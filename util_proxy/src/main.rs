@@ -0,0 +1,247 @@
+extern crate aucont_core as core;
+extern crate aucont_util_net as net;
+extern crate libc;
+#[macro_use]
+extern crate clap;
+
+use ::core::pid_t;
+use ::core::check::Check;
+use ::core::raw_process::RawProcess;
+use ::core::aucont_paths::container_info_file;
+use ::std::{io, fs, thread, process};
+use ::std::io::Write;
+use ::std::collections::HashMap;
+use ::std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket,
+                 Shutdown};
+use ::std::sync::{Arc, Mutex};
+use ::std::sync::atomic::{AtomicUsize, Ordering};
+use ::std::time::Duration;
+use ::net::{PortMapping, Protocol};
+
+// Userspace alternative to the DNAT rules of aucont_util_net.
+// Listens on the host ports and forwards the connections to the container address.
+// Exits together with the container.
+
+// UDP has no connections, a client is forgotten after it has been idle for this long
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Pause after failures to receive, e.g. while out of memory or file descriptors.
+// It doubles with each failure in a row.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicUsize,
+    total: AtomicUsize,
+}
+
+// Counts a connection as active while it's alive
+struct ConnectionGuard(Arc<Counters>);
+
+impl ConnectionGuard {
+    fn new(counters: &Arc<Counters>) -> ConnectionGuard {
+        counters.active.fetch_add(1, Ordering::SeqCst);
+        counters.total.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(counters.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn unspecified(target: &IpAddr) -> IpAddr {
+    match *target {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+// Copies the data in both directions until both sides close their end
+fn tcp_forward(client: TcpStream, target: SocketAddr, counters: Arc<Counters>) {
+    let _guard = ConnectionGuard::new(&counters);
+    let upstream = match TcpStream::connect(target) {
+        Ok(upstream) => upstream,
+        Err(_) => return,
+    };
+    let (mut client_read, mut upstream_write) = match (client.try_clone(), upstream.try_clone()) {
+        (Ok(client_read), Ok(upstream_write)) => (client_read, upstream_write),
+        _ => return,
+    };
+    let uploader = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let _ = io::copy(&mut upstream_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = uploader.join();
+}
+
+// Returns false if the socket can't be used anymore.
+// Failures which may go away are logged and followed by a pause, so that they aren't retried
+// in a busy loop.
+fn recover(error: io::Error, backoff: &mut Duration) -> bool {
+    match error.kind() {
+        // e.g. the client reset the connection before it was accepted
+        io::ErrorKind::Interrupted | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionRefused => return true,
+        _ => {},
+    }
+    let transient = matches!(error.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM));
+    if !transient {
+        Err::<(), _>(error).log_error("Error receiving, the port isn't forwarded anymore");
+        return false;
+    }
+    Err::<(), _>(error).log_error(format!("Error receiving, retrying in {}ms",
+                                          backoff.as_millis()));
+    thread::sleep(*backoff);
+    *backoff = (*backoff * 2).min(MAX_BACKOFF);
+    true
+}
+
+fn tcp_serve(listener: TcpListener, target: SocketAddr, counters: Arc<Counters>) {
+    let mut backoff = MIN_BACKOFF;
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => if recover(e, &mut backoff) { continue } else { return },
+        };
+        backoff = MIN_BACKOFF;
+        let counters = counters.clone();
+        thread::spawn(move || tcp_forward(client, target, counters));
+    }
+}
+
+// Each client gets its own upstream socket, so that the replies can be told apart
+fn udp_serve(socket: UdpSocket, target: SocketAddr, counters: Arc<Counters>) {
+    let sessions: Arc<Mutex<HashMap<SocketAddr, UdpSocket>>> = Default::default();
+    let mut buf = [0; 65536];
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) => if recover(e, &mut backoff) { continue } else { return },
+        };
+        backoff = MIN_BACKOFF;
+        let upstream = sessions.lock().unwrap().get(&client).and_then(|up| up.try_clone().ok());
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => match udp_session(&socket, client, target, &sessions, &counters) {
+                Ok(upstream) => upstream,
+                Err(_) => continue,
+            },
+        };
+        let _ = upstream.send(&buf[..len]);
+    }
+}
+
+fn udp_session(socket: &UdpSocket, client: SocketAddr, target: SocketAddr,
+               sessions: &Arc<Mutex<HashMap<SocketAddr, UdpSocket>>>, counters: &Arc<Counters>)
+               -> io::Result<UdpSocket> {
+    let upstream = UdpSocket::bind(SocketAddr::new(unspecified(&target.ip()), 0))?;
+    upstream.connect(target)?;
+    upstream.set_read_timeout(Some(UDP_SESSION_TIMEOUT))?;
+    let (replies, socket) = (upstream.try_clone()?, socket.try_clone()?);
+    sessions.lock().unwrap().insert(client, upstream.try_clone()?);
+
+    let guard = ConnectionGuard::new(counters);
+    let sessions = sessions.clone();
+    thread::spawn(move || {
+        let _guard = guard;
+        let mut buf = [0; 65536];
+        while let Ok(len) = replies.recv(&mut buf) {
+            let _ = socket.send_to(&buf[..len], client);
+        }
+        sessions.lock().unwrap().remove(&client);
+    });
+    Ok(upstream)
+}
+
+// One line per mapping: "HOSTPORT:CONTPORT/PROTOCOL active=N total=N".
+// The file is replaced atomically, so that readers never see a partial one.
+fn write_stats(id: pid_t, stats: &[(PortMapping, Arc<Counters>)]) -> io::Result<()> {
+    let path = container_info_file(id, "proxy_stats");
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    for (mapping, counters) in stats {
+        writeln!(file, "{} active={} total={}", mapping,
+                 counters.active.load(Ordering::SeqCst), counters.total.load(Ordering::SeqCst))?;
+    }
+    fs::rename(tmp_path, path)
+}
+
+// All the ports are bound before the PID is printed, which tells the caller we're ready
+fn run(id: pid_t, target: IpAddr, publish: &[PortMapping]) -> io::Result<()> {
+    let start_time = RawProcess::from_pid(id).start_time()?;
+    let mut stats = Vec::new();
+    let mut servers: Vec<Box<dyn FnOnce() + Send>> = Vec::new();
+    for &mapping in publish {
+        let counters = Arc::new(Counters::default());
+        let local = SocketAddr::new(unspecified(&target), mapping.host_port);
+        let remote = SocketAddr::new(target, mapping.cont_port);
+        let server_counters = counters.clone();
+        servers.push(match mapping.protocol {
+            Protocol::Tcp => {
+                let listener = TcpListener::bind(local)?;
+                Box::new(move || tcp_serve(listener, remote, server_counters))
+            },
+            Protocol::Udp => {
+                let socket = UdpSocket::bind(local)?;
+                Box::new(move || udp_serve(socket, remote, server_counters))
+            },
+        });
+        stats.push((mapping, counters));
+    }
+    for server in servers {
+        thread::spawn(server);
+    }
+    write_stats(id, &stats)?;
+    println!("{}", process::id());
+    io::stdout().flush()?;
+
+    loop {
+        thread::sleep(STATS_INTERVAL);
+        match RawProcess::from_pid(id).start_time() {
+            Ok(time) if time == start_time => {},
+            _ => return Ok(()),
+        }
+        write_stats(id, &stats)?;
+    }
+}
+
+fn main() {
+    let matches = clap::App::new("aucont_util_proxy")
+        .version("0.1")
+        .about("Utility tool used by aucont to forward host ports to a container \
+        without firewall rules.")
+        .arg(clap::Arg::with_name("cont_pid")
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .help("Container id as returned by aucont_start"))
+        .arg(clap::Arg::with_name("target")
+            .long("target")
+            .required(true)
+            .value_name("IP")
+            .help("Address of the container"))
+        .arg(clap::Arg::with_name("publish")
+            .long("publish")
+            .required(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("HOSTPORT:CONTPORT[/tcp|udp]")
+            .help("Forward a port of the host to the container"))
+        .get_matches();
+
+    let id = value_t_or_exit!(matches.value_of("cont_pid"), pid_t);
+    let target = value_t_or_exit!(matches.value_of("target"), IpAddr);
+    let publish = values_t_or_exit!(matches.values_of("publish"), PortMapping);
+    run(id, target, &publish).check("Error forwarding ports");
+}